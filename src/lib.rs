@@ -2,12 +2,33 @@ pub mod renderer;
 pub mod modifiers;
 
 use std::io;
+use std::path::Path;
+use std::time::Instant;
 use image::imageops::FilterType;
 use nalgebra::Vector4;
-use minifb::{Key, WindowOptions, Window, Scale};
+use minifb::{Key, KeyRepeat, WindowOptions, Window, Scale};
 
-use renderer::render::{draw_object, Camera};
-use modifiers::io::load_texture;
+use renderer::render::{draw_object, draw_object_shaded, get_model_matrix, Camera, Object3D, RenderStats};
+use renderer::framebuffer::Framebuffer;
+use renderer::tonemap::{ToneMapper, ToneMapping};
+use renderer::postprocess::{Bloom, Dither, Fog, FogFalloff, Fxaa, PostEffect, PostProcessStack, Ssao};
+use renderer::shader::{DepthDebugShader, FlatLitShader, NormalsDebugShader, PbrShader, PhongShader, TexturedShader, VertexColorShader};
+use renderer::material::{Material, MaterialSet};
+use renderer::texture::{Texture, TextureFilter};
+use renderer::light::{Attenuation, Light};
+use renderer::math::srgb_color_to_linear;
+use renderer::bvh::{Instance, MeshBvh, SceneBvh};
+use renderer::raytracer::{Raytracer, RaytracerSettings, TracerMaterial};
+use renderer::shadow::{CascadedShadowMap, ShadowCaster, ShadowSettings};
+use renderer::pathtracer::{Accumulator, PathMaterial, PathTracer, PathTracerSettings};
+use renderer::environment::{draw_environment, draw_skybox, ConstantEnvironment, CubeMap, Environment, GradientSky, ImageBasedLight};
+use renderer::sky::{sun_direction, AtmosphericSky};
+use renderer::deferred::{draw_object_deferred, shade_deferred, GBuffer, GBufferChannel};
+use renderer::water::{default_waves, WaterShader};
+use modifiers::io::{load_heightfield, load_linear_texture, load_texture};
+use modifiers::modifiers::MapFilter;
+use modifiers::config::MapConfig;
+use modifiers::terrain::{colorize_heightfield, export_heightmap, generate_terrain, tint_erosion, tint_water};
 
 fn run_debug_scene() {
     // loading object from obj
@@ -55,15 +76,116 @@ fn run_debug_scene() {
     let rotation_3 = Vector4::new(0.0, 10.0, 180.0, 0.0);
     let scale = Vector4::new(1.0, 1.0, 1.0, 0.0);
 
+    let objects: [(&Object3D, _); 4] = [
+        (&monkey, get_model_matrix(monkey_pos, rotation_3, scale)),
+        (&cube, get_model_matrix(cube_pos, rotation_2, scale)),
+        (&plane, get_model_matrix(plane_pos, rotation, scale)),
+        (&sphere, get_model_matrix(sphere_pos, rotation, scale)),
+    ];
+    // intensities are irradiance, lambert reflects albedo / pi of it, so the pi keeps a white
+    // surface facing the sun at 0.9 like before the shaders divided by pi
+    let lights = [
+        Light::Directional {
+            direction: nalgebra::Vector3::new(0.5, -1.0, 0.8),
+            color: srgb_color_to_linear(0xFFFFFF),
+            intensity: 0.9 * std::f32::consts::PI,
+        },
+        Light::Hemisphere {
+            up: nalgebra::Vector3::y(),
+            sky_color: srgb_color_to_linear(0x8FB8FF),
+            ground_color: srgb_color_to_linear(0x403020),
+            intensity: 0.3 * std::f32::consts::PI,
+        },
+    ];
+    let mut texture = Texture::from_image(&load_texture("resources/map_color.png"));
+    texture.generate_srgb_mipmaps(FilterType::Triangle);
+    let gold = Material { base_color: Vector4::new(1.0, 0.77, 0.34, 1.0), metallic: 1.0, roughness: 0.3, vertex_colors: false, ..Default::default() };
+    let plastic = Material { roughness: 0.6, ..Default::default() };
+    let textured = Material { base_color_texture: Some(&texture), roughness: 0.8, double_sided: true, vertex_colors: false, filter: TextureFilter::Trilinear, ..Default::default() };
+    let mut cube_materials = MaterialSet::single(plastic);
+    // the second half of the cube is polished metal
+    cube_materials.assign(cube.triangles.len() / 2..cube.triangles.len(), Material { metallic: 1.0, roughness: 0.15, ..Default::default() });
+    let materials = [MaterialSet::single(gold), cube_materials, MaterialSet::single(textured), MaterialSet::single(textured)];
+    // sky and ambient light of the pbr mode, from resources/environment.hdr or the six faces in
+    // resources/skybox if there are any
+    let environment_map = if Path::new("resources/environment.hdr").exists() {
+        CubeMap::from_equirectangular(&Texture::from_float_image(&load_linear_texture("resources/environment.hdr")), 256)
+    } else if Path::new("resources/skybox").is_dir() {
+        CubeMap::load_faces("resources/skybox").unwrap_or_else(|e| panic!("Could not load skybox: {}", e))
+    } else {
+        CubeMap::from_environment(&GradientSky::default(), 64)
+    };
+    let image_based_light = ImageBasedLight::new(&environment_map, 64, 6, 64);
+    let mut framebuffer = Framebuffer::new(dimensions);
+    let tone_mapper = ToneMapper::default();
+    // the debug views show their values without any curve applied
+    let debug_tone_mapper = ToneMapper::new(ToneMapping::Clamp, 0.0);
+    // the stack tone maps itself, so its output is resolved with debug_tone_mapper
+    let post_process = PostProcessStack::load("resources/post_process.txt").unwrap_or_else(|e| {
+        println!("Could not load the post processing stack, using the default one: {}", e);
+        let mut stack = PostProcessStack::new();
+        stack.push(Bloom::default()).push(tone_mapper).push(Fxaa::default()).push(Dither::default());
+        stack
+    });
+    let mut post_processing = true;
+    let ambient_occlusion_debug = Ssao { debug: true, ..Default::default() };
+    // 1: wireframe, 2: vertex colors, 3: flat lit, 4: phong, 5: textured, 6: normals, 7: depth, 8: pbr,
+    // 9: ambient occlusion
+    let mode_keys = [Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9];
+    let mut mode = 0;
+
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        draw_object(&mut buffer, &monkey, dimensions, &camera, monkey_pos, rotation_3, scale, Some(0x000000));
-        draw_object(&mut buffer, &cube, dimensions, &camera, cube_pos, rotation_2, scale, None);
-        draw_object(&mut buffer, &plane, dimensions, &camera, plane_pos, rotation, scale, None);
-        draw_object(&mut buffer, &sphere, dimensions, &camera, sphere_pos, rotation, scale, None);
+        let mut stats = RenderStats::default();
+        if mode == 0 {
+            stats.record(draw_object(&mut buffer, &monkey, dimensions, &camera, monkey_pos, rotation_3, scale, Some(0x000000)));
+            stats.record(draw_object(&mut buffer, &cube, dimensions, &camera, cube_pos, rotation_2, scale, None));
+            stats.record(draw_object(&mut buffer, &plane, dimensions, &camera, plane_pos, rotation, scale, None));
+            stats.record(draw_object(&mut buffer, &sphere, dimensions, &camera, sphere_pos, rotation, scale, None));
+        } else {
+            framebuffer.clear(0x000000);
+            for ((object, transform), materials) in objects.iter().zip(materials.iter()) {
+                let drawn = match mode {
+                    1 => draw_object_shaded(&mut framebuffer, object, &camera, transform, &VertexColorShader),
+                    2 => draw_object_shaded(&mut framebuffer, object, &camera, transform, &FlatLitShader::new(object, &lights)),
+                    3 => draw_object_shaded(&mut framebuffer, object, &camera, transform, &PhongShader { lights: &lights, shadows: &[], specular: 0.5, shininess: 32.0 }),
+                    4 => draw_object_shaded(&mut framebuffer, object, &camera, transform, &TexturedShader { texture: &texture, filter: TextureFilter::Anisotropic(8), lights: &lights }),
+                    5 => draw_object_shaded(&mut framebuffer, object, &camera, transform, &NormalsDebugShader),
+                    6 => draw_object_shaded(&mut framebuffer, object, &camera, transform, &DepthDebugShader { max_distance: 30.0 }),
+                    // the environment replaces the hemisphere light
+                    _ => draw_object_shaded(&mut framebuffer, object, &camera, transform, &PbrShader {
+                        environment: Some(&image_based_light),
+                        ..PbrShader::new(object, materials, &lights[..1], &[])
+                    }),
+                };
+                stats.record(drawn);
+            }
+            if mode == 7 {
+                draw_skybox(&mut framebuffer, &camera, &environment_map);
+            }
+            if mode == 5 || mode == 6 {
+                framebuffer.resolve(&debug_tone_mapper, &mut buffer);
+            } else if mode == 8 {
+                ambient_occlusion_debug.apply(&mut framebuffer, &camera);
+                framebuffer.resolve(&debug_tone_mapper, &mut buffer);
+            } else if post_processing {
+                post_process.apply(&mut framebuffer, &camera);
+                framebuffer.resolve(&debug_tone_mapper, &mut buffer);
+            } else {
+                framebuffer.resolve(&tone_mapper, &mut buffer);
+            }
+        }
+        window.set_title(&format!("DEBUG SCENE - drawn: {} culled: {} - post processing {}", stats.drawn, stats.culled, if post_processing { "on" } else { "off" }));
         window
             .update_with_buffer(&buffer, dimensions.0, dimensions.1)
             .unwrap();
 
+        if let Some(key) = mode_keys.iter().position(|key| window.is_key_down(*key)) {
+            mode = key;
+        }
+        // P toggles the post processing
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            post_processing = !post_processing;
+        }
         if window.get_mouse_down(minifb::MouseButton::Right) {
             if window.is_key_down(Key::Space) {
                 camera.position.y += 0.1;
@@ -85,24 +207,34 @@ fn run_debug_scene() {
 fn run_heightmap_display() {
     let window_size: (usize, usize) = (1024, 800);
     let dimensions: (usize, usize) = (256, 256);
-    let image_filter = FilterType::Nearest;
-    let mut heightmap = load_texture("resources/map_height.png");
-    let mut colormap = load_texture("resources/map_color.png");
-
-    match modifiers::modifiers::scale_image(&mut heightmap, (dimensions.0 as u32, dimensions.1 as u32), image_filter) {
-        Ok(_) => {}
+    // the terrain is generated from the terrain tool settings, the baked maps are the fallback
+    let map_config = match MapConfig::load("resources/map_config.json") {
+        Ok(config) => Some(config),
         Err(e) => {
-            println!("Error: {}", e);
+            println!("Error: could not load the map config, using the baked maps: {}", e);
+            None
         }
-    }
-    match modifiers::modifiers::scale_image(&mut colormap, (dimensions.0 as u32, dimensions.1 as u32), image_filter) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("Error: {}", e);
+    };
+    let generated = map_config.as_ref().map(|config| generate_terrain(config, dimensions));
+    // rivers and lakes get their own color and glossy banks, the per pixel modes draw the water
+    // surface over them. the heights stay float, the maps can have any size
+    let (heightfield, colormap, water_roughness) = match (&map_config, &generated) {
+        (Some(config), Some(terrain)) => {
+            let water_level = config.as_water.then(|| config.ground_height());
+            let mut colormap = colorize_heightfield(&terrain.heightfield, water_level);
+            if let Some(maps) = &terrain.erosion {
+                tint_erosion(&mut colormap, maps);
+            }
+            if let Some(water) = &terrain.water {
+                tint_water(&mut colormap, &terrain.heightfield, water);
+            }
+            let water_roughness = terrain.water.as_ref().map(|water| water.roughness_map(0.9, 0.05));
+            (terrain.heightfield.clone(), colormap, water_roughness)
         }
-    }
+        _ => (load_heightfield("resources/map_height.png"), load_texture("resources/map_color.png"), None),
+    };
 
-    let mut plane = renderer::reader::unit_plane(dimensions.0 as usize, dimensions.1 as usize, 0x00FF00);
+    let mut plane = renderer::reader::unit_plane(dimensions.0, dimensions.1, 0x00FF00);
     let mut camera = Camera {
         fov: 90.0,
         near: 0.1,
@@ -127,20 +259,275 @@ fn run_heightmap_display() {
     });
 
     //displace plane
-    modifiers::modifiers::displace_plane(&mut plane, &heightmap, 30.0);
-    modifiers::modifiers::colorize_plane(&mut plane, &colormap);
+    modifiers::modifiers::displace_plane(&mut plane, &heightfield, 30.0, MapFilter::Bicubic);
+    modifiers::modifiers::colorize_plane(&mut plane, &colormap, MapFilter::Bilinear);
 
 
+    // the water surface lies on the water levels, the mask cuts it to the rivers, lakes and sea
+    let water = generated.as_ref().and_then(|terrain| terrain.water.as_ref());
+    let water_plane = water.map(|water| {
+        let mut water_plane = renderer::reader::unit_plane(dimensions.0, dimensions.1, 0x1E90FF);
+        modifiers::modifiers::displace_plane(&mut water_plane, &water.level, 30.0, MapFilter::Bilinear);
+        water_plane
+    });
+    let water_mask = water.map(|water| Texture::from_image(&water.mask.to_image()));
+    let water_waves = default_waves();
+    let start_time = Instant::now();
+
     let rotation = Vector4::new(0.0, 0.0, 0.0, 0.0);
     let uni_size = 10.0;
     let scale = Vector4::new(uni_size / dimensions.0 as f32, uni_size / dimensions.0 as f32, uni_size / dimensions.0 as f32, 0.0);
     let position = Vector4::new(0.0, 1.0, 0.0, 0.0);
+
+    let transform_matrix = get_model_matrix(position, rotation, scale);
+    let world_sphere = plane.bounds().sphere.transformed(&transform_matrix);
+    let shadow_settings = ShadowSettings { resolution: 2048, ..Default::default() };
+
+    // the per pixel modes give the sun cascades along the view reaching to the far side of the
+    // terrain. they only cover the view they were fitted to
+    let aspect_ratio = window_size.0 as f32 / window_size.1 as f32;
+    let sun_cascades = |direction: nalgebra::Vector3<f32>, camera: &Camera| {
+        let shadow_distance = (camera.position.xyz() - world_sphere.center).norm() + world_sphere.radius;
+        let mut cascades = CascadedShadowMap::new(camera, aspect_ratio, direction, 3, shadow_distance, 0.5, shadow_settings);
+        cascades.render(&[(&plane, transform_matrix)]);
+        cascades
+    };
+
+    // per pixel lighting with a normal map made from the heightmap, the scale matches displace_plane
+    let mut color_texture = Texture::from_image(&colormap);
+    color_texture.generate_srgb_mipmaps(FilterType::Triangle);
+    let mut normal_texture = Texture::from_image(&modifiers::modifiers::height_to_normal_map(&heightfield, 30.0));
+    normal_texture.generate_mipmaps(FilterType::Triangle);
+    let roughness_texture = water_roughness.map(|roughness| {
+        let mut texture = Texture::from_image(&roughness);
+        texture.generate_mipmaps(FilterType::Triangle);
+        texture
+    });
+    let terrain_materials = MaterialSet::single(Material {
+        base_color_texture: Some(&color_texture),
+        normal_texture: Some(&normal_texture),
+        // the water map holds the roughness of land and water
+        roughness: if roughness_texture.is_some() { 1.0 } else { 0.9 },
+        metallic_roughness_texture: roughness_texture.as_ref(),
+        vertex_colors: false,
+        filter: TextureFilter::Trilinear,
+        ..Default::default()
+    });
+    let mut framebuffer = Framebuffer::new(window_size);
+    // the physically based terrain shading reflects albedo / pi of the sun, so it needs more exposure
+    let mut tone_mapper = ToneMapper::new(ToneMapping::AcesFilmic, 1.5);
+
+    // every mode is lit by an atmospheric sky and its sun, placed by the time of day at a mid
+    // latitude in summer. Z and X turn the clock, C runs the day night cycle
+    let (day_of_year, latitude) = (172.0, 47.0);
+    let mut time_of_day = 16.0;
+    let mut day_night_cycle = false;
+    // the sky is kept brighter against the sun than single scattering alone would make it, which
+    // misses the light bounced around the atmosphere more than once
+    let mut atmosphere = AtmosphericSky { sun_intensity: 6.0, ..Default::default() };
+    let sun_irradiance = 1.5;
+    let bake_daylight = |atmosphere: &AtmosphericSky| {
+        let sun = atmosphere.sun_light(sun_irradiance);
+        let sky_map = CubeMap::from_environment(atmosphere, 32);
+        let sky_light = ImageBasedLight::new(&sky_map, 32, 4, 16);
+        (sun, sky_map, sky_light)
+    };
+    atmosphere.sun_direction = sun_direction(time_of_day, day_of_year, latitude);
+    let (mut daylight, mut sky_map, mut sky_light) = bake_daylight(&atmosphere);
+    let mut daylight_shadow = sun_cascades(-atmosphere.sun_direction, &camera);
+    // the view the cascades were fitted to, None after the sun moved
+    let mut fitted_view = Some((camera.position, camera.look_at, camera.up));
+
+    // the baked mode on key 1 puts the same sun and sky, with the shadows of the mountains, into
+    // the vertex colors of a copy of the terrain. it has no tone mapper, so everything is brightened
+    // by baked_exposure to keep a white surface facing the sun at noon close to white. the bake has
+    // to cover all of the terrain, so the sun gets a single orthographic map around it
+    let baked_exposure = std::f32::consts::PI / sun_irradiance;
+    let baked_sky_tone_mapper = ToneMapper::new(ToneMapping::Clamp, baked_exposure.log2());
+    let mut baked_plane = Object3D::new(plane.vertices.clone(), plane.colors.clone(), vec![], plane.triangles.clone());
+    let bake_terrain = |baked_plane: &mut Object3D, atmosphere: &AtmosphericSky, sky_map: &CubeMap| {
+        baked_plane.colors.copy_from_slice(&plane.colors);
+        let sun = atmosphere.sun_light(sun_irradiance * baked_exposure);
+        // the sky radiance around 45 degrees up, a uniform sky of radiance l gives pi * l irradiance
+        let sky_color = (0..8).map(|i| {
+            let angle = i as f32 * std::f32::consts::PI / 4.0;
+            sky_map.radiance(&nalgebra::Vector3::new(angle.cos(), 1.0, angle.sin()))
+        }).sum::<nalgebra::Vector3<f32>>() / 8.0;
+        let sky = Light::Hemisphere {
+            up: nalgebra::Vector3::y(),
+            sky_color,
+            ground_color: sky_color.component_mul(&srgb_color_to_linear(0x605040)),
+            intensity: std::f32::consts::PI * baked_exposure,
+        };
+        let sun_shadow = sun.render_shadow(&[(&plane, transform_matrix)], world_sphere.center, world_sphere.radius, shadow_settings);
+        modifiers::modifiers::light_plane(baked_plane, &transform_matrix, &[sun, sky], &[sun_shadow.as_deref(), None]);
+    };
+    // the time of day baked_plane was lit for, it is only baked again while the baked mode is shown
+    let mut baked_time = None;
+
+    let terrain_occlusion = Ssao { radius: 1.0, strength: 1.5, ..Default::default() };
+    // light haze over the distance and denser fog sitting in the valleys, tinted like the horizon
+    let mut terrain_fog = Fog {
+        color: srgb_color_to_linear(0x9FB8D0),
+        falloff: FogFalloff::Exponential { density: 0.012 },
+        height_density: 0.5,
+        height_falloff: 2.0,
+        base_height: 0.8,
+        fog_background: false,
+    };
+    let terrain_occlusion_debug = Ssao { debug: true, ..terrain_occlusion };
+    let mut display_effects = PostProcessStack::new();
+    display_effects.push(Fxaa::default()).push(Dither::default());
+    let display_tone_mapper = ToneMapper::new(ToneMapping::Clamp, 0.0);
+    // 1: baked vertex lighting, 2: normal mapped per pixel lighting,
+    // 3: like 2 with anti aliasing and dithering after the tone mapper, 4: ambient occlusion,
+    // 5: deferred shading with lanterns all over the terrain, G cycles through the g-buffer channels
+    let mode_keys = [Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5];
+    let mut mode = 0;
+    let mut middle_was_down = false;
+
+    let plane_bvh = MeshBvh::build(&plane);
+    let scene_bvh = SceneBvh::build(vec![Instance::new(&plane, &plane_bvh, transform_matrix)]);
+
+    // a grid of small point lights dropped onto the terrain, too many to shade forward every frame
+    let lantern_colors = [0xFFB060, 0xFF8040, 0xFFD890, 0x90C0FF];
+    let lanterns: Vec<Light> = (0..64).filter_map(|i| {
+        let (x, z) = ((i % 8) as f32 - 3.5, (i / 8) as f32 - 3.5);
+        let origin = world_sphere.center + nalgebra::Vector3::new(x, world_sphere.radius, z);
+        let ray = renderer::bvh::Ray::new(origin, -nalgebra::Vector3::y());
+        let hit = scene_bvh.intersect_ray(&ray, 2.0 * world_sphere.radius)?;
+        Some(Light::Point {
+            position: ray.at(hit.hit.t) + nalgebra::Vector3::new(0.0, 0.1, 0.0),
+            color: srgb_color_to_linear(lantern_colors[i % lantern_colors.len()]),
+            intensity: 0.3,
+            range: 1.5,
+            attenuation: Attenuation::Smooth,
+        })
+    }).collect();
+    let mut gbuffer = GBuffer::new(window_size);
+    let mut gbuffer_channel: Option<usize> = None;
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        draw_object(&mut buffer, &plane, window_size, &camera, position, rotation, scale, Some(0x000000));
+        let mut stats = RenderStats::default();
+        if mode == 0 {
+            if baked_time != Some(time_of_day) {
+                bake_terrain(&mut baked_plane, &atmosphere, &sky_map);
+                baked_time = Some(time_of_day);
+            }
+            draw_environment(&mut buffer, window_size, &camera, &sky_map, &baked_sky_tone_mapper);
+            stats.record(draw_object(&mut buffer, &baked_plane, window_size, &camera, position, rotation, scale, None));
+        } else {
+            // the cascades follow the camera, they are fitted again whenever the view or the sun moved
+            let view = (camera.position, camera.look_at, camera.up);
+            if fitted_view != Some(view) {
+                daylight_shadow = sun_cascades(-atmosphere.sun_direction, &camera);
+                fitted_view = Some(view);
+            }
+            // the fog takes the average color around the horizon
+            terrain_fog.color = (0..8).map(|i| {
+                let angle = i as f32 * std::f32::consts::PI / 4.0;
+                sky_map.radiance(&nalgebra::Vector3::new(angle.cos(), 0.05, angle.sin()))
+            }).sum::<nalgebra::Vector3<f32>>() / 8.0;
+            // effects on the hdr image before and on the display values after the adjustable tone mapper
+            let mut hdr_effects = PostProcessStack::new();
+            hdr_effects.push(terrain_occlusion).push(terrain_fog).push(Bloom { threshold: 1.5, ..Default::default() });
+            let sun_lights = [daylight];
+            let sun_shadows = [Some(&daylight_shadow as &dyn ShadowCaster)];
+            framebuffer.clear(0x000000);
+            if mode == 4 {
+                // the sun keeps its shadow, the lanterns come after it
+                let terrain_lights: Vec<Light> = std::iter::once(daylight).chain(lanterns.iter().copied()).collect();
+                gbuffer.clear();
+                stats.record(draw_object_deferred(&mut gbuffer, &plane, &camera, &transform_matrix, &terrain_materials, 0));
+                shade_deferred(&gbuffer, &mut framebuffer, &camera, &terrain_lights, &sun_shadows, Some(&sky_light));
+            } else {
+                let terrain_shader = PbrShader {
+                    environment: Some(&sky_light),
+                    ..PbrShader::new(&plane, &terrain_materials, &sun_lights, &sun_shadows)
+                };
+                stats.record(draw_object_shaded(&mut framebuffer, &plane, &camera, &transform_matrix, &terrain_shader));
+            }
+            draw_skybox(&mut framebuffer, &camera, &sky_map);
+            // forward over the lit terrain in every per pixel mode, it needs the finished image behind
+            // it. the baked mode has no framebuffer to refract and shows the terrain without water
+            if let (Some(water_plane), Some(water_mask)) = (&water_plane, &water_mask) {
+                let background = framebuffer.clone();
+                let water_shader = WaterShader {
+                    mask: Some(water_mask),
+                    ..WaterShader::new(&background, &water_waves, start_time.elapsed().as_secs_f32(), &sun_lights, &sun_shadows, &sky_map)
+                };
+                stats.record(draw_object_shaded(&mut framebuffer, water_plane, &camera, &transform_matrix, &water_shader));
+            }
+            if mode == 3 {
+                terrain_occlusion_debug.apply(&mut framebuffer, &camera);
+                framebuffer.resolve(&display_tone_mapper, &mut buffer);
+            } else if let (4, Some(channel)) = (mode, gbuffer_channel) {
+                let preview = gbuffer.preview(GBufferChannel::ALL[channel], &camera);
+                for (color, value) in framebuffer.color.iter_mut().zip(preview) {
+                    *color = Vector4::new(value.x, value.y, value.z, 1.0);
+                }
+                framebuffer.resolve(&display_tone_mapper, &mut buffer);
+            } else if mode == 1 || mode == 4 {
+                hdr_effects.apply(&mut framebuffer, &camera);
+                framebuffer.resolve(&tone_mapper, &mut buffer);
+            } else {
+                hdr_effects.apply(&mut framebuffer, &camera);
+                PostEffect::apply(&tone_mapper, &mut framebuffer, &camera);
+                display_effects.apply(&mut framebuffer, &camera);
+                framebuffer.resolve(&display_tone_mapper, &mut buffer);
+            }
+        }
+        let channel_name = match (mode, gbuffer_channel) {
+            (4, Some(channel)) => format!(" - g-buffer {}", GBufferChannel::ALL[channel].name()),
+            _ => String::new(),
+        };
+        window.set_title(&format!(
+            "HEIGHTMAP DISPLAY - drawn: {} culled: {} - {:?} exposure {:+.1} - {:02}:{:02}{}",
+            stats.drawn, stats.culled, tone_mapper.operator, tone_mapper.exposure,
+            time_of_day as u32, (time_of_day.fract() * 60.0) as u32, channel_name,
+        ));
         window
             .update_with_buffer(&buffer, window_size.0, window_size.1)
             .unwrap();
 
+        if let Some(key) = mode_keys.iter().position(|key| window.is_key_down(*key)) {
+            mode = key;
+        }
+        // T cycles the tone mapping operator, Q and E change the exposure
+        if window.is_key_pressed(Key::T, KeyRepeat::No) {
+            tone_mapper.operator = tone_mapper.operator.next();
+        }
+        if window.is_key_down(Key::Q) {
+            tone_mapper.exposure -= 0.05;
+        }
+        if window.is_key_down(Key::E) {
+            tone_mapper.exposure += 0.05;
+        }
+        if window.is_key_pressed(Key::G, KeyRepeat::No) {
+            gbuffer_channel = match gbuffer_channel {
+                None => Some(0),
+                Some(channel) if channel + 1 < GBufferChannel::ALL.len() => Some(channel + 1),
+                Some(_) => None,
+            };
+        }
+        if window.is_key_pressed(Key::C, KeyRepeat::No) {
+            day_night_cycle = !day_night_cycle;
+        }
+        let previous_time = time_of_day;
+        if day_night_cycle {
+            time_of_day += 0.05;
+        }
+        if window.is_key_down(Key::Z) {
+            time_of_day -= 0.1;
+        }
+        if window.is_key_down(Key::X) {
+            time_of_day += 0.1;
+        }
+        if time_of_day != previous_time {
+            time_of_day = time_of_day.rem_euclid(24.0);
+            atmosphere.sun_direction = sun_direction(time_of_day, day_of_year, latitude);
+            (daylight, sky_map, sky_light) = bake_daylight(&atmosphere);
+            fitted_view = None;
+        }
         if window.get_mouse_down(minifb::MouseButton::Right) {
             if window.is_key_down(Key::Space) {
                 camera.position.y += 0.1;
@@ -156,11 +543,237 @@ fn run_heightmap_display() {
                 camera.position.y -= 0.1;
             }
         }
+        // picks once per click, the button stays down for several frames
+        let middle_down = window.get_mouse_down(minifb::MouseButton::Middle);
+        if middle_down && !middle_was_down {
+            if let Some((x, y)) = window.get_mouse_pos(minifb::MouseMode::Discard) {
+                let ray = camera.get_ray(window_size, x, y);
+                match scene_bvh.intersect_ray(&ray, camera.far) {
+                    Some(hit) => {
+                        let point = ray.at(hit.hit.t);
+                        println!("Picked terrain at ({:.3}, {:.3}, {:.3})", point.x, point.y, point.z);
+                    }
+                    None => println!("Nothing picked"),
+                }
+            }
+        }
+        middle_was_down = middle_down;
         if window.get_mouse_down(minifb::MouseButton::Left){
             let buffer_rgba = modifiers::modifiers::buffer_to_image_buffer_rgba(&buffer,(window_size.0 as u32, window_size.1 as u32));
             let buffer_rgb = modifiers::modifiers::buffer_to_image_buffer_rgb(&buffer,(window_size.0 as u32, window_size.1 as u32));
             modifiers::modifiers::save_image_to_desktop(&buffer_rgb, "RGB", "test");
             modifiers::modifiers::save_image_to_desktop(&buffer_rgba, "RGBA", "test");
+            if mode == 1 {
+                let radiance = modifiers::modifiers::radiance_to_image_buffer(&framebuffer.radiance(), (window_size.0 as u32, window_size.1 as u32));
+                modifiers::modifiers::save_hdr_to_desktop(&radiance, "terrain", "linear");
+                modifiers::modifiers::save_exr_to_desktop(&radiance, "terrain", "linear");
+            }
+            if mode == 4 {
+                modifiers::modifiers::save_gbuffer_to_desktop(&gbuffer, &camera, "terrain_gbuffer");
+            }
+            if let (Some(config), Some(terrain)) = (&map_config, &generated) {
+                modifiers::modifiers::save_image_to_desktop(&export_heightmap(config, &terrain.heightfield), &config.filename, "height");
+            }
+        }
+    }
+}
+
+fn run_raytraced_scene() {
+    let monkey = renderer::reader::read_obj("resources/monkey.obj");
+    let cube = renderer::reader::unit_cube(0xFFFFFF);
+    let sphere = renderer::reader::unit_sphere(0xFF0000);
+    let floor = renderer::reader::unit_plane(10, 10, 0x808080);
+    let objects = [&monkey, &cube, &sphere, &floor];
+    let bvhs: Vec<_> = objects.iter().map(|object| MeshBvh::build(object)).collect();
+
+    let scale = Vector4::new(1.0, 1.0, 1.0, 0.0);
+    let transforms = [
+        get_model_matrix(Vector4::new(0.0, 0.0, 0.0, 0.0), Vector4::new(0.0, 10.0, 180.0, 0.0), scale),
+        get_model_matrix(Vector4::new(-2.0, 2.0, 0.0, 0.0), Vector4::new(0.0, 30.0, 0.0, 0.0), scale),
+        get_model_matrix(Vector4::new(2.0, 2.5, 5.0, 0.0), Vector4::new(0.0, 0.0, 0.0, 0.0), scale),
+        get_model_matrix(Vector4::new(0.0, -2.0, 0.0, 0.0), Vector4::new(0.0, 0.0, 0.0, 0.0), Vector4::new(2.0, 1.0, 2.0, 0.0)),
+    ];
+    let materials = [
+        TracerMaterial { specular: 0.5, ..Default::default() },
+        TracerMaterial { reflectivity: 0.6, ..Default::default() },
+        TracerMaterial { transparency: 0.9, refractive_index: 1.5, specular: 1.0, shininess: 128.0, ..Default::default() },
+        TracerMaterial::default(),
+    ];
+    let scene = SceneBvh::build(objects.iter().zip(bvhs.iter()).zip(transforms.iter())
+        .map(|((object, bvh), transform)| Instance::new(object, bvh, *transform))
+        .collect());
+    let lights = [
+        Light::Point {
+            position: nalgebra::Vector3::new(-5.0, 10.0, -10.0),
+            color: srgb_color_to_linear(0xFFFFFF),
+            intensity: 250.0,
+            range: 100.0,
+            attenuation: Attenuation::Smooth,
+        },
+        Light::Hemisphere {
+            up: nalgebra::Vector3::y(),
+            sky_color: srgb_color_to_linear(0x8FB8FF),
+            ground_color: srgb_color_to_linear(0x403020),
+            intensity: 0.2,
+        },
+    ];
+    let raytracer = Raytracer {
+        scene: &scene,
+        materials: &materials,
+        lights: &lights,
+        settings: RaytracerSettings { background_color: 0x203040, ambient: 0.0, ..Default::default() },
+    };
+
+    let mut camera = Camera {
+        fov: 25.0,
+        near: 0.1,
+        up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+        far: 1000.0,
+        position: Vector4::new(0.0, 0.0, -20.0, 1.0),
+        look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+    };
+    let dimensions = (1024, 800);
+    let mut buffer = vec![0u32; dimensions.0 * dimensions.1];
+    let mut window = Window::new(
+        "RAY TRACER",
+        dimensions.0,
+        dimensions.1,
+        WindowOptions {
+            scale: Scale::X1,
+            ..Default::default()
+        },
+    ).unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+
+    // tracing is too slow to run every frame, so the image is only refreshed after the camera moved
+    let mut dirty = true;
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        if dirty {
+            raytracer.render(&mut buffer, dimensions, &camera);
+            dirty = false;
+        }
+        window
+            .update_with_buffer(&buffer, dimensions.0, dimensions.1)
+            .unwrap();
+
+        if window.is_key_down(Key::A) {
+            camera.rotate_around_look_at(camera.up, 0.1);
+            dirty = true;
+        }
+        if window.is_key_down(Key::D) {
+            camera.rotate_around_look_at(camera.up, -0.1);
+            dirty = true;
+        }
+        if window.get_mouse_down(minifb::MouseButton::Left) {
+            let buffer_rgb = modifiers::modifiers::buffer_to_image_buffer_rgb(&buffer, (dimensions.0 as u32, dimensions.1 as u32));
+            modifiers::modifiers::save_image_to_desktop(&buffer_rgb, "raytraced", "RGB");
+        }
+    }
+}
+
+fn run_pathtraced_scene() {
+    let monkey = renderer::reader::read_obj("resources/monkey.obj");
+    let cube = renderer::reader::unit_cube(0xD4AF37);
+    let sphere = renderer::reader::unit_sphere(0xFFFFFF);
+    let floor = renderer::reader::unit_plane(10, 10, 0xB0B0B0);
+    let lamp = renderer::reader::unit_cube(0xFFFFFF);
+    let objects = [&monkey, &cube, &sphere, &floor, &lamp];
+    let bvhs: Vec<_> = objects.iter().map(|object| MeshBvh::build(object)).collect();
+
+    let scale = Vector4::new(1.0, 1.0, 1.0, 0.0);
+    let rotation = Vector4::new(0.0, 0.0, 0.0, 0.0);
+    let transforms = [
+        get_model_matrix(Vector4::new(0.0, 0.0, 0.0, 0.0), Vector4::new(0.0, 10.0, 180.0, 0.0), scale),
+        get_model_matrix(Vector4::new(-2.0, 2.0, 0.0, 0.0), Vector4::new(0.0, 30.0, 0.0, 0.0), scale),
+        get_model_matrix(Vector4::new(2.0, 2.5, 5.0, 0.0), rotation, scale),
+        get_model_matrix(Vector4::new(0.0, -2.0, 0.0, 0.0), rotation, Vector4::new(2.0, 1.0, 2.0, 0.0)),
+        get_model_matrix(Vector4::new(3.0, 6.0, -2.0, 0.0), rotation, Vector4::new(1.0, 0.1, 1.0, 0.0)),
+    ];
+    let materials = [
+        PathMaterial::Microfacet { roughness: 0.6, metallic: 0.0 },
+        PathMaterial::Microfacet { roughness: 0.25, metallic: 1.0 },
+        PathMaterial::Glass { refractive_index: 1.5 },
+        PathMaterial::Diffuse,
+        PathMaterial::Emissive { color: nalgebra::Vector3::new(1.0, 0.9, 0.8), strength: 8.0 },
+    ];
+    let scene = SceneBvh::build(objects.iter().zip(bvhs.iter()).zip(transforms.iter())
+        .map(|((object, bvh), transform)| Instance::new(object, bvh, *transform))
+        .collect());
+    let lights = [
+        Light::Point {
+            position: nalgebra::Vector3::new(-5.0, 10.0, -10.0),
+            color: srgb_color_to_linear(0xFFFFFF),
+            intensity: 150.0,
+            range: f32::INFINITY,
+            attenuation: Attenuation::InverseSquare,
+        },
+        Light::Spot {
+            position: nalgebra::Vector3::new(4.0, 8.0, 0.0),
+            direction: nalgebra::Vector3::new(-0.5, -1.0, 0.0),
+            color: srgb_color_to_linear(0xFFB070),
+            intensity: 80.0,
+            range: 30.0,
+            attenuation: Attenuation::Smooth,
+            inner_angle: 20.0,
+            outer_angle: 35.0,
+        },
+    ];
+    let environment = ConstantEnvironment(nalgebra::Vector3::new(0.15, 0.2, 0.3));
+    let tracer = PathTracer {
+        scene: &scene,
+        materials: &materials,
+        lights: &lights,
+        environment: &environment,
+        settings: PathTracerSettings::default(),
+    };
+
+    let mut camera = Camera {
+        fov: 25.0,
+        near: 0.1,
+        up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+        far: 1000.0,
+        position: Vector4::new(0.0, 0.0, -20.0, 1.0),
+        look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+    };
+    let dimensions = (640, 480);
+    let mut buffer = vec![0u32; dimensions.0 * dimensions.1];
+    let mut accumulator = Accumulator::new(dimensions);
+    let tone_mapper = ToneMapper::default();
+    let mut window = Window::new(
+        "PATH TRACER",
+        dimensions.0,
+        dimensions.1,
+        WindowOptions {
+            scale: Scale::X1,
+            ..Default::default()
+        },
+    ).unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        accumulator.add_pass(&tracer, &camera);
+        accumulator.resolve(&tone_mapper, &mut buffer);
+        window.set_title(&format!("PATH TRACER - {} samples per pixel", accumulator.passes));
+        window
+            .update_with_buffer(&buffer, dimensions.0, dimensions.1)
+            .unwrap();
+
+        if window.is_key_down(Key::A) {
+            camera.rotate_around_look_at(camera.up, 0.1);
+            accumulator.reset();
+        }
+        if window.is_key_down(Key::D) {
+            camera.rotate_around_look_at(camera.up, -0.1);
+            accumulator.reset();
+        }
+        if window.get_mouse_down(minifb::MouseButton::Left) {
+            let buffer_rgb = modifiers::modifiers::buffer_to_image_buffer_rgb(&buffer, (dimensions.0 as u32, dimensions.1 as u32));
+            let radiance = modifiers::modifiers::radiance_to_image_buffer(&accumulator.average(), (dimensions.0 as u32, dimensions.1 as u32));
+            modifiers::modifiers::save_image_to_desktop(&buffer_rgb, "pathtraced", "RGB");
+            modifiers::modifiers::save_exr_to_desktop(&radiance, "pathtraced", "linear");
+            modifiers::modifiers::save_hdr_to_desktop(&radiance, "pathtraced", "linear");
         }
     }
 }
@@ -169,6 +782,8 @@ pub fn run() {
     println!("Please enter the scene you want to Open:");
     println!("0: Open the Debug Scene");
     println!("1: Open the Heightmap Renderer");
+    println!("2: Open the Ray Traced Scene");
+    println!("3: Open the Path Traced Scene");
 
     let mut input = String::new();
     io::stdin().read_line(&mut input).expect("failed to read from stdin");
@@ -179,16 +794,16 @@ pub fn run() {
                 run_debug_scene();
             } else if i == 1 {
                 run_heightmap_display();
+            } else if i == 2 {
+                run_raytraced_scene();
+            } else if i == 3 {
+                run_pathtraced_scene();
             } else {
                 println!("Can't detect scene to open")
             }
         }
-        Err(e) => {
+        Err(_) => {
             println!("Not a valid scene value provided");
         }
     }
 }
-
-fn main() {
-    run();
-}
\ No newline at end of file
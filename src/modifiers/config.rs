@@ -0,0 +1,296 @@
+use std::error::Error;
+use std::fs;
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+
+// enums the terrain tool stores as plain numbers
+macro_rules! numbered_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:literal),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(try_from = "u8", into = "u8")]
+        pub enum $name {
+            $($variant = $value),*
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = String;
+
+            fn try_from(value: u8) -> Result<$name, String> {
+                match value {
+                    $($value => Ok($name::$variant),)*
+                    _ => Err(format!("unknown {} {}", stringify!($name), value)),
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(value: $name) -> u8 {
+                value as u8
+            }
+        }
+    };
+}
+
+numbered_enum!(
+    // how a noise layer is combined with the heights below it, see modifiers::terrain::blend.
+    // numbered in the order the terrain tool lists them, normal, add, multiply, screen, overlay,
+    // the modes it does not have come after
+    BlendMode {
+        Normal = 0,
+        Add = 1,
+        Multiply = 2,
+        Screen = 3,
+        Overlay = 4,
+        Subtract = 5,
+        Darken = 6,
+        Lighten = 7,
+        Difference = 8,
+        SoftLight = 9,
+    }
+);
+
+numbered_enum!(
+    NoiseKind {
+        Perlin = 0,
+        Simplex = 1,
+        Value = 2,
+        Worley = 3,
+    }
+);
+
+numbered_enum!(
+    FractalKind {
+        Fbm = 0,
+        Ridged = 1,
+        Billow = 2,
+    }
+);
+
+numbered_enum!(
+    ErosionMode {
+        None = 0,
+        Thermal = 1,
+        Hydraulic = 2,
+    }
+);
+
+numbered_enum!(
+    // resampling of the exported heightmap, in the order of image::imageops::FilterType
+    ExportFilter {
+        Nearest = 0,
+        Triangle = 1,
+        CatmullRom = 2,
+        Gaussian = 3,
+        Lanczos3 = 4,
+    }
+);
+
+impl ExportFilter {
+    pub fn filter_type(&self) -> FilterType {
+        match self {
+            ExportFilter::Nearest => FilterType::Nearest,
+            ExportFilter::Triangle => FilterType::Triangle,
+            ExportFilter::CatmullRom => FilterType::CatmullRom,
+            ExportFilter::Gaussian => FilterType::Gaussian,
+            ExportFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+// which noise is summed up how. the terrain tool does not write these, its files get perlin fbm
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoiseSettings {
+    pub noise: NoiseKind,
+    pub fractal: FractalKind,
+    pub octaves: u32,
+    // frequency factor from one octave to the next
+    pub lacunarity: f32,
+    // amplitude factor from one octave to the next
+    pub gain: f32,
+}
+
+impl Default for NoiseSettings {
+    fn default() -> NoiseSettings {
+        NoiseSettings {
+            noise: NoiseKind::Perlin,
+            fractal: FractalKind::Fbm,
+            octaves: 5,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+// noise blended over the base noise with the opacity, offsets are in heightmap pixels
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NoiseLayer {
+    pub scale: f32,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub seed: u32,
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+    #[serde(flatten)]
+    pub noise: NoiseSettings,
+}
+
+// settings of the terrain tool as saved in map_config.json. missing fields take the defaults
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MapConfig {
+    // base noise, frequency per heightmap pixel and offsets in pixels
+    pub scale: f32,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    pub seed: u32,
+    #[serde(flatten)]
+    pub noise: NoiseSettings,
+    // the tool writes the layers as a json string inside the json
+    #[serde(with = "json_string")]
+    pub layers: Vec<NoiseLayer>,
+    pub erosion_mode: ErosionMode,
+    pub erosion_iterations: u32,
+    // steepest stable height difference between neighbouring pixels, heights are 0..1
+    pub talus_angle: f32,
+    // raises the heights below the ground level to flat land, unless as_water makes it sea
+    pub flatten_enabled: bool,
+    // 0..255 like the heightmap pixels
+    pub ground_level: f32,
+    pub calculate_rivers: bool,
+    pub river_iterations: u32,
+    pub erosion_factor: f32,
+    pub river_amount: u32,
+    pub river_seed: u32,
+    // exported files are named after it, without extension
+    pub filename: String,
+    // the exported heightmap is 2^export_scale times the generated size
+    pub export_scale: u32,
+    pub export_filter: ExportFilter,
+    // heights below the ground level become water instead of flat land
+    pub as_water: bool,
+    // worker threads of the generation, 0 uses all cores. the heights are the same for any number,
+    // the tool does not write it
+    #[serde(skip)]
+    pub threads: usize,
+}
+
+impl Default for MapConfig {
+    fn default() -> MapConfig {
+        MapConfig {
+            scale: 0.01,
+            offset_x: 0.0,
+            offset_y: 0.0,
+            seed: 0,
+            noise: NoiseSettings::default(),
+            layers: vec![],
+            erosion_mode: ErosionMode::None,
+            erosion_iterations: 0,
+            talus_angle: 0.01,
+            flatten_enabled: false,
+            ground_level: 0.0,
+            calculate_rivers: false,
+            river_iterations: 0,
+            erosion_factor: 1.0,
+            river_amount: 0,
+            river_seed: 0,
+            filename: "map".to_string(),
+            export_scale: 0,
+            export_filter: ExportFilter::Triangle,
+            as_water: false,
+            threads: 0,
+        }
+    }
+}
+
+impl MapConfig {
+    pub fn parse(text: &str) -> Result<MapConfig, Box<dyn Error>> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    pub fn load(path: &str) -> Result<MapConfig, Box<dyn Error>> {
+        MapConfig::parse(&fs::read_to_string(path)?)
+    }
+
+    // writes the same format the tool reads, layers included as a string
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // ground level in 0..1 heights
+    pub fn ground_height(&self) -> f32 {
+        self.ground_level / 255.0
+    }
+
+    pub fn export_size(&self, dimensions: (u32, u32)) -> (u32, u32) {
+        let factor = 1u32 << self.export_scale.min(4);
+        (dimensions.0 * factor, dimensions.1 * factor)
+    }
+}
+
+// (de)serializes a value as json text inside a json string
+mod json_string {
+    use serde::de::{DeserializeOwned, Error as _};
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&serde_json::to_string(value).map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        serde_json::from_str(&text).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP_CONFIG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/map_config.json");
+
+    #[test]
+    fn parses_the_shipped_map_config() {
+        let config = MapConfig::load(MAP_CONFIG).unwrap();
+        assert_eq!(config.seed, 1410);
+        // the layers are a json string inside the json
+        assert_eq!(config.layers.len(), 4);
+        assert_eq!(config.layers[1].scale, 0.1);
+        assert_eq!(config.layers[3].opacity, 0.219_025_51);
+        assert!(config.layers.iter().all(|layer| layer.seed == 1 && layer.blend_mode == BlendMode::Multiply));
+        assert_eq!(config.erosion_mode, ErosionMode::Thermal);
+        assert_eq!(config.export_filter, ExportFilter::Gaussian);
+        // written as an integer
+        assert_eq!(config.ground_level, 95.0);
+        assert_eq!(config.ground_height(), 95.0 / 255.0);
+        assert!(config.flatten_enabled && config.calculate_rivers && config.as_water);
+    }
+
+    #[test]
+    fn rejects_unknown_enum_numbers() {
+        assert!(MapConfig::parse(r#"{"erosion_mode": 7}"#).is_err());
+        assert!(MapConfig::parse(r#"{"layers": "[{\"scale\":1,\"offset_x\":0,\"offset_y\":0,\"seed\":0,\"opacity\":1,\"blend_mode\":10}]"}"#).is_err());
+    }
+
+    #[test]
+    fn missing_fields_take_the_defaults() {
+        assert_eq!(MapConfig::parse("{}").unwrap(), MapConfig::default());
+    }
+
+    #[test]
+    fn save_and_parse_round_trip() {
+        let config = MapConfig::load(MAP_CONFIG).unwrap();
+        let path = std::env::temp_dir().join(format!("map_config_round_trip_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        config.save(path).unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        // the layers stay a string, so the tool can read the file again
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert!(value["layers"].is_string());
+        assert_eq!(MapConfig::parse(&text).unwrap(), config);
+    }
+}
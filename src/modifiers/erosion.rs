@@ -0,0 +1,410 @@
+use std::f32::consts::SQRT_2;
+use std::thread;
+
+use super::super::renderer::math::Rng;
+use super::config::MapConfig;
+use super::terrain::Heightfield;
+
+// which pixels count as neighbours, the 8 neighbourhood also moves material diagonally
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Neighbourhood {
+    Four,
+    Eight,
+}
+
+impl Neighbourhood {
+    // offsets and their distance in pixels
+    fn offsets(&self) -> &'static [(i32, i32, f32)] {
+        const FOUR: [(i32, i32, f32); 4] = [(1, 0, 1.0), (-1, 0, 1.0), (0, 1, 1.0), (0, -1, 1.0)];
+        const EIGHT: [(i32, i32, f32); 8] = [
+            (1, 0, 1.0), (-1, 0, 1.0), (0, 1, 1.0), (0, -1, 1.0),
+            (1, 1, SQRT_2), (-1, 1, SQRT_2), (1, -1, SQRT_2), (-1, -1, SQRT_2),
+        ];
+        match self {
+            Neighbourhood::Four => &FOUR,
+            Neighbourhood::Eight => &EIGHT,
+        }
+    }
+}
+
+// thermal weathering, material on slopes steeper than the talus angle slides down to the lower
+// neighbours until the slopes are stable (Musgrave et al. 1989)
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalErosion {
+    pub iterations: u32,
+    // steepest stable height difference per pixel of distance, heights are 0..1
+    pub talus_angle: f32,
+    // part of the excess material moved per iteration, 0.5 levels a slope of two pixels at once
+    pub rate: f32,
+    pub neighbourhood: Neighbourhood,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> ThermalErosion {
+        ThermalErosion { iterations: 50, talus_angle: 0.01, rate: 0.5, neighbourhood: Neighbourhood::Eight }
+    }
+}
+
+impl ThermalErosion {
+    pub fn from_config(config: &MapConfig) -> ThermalErosion {
+        ThermalErosion { iterations: config.erosion_iterations, talus_angle: config.talus_angle, ..Default::default() }
+    }
+
+    // all pixels move their material at the same time, so the result does not depend on the order
+    pub fn apply(&self, heightfield: &mut Heightfield) {
+        let (width, height) = (heightfield.width as i32, heightfield.height as i32);
+        let offsets = self.neighbourhood.offsets();
+        let mut change = vec![0.0; heightfield.heights.len()];
+        for _ in 0..self.iterations {
+            change.iter_mut().for_each(|value| *value = 0.0);
+            for y in 0..height {
+                for x in 0..width {
+                    let index = (y * width + x) as usize;
+                    let center = heightfield.heights[index];
+                    // how far every lower neighbour lies below its stable height
+                    let mut excess = [(0usize, 0.0f32); 8];
+                    let (mut count, mut total, mut steepest) = (0, 0.0, 0.0f32);
+                    for &(dx, dy, distance) in offsets {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                            continue;
+                        }
+                        let neighbour = (ny * width + nx) as usize;
+                        let difference = center - heightfield.heights[neighbour] - self.talus_angle * distance;
+                        if difference > 0.0 {
+                            excess[count] = (neighbour, difference);
+                            count += 1;
+                            total += difference;
+                            steepest = steepest.max(difference);
+                        }
+                    }
+                    if count == 0 {
+                        continue;
+                    }
+                    // half of the steepest excess keeps the pixel from ending up below the neighbour
+                    let moved = self.rate * steepest * 0.5;
+                    change[index] -= moved;
+                    for &(neighbour, difference) in &excess[..count] {
+                        change[neighbour] += moved * difference / total;
+                    }
+                }
+            }
+            for (height, change) in heightfield.heights.iter_mut().zip(change.iter()) {
+                *height += change;
+            }
+        }
+    }
+}
+
+// where the droplets of the hydraulic erosion went, both 0..1 per heightmap pixel
+#[derive(Clone, Debug)]
+pub struct ErosionMaps {
+    // water that ran over the pixel, high along the channels
+    pub flow: Heightfield,
+    // material that was dropped on the pixel, high in the valleys and on the fans
+    pub sediment: Heightfield,
+}
+
+// one step of a droplet, applied to the heights after its batch ran
+#[derive(Clone, Copy, Debug)]
+struct DropletStep {
+    x: f32,
+    y: f32,
+    // dropped material if positive, picked up material if negative
+    change: f32,
+    // water times speed
+    flow: f32,
+}
+
+// particle based hydraulic erosion, droplets run downhill, pick up material where they speed up
+// and drop it where they slow down or the water evaporates (Beyer 2015)
+#[derive(Clone, Copy, Debug)]
+pub struct HydraulicErosion {
+    pub droplets: u32,
+    pub seed: u32,
+    pub lifetime: u32,
+    // how much of its direction a droplet keeps instead of following the slope
+    pub inertia: f32,
+    // sediment a droplet can carry per slope, speed and water
+    pub capacity: f32,
+    pub min_capacity: f32,
+    // part of the free capacity picked up per step
+    pub erosion_rate: f32,
+    // part of the excess sediment dropped per step
+    pub deposition_rate: f32,
+    // part of the water lost per step
+    pub evaporation_rate: f32,
+    pub gravity: f32,
+    // material is picked up from all pixels within the radius, keeps the droplets from digging pits
+    pub brush_radius: f32,
+    // worker threads, 0 uses all cores. the heights are the same for any number
+    pub threads: usize,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> HydraulicErosion {
+        HydraulicErosion {
+            droplets: 50000,
+            seed: 0,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation_rate: 0.01,
+            gravity: 4.0,
+            brush_radius: 3.0,
+            threads: 0,
+        }
+    }
+}
+
+impl HydraulicErosion {
+    // erosion_factor is the number of droplets per 16 pixels, so the look does not change with the size
+    pub fn from_config(config: &MapConfig, dimensions: (usize, usize)) -> HydraulicErosion {
+        let droplets = (dimensions.0 * dimensions.1) as f32 * config.erosion_factor.max(0.0) / 16.0;
+        HydraulicErosion { droplets: droplets as u32, seed: config.seed, threads: config.threads, ..Default::default() }
+    }
+
+    pub fn apply(&self, heightfield: &mut Heightfield) {
+        self.erode(heightfield, None);
+    }
+
+    // like apply, also returns where the water flowed and the sediment was dropped
+    pub fn apply_with_maps(&self, heightfield: &mut Heightfield) -> ErosionMaps {
+        let mut maps = ErosionMaps { flow: Heightfield::new(heightfield.width, heightfield.height), sediment: Heightfield::new(heightfield.width, heightfield.height) };
+        self.erode(heightfield, Some(&mut maps));
+        // the totals are spread very unevenly, v / (v + mean) keeps the faint paths visible
+        for map in [&mut maps.flow, &mut maps.sediment] {
+            let mean = (map.heights.iter().sum::<f32>() / map.heights.len().max(1) as f32).max(1e-9);
+            map.heights.iter_mut().for_each(|value| *value /= *value + mean);
+        }
+        maps
+    }
+
+    // the droplets run in batches on the threads against the heights before the batch, then their
+    // steps are applied in droplet order. the result is the same for any number of threads
+    fn erode(&self, heightfield: &mut Heightfield, mut maps: Option<&mut ErosionMaps>) {
+        let (width, height) = (heightfield.width, heightfield.height);
+        if width < 2 || height < 2 {
+            return;
+        }
+        let brush = self.brush();
+        let threads = match self.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        // small batches so few droplets of a batch cross each other
+        let batch_size = (width * height / 256).max(256) as u32;
+        let mut steps = Vec::new();
+        for batch in (0..self.droplets).step_by(batch_size as usize) {
+            let droplets: Vec<u32> = (batch..(batch + batch_size).min(self.droplets)).collect();
+            let heights = &*heightfield;
+            let chunks: Vec<Vec<DropletStep>> = thread::scope(|scope| {
+                let workers: Vec<_> = droplets.chunks(droplets.len().div_ceil(threads).max(1)).map(|chunk| {
+                    scope.spawn(move || {
+                        let mut steps = Vec::new();
+                        chunk.iter().for_each(|&droplet| self.run_droplet(heights, droplet, &mut steps));
+                        steps
+                    })
+                }).collect();
+                workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+            });
+            steps.clear();
+            chunks.into_iter().for_each(|chunk| steps.extend(chunk));
+            for step in &steps {
+                let (ix, iy) = (step.x as usize, step.y as usize);
+                let (fx, fy) = (step.x - ix as f32, step.y - iy as f32);
+                if step.change > 0.0 {
+                    // dropped bilinearly on the four pixels around the droplet
+                    let index = iy * width + ix;
+                    heightfield.heights[index] += step.change * (1.0 - fx) * (1.0 - fy);
+                    heightfield.heights[index + 1] += step.change * fx * (1.0 - fy);
+                    heightfield.heights[index + width] += step.change * (1.0 - fx) * fy;
+                    heightfield.heights[index + width + 1] += step.change * fx * fy;
+                } else {
+                    for &(dx, dy, weight) in &brush {
+                        let (x, y) = (ix as i32 + dx, iy as i32 + dy);
+                        if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                            continue;
+                        }
+                        let value = &mut heightfield.heights[y as usize * width + x as usize];
+                        *value -= (-step.change * weight).min(*value);
+                    }
+                }
+                if let Some(maps) = maps.as_deref_mut() {
+                    let index = iy * width + ix;
+                    maps.flow.heights[index] += step.flow;
+                    maps.sediment.heights[index] += step.change.max(0.0);
+                }
+            }
+        }
+    }
+
+    // offsets within the radius weighted by the distance to the border, they sum up to 1
+    fn brush(&self) -> Vec<(i32, i32, f32)> {
+        let reach = self.brush_radius.ceil() as i32;
+        let mut brush = vec![];
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                let weight = self.brush_radius - ((dx * dx + dy * dy) as f32).sqrt();
+                if weight > 0.0 {
+                    brush.push((dx, dy, weight));
+                }
+            }
+        }
+        let total: f32 = brush.iter().map(|(_, _, weight)| weight).sum();
+        brush.iter_mut().for_each(|(_, _, weight)| *weight /= total.max(1e-6));
+        brush
+    }
+
+    fn run_droplet(&self, heightfield: &Heightfield, droplet: u32, steps: &mut Vec<DropletStep>) {
+        let (width, height) = ((heightfield.width - 1) as f32, (heightfield.height - 1) as f32);
+        // every droplet has its own stream, so it starts at the same place however the batch is split
+        let mut rng = Rng::new(self.seed as u64, droplet as u64);
+        let (mut x, mut y) = (rng.range(0.0, width), rng.range(0.0, height));
+        let (mut direction_x, mut direction_y) = (0.0f32, 0.0f32);
+        let (mut speed, mut water, mut sediment) = (1.0f32, 1.0f32, 0.0f32);
+        for _ in 0..self.lifetime {
+            let (current, gradient_x, gradient_y) = height_and_gradient(heightfield, x, y);
+            direction_x = direction_x * self.inertia - gradient_x * (1.0 - self.inertia);
+            direction_y = direction_y * self.inertia - gradient_y * (1.0 - self.inertia);
+            let length = (direction_x * direction_x + direction_y * direction_y).sqrt();
+            // flat ground, the droplet has nowhere to go
+            if length < 1e-9 {
+                break;
+            }
+            (direction_x, direction_y) = (direction_x / length, direction_y / length);
+            let (next_x, next_y) = (x + direction_x, y + direction_y);
+            if next_x < 0.0 || next_y < 0.0 || next_x >= width || next_y >= height {
+                break;
+            }
+            let difference = height_and_gradient(heightfield, next_x, next_y).0 - current;
+            let capacity = (-difference * speed * water * self.capacity).max(self.min_capacity);
+            let change = if difference > 0.0 {
+                // uphill, fill the pit behind the droplet as far as the sediment goes
+                sediment.min(difference)
+            } else if sediment > capacity {
+                (sediment - capacity) * self.deposition_rate
+            } else {
+                // never more than the height difference, the droplet would dig a hole otherwise
+                -((capacity - sediment) * self.erosion_rate).min(-difference)
+            };
+            sediment -= change;
+            steps.push(DropletStep { x, y, change, flow: water * speed });
+            speed = (speed * speed - difference * self.gravity).max(0.0).sqrt();
+            water *= 1.0 - self.evaporation_rate;
+            (x, y) = (next_x, next_y);
+        }
+    }
+}
+
+// bilinear height and its gradient at a position between the pixels, x and y below the last pixel
+fn height_and_gradient(heightfield: &Heightfield, x: f32, y: f32) -> (f32, f32, f32) {
+    let (ix, iy) = (x as usize, y as usize);
+    let (fx, fy) = (x - ix as f32, y - iy as f32);
+    let index = iy * heightfield.width + ix;
+    let heights = &heightfield.heights;
+    let (h00, h10) = (heights[index], heights[index + 1]);
+    let (h01, h11) = (heights[index + heightfield.width], heights[index + heightfield.width + 1]);
+    let gradient_x = (h10 - h00) * (1.0 - fy) + (h11 - h01) * fy;
+    let gradient_y = (h01 - h00) * (1.0 - fx) + (h11 - h10) * fx;
+    let value = h00 * (1.0 - fx) * (1.0 - fy) + h10 * fx * (1.0 - fy) + h01 * (1.0 - fx) * fy + h11 * fx * fy;
+    (value, gradient_x, gradient_y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a few hills, so the droplets have somewhere to run
+    fn hills() -> Heightfield {
+        Heightfield::from_fn(64, 48, |x, y| {
+            let (x, y) = (x as f32 * 0.2, y as f32 * 0.15);
+            0.5 + 0.25 * x.sin() * y.cos() + 0.1 * (x * 0.3 + y * 0.7).sin()
+        })
+    }
+
+    // a tall column in the middle of a flat field
+    fn spike() -> Heightfield {
+        Heightfield::from_fn(25, 25, |x, y| if (x, y) == (12, 12) { 1.0 } else { 0.1 })
+    }
+
+    // how far the steepest slope lies above the talus angle
+    fn steepest_excess(heightfield: &Heightfield, erosion: &ThermalErosion) -> f32 {
+        let (width, height) = (heightfield.width as i32, heightfield.height as i32);
+        let mut steepest = f32::MIN;
+        for y in 0..height {
+            for x in 0..width {
+                for &(dx, dy, distance) in erosion.neighbourhood.offsets() {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx >= 0 && ny >= 0 && nx < width && ny < height {
+                        let difference = heightfield.heights[(y * width + x) as usize] - heightfield.heights[(ny * width + nx) as usize];
+                        steepest = steepest.max(difference - erosion.talus_angle * distance);
+                    }
+                }
+            }
+        }
+        steepest
+    }
+
+    #[test]
+    fn thermal_erosion_conserves_material() {
+        for neighbourhood in [Neighbourhood::Four, Neighbourhood::Eight] {
+            // hills reaching the borders, nothing may slide off them
+            for mut heightfield in [spike(), hills()] {
+                let before: f64 = heightfield.heights.iter().map(|&h| h as f64).sum();
+                ThermalErosion { iterations: 100, neighbourhood, ..Default::default() }.apply(&mut heightfield);
+                let after: f64 = heightfield.heights.iter().map(|&h| h as f64).sum();
+                assert!((after - before).abs() < 1e-3, "{} {}", before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn thermal_erosion_settles_at_the_talus_angle() {
+        for neighbourhood in [Neighbourhood::Four, Neighbourhood::Eight] {
+            let erosion = ThermalErosion { iterations: 2000, talus_angle: 0.05, neighbourhood, ..Default::default() };
+            let mut heightfield = spike();
+            assert!(steepest_excess(&heightfield, &erosion) > 0.8);
+            erosion.apply(&mut heightfield);
+            assert!(steepest_excess(&heightfield, &erosion) < 1e-3, "{:?}", neighbourhood);
+            // the column became a cone, not a pit
+            assert!(heightfield.heights[12 * 25 + 12] > heightfield.heights[12 * 25 + 11]);
+        }
+    }
+
+    #[test]
+    fn only_the_eight_neighbourhood_moves_material_diagonally() {
+        let erode = |neighbourhood| {
+            let mut heightfield = spike();
+            ThermalErosion { iterations: 1, neighbourhood, ..Default::default() }.apply(&mut heightfield);
+            heightfield
+        };
+        let (four, eight) = (erode(Neighbourhood::Four), erode(Neighbourhood::Eight));
+        let diagonal = 11 * 25 + 11;
+        assert_eq!(four.heights[diagonal], 0.1);
+        assert!(eight.heights[diagonal] > 0.1);
+        assert!(four.heights[12 * 25 + 11] > eight.heights[12 * 25 + 11]);
+    }
+
+    #[test]
+    fn hydraulic_erosion_is_deterministic() {
+        let erosion = HydraulicErosion { droplets: 3000, seed: 5, ..Default::default() };
+        let mut first = hills();
+        let first_maps = erosion.apply_with_maps(&mut first);
+        assert_ne!(first, hills());
+        for threads in [0, 1, 3] {
+            let mut heightfield = hills();
+            let maps = HydraulicErosion { threads, ..erosion }.apply_with_maps(&mut heightfield);
+            assert_eq!(heightfield, first);
+            assert_eq!(maps.flow, first_maps.flow);
+            assert_eq!(maps.sediment, first_maps.sediment);
+        }
+        // the maps do not change the heights
+        let mut heightfield = hills();
+        erosion.apply(&mut heightfield);
+        assert_eq!(heightfield, first);
+    }
+}
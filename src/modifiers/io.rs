@@ -1,11 +1,49 @@
 
-use image::{ImageBuffer, Rgba};
+use image::{DynamicImage, ImageBuffer, Rgba};
+
+use super::super::renderer::math::srgb_to_linear;
+use super::terrain::Heightfield;
 
 pub fn load_texture(path: &str) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
     match image::open(path) {
-        Ok(img) => {
-            return img.to_rgba8();
-        },
+        Ok(img) => img.to_rgba8(),
+        Err(e) => panic!("Could not load texture: {}", e),
+    }
+}
+
+// heights with the precision of the file, 16 bit and float images are not rounded to 8 bit. the
+// first channel is the height, white is high
+pub fn load_heightfield(path: &str) -> Heightfield {
+    let image = match image::open(path) {
+        Ok(img) => img,
+        Err(e) => panic!("Could not load heightmap: {}", e),
+    };
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let heights = match image {
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) => {
+            image.to_luma16().pixels().map(|pixel| pixel[0] as f32 / 65535.0).collect()
+        }
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => image.to_rgba32f().pixels().map(|pixel| pixel[0]).collect(),
+        _ => image.to_rgba8().pixels().map(|pixel| pixel[0] as f32 / 255.0).collect(),
+    };
+    Heightfield { width, height, heights }
+}
+
+// float rgba with linear color channels. hdr and exr files already are linear, everything else is
+// treated as sRGB and linearized
+pub fn load_linear_texture(path: &str) -> ImageBuffer<Rgba<f32>, Vec<f32>> {
+    let image = match image::open(path) {
+        Ok(img) => img,
         Err(e) => panic!("Could not load texture: {}", e),
+    };
+    let linear = matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    let mut image = image.to_rgba32f();
+    if !linear {
+        for pixel in image.pixels_mut() {
+            for channel in 0..3 {
+                pixel[channel] = srgb_to_linear(pixel[channel]);
+            }
+        }
     }
-}
\ No newline at end of file
+    image
+}
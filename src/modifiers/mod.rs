@@ -1,2 +1,9 @@
+#[allow(clippy::module_inception)]
 pub mod modifiers;
-pub mod io;
\ No newline at end of file
+pub mod io;
+pub mod config;
+pub mod noise;
+pub mod terrain;
+pub mod erosion;
+pub mod rivers;
+pub mod shaping;
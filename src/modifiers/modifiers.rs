@@ -1,9 +1,18 @@
 extern crate dirs;
 
+use std::f32::consts::PI;
+
 use super::super::renderer::render;
-use render::Object3D;
-use image::{ImageBuffer, Rgba, imageops::FilterType};
+use super::super::renderer::shadow::ShadowCaster;
+use super::super::renderer::light::Light;
+use super::super::renderer::math::{linear_to_srgb, srgb_color_to_linear, vector_to_color};
+use super::super::renderer::deferred::{GBuffer, GBufferChannel};
+use render::{Camera, Object3D};
+use image::{ImageBuffer, ImageError, Luma, Rgb, Rgba, imageops::FilterType, codecs::hdr::HdrEncoder};
+use nalgebra::{Matrix4, Vector2, Vector3, Vector4};
 use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
 
 pub fn save_image_to_desktop(buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>, filename: &str, suffix: &str) {
     let desktop_path = dirs::desktop_dir();
@@ -26,6 +35,73 @@ pub fn save_image_to_desktop(buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>, filename:
     }
 }
 
+// keeps the linear floating point data, the image crate picks OpenEXR from the extension
+pub fn save_exr_to_desktop(buffer: &ImageBuffer<Rgb<f32>, Vec<f32>>, filename: &str, suffix: &str) {
+    let desktop_path = dirs::desktop_dir();
+    match desktop_path {
+        Some(path) => {
+            let full_path = path.join(format!("{}_{}.exr", filename, suffix));
+            println!("Desktop path: {}", full_path.display());
+            match buffer.save(full_path) {
+                Ok(_) => {
+                    println!("Image saved");
+                }
+                Err(e) => {
+                    println!("Couldn't save image: {}", e);
+                }
+            }
+        }
+        None => {
+            println!("Couldn't find desktop path");
+        }
+    }
+}
+
+// radiance hdr keeps the linear data in 32 bits per pixel with a shared exponent
+pub fn save_hdr_to_desktop(buffer: &ImageBuffer<Rgb<f32>, Vec<f32>>, filename: &str, suffix: &str) {
+    let desktop_path = dirs::desktop_dir();
+    match desktop_path {
+        Some(path) => {
+            let full_path = path.join(format!("{}_{}.hdr", filename, suffix));
+            println!("Desktop path: {}", full_path.display());
+            let result = File::create(&full_path).map_err(ImageError::IoError).and_then(|file| {
+                let pixels: Vec<Rgb<f32>> = buffer.pixels().copied().collect();
+                HdrEncoder::new(BufWriter::new(file)).encode(&pixels, buffer.width() as usize, buffer.height() as usize)
+            });
+            match result {
+                Ok(_) => {
+                    println!("Image saved");
+                }
+                Err(e) => {
+                    println!("Couldn't save image: {}", e);
+                }
+            }
+        }
+        None => {
+            println!("Couldn't find desktop path");
+        }
+    }
+}
+
+// every g-buffer channel twice, as exr with the raw values for compositing and as png preview
+pub fn save_gbuffer_to_desktop(gbuffer: &GBuffer, camera: &Camera, filename: &str) {
+    let dimensions = (gbuffer.dimensions.0 as u32, gbuffer.dimensions.1 as u32);
+    for channel in GBufferChannel::ALL {
+        save_exr_to_desktop(&radiance_to_image_buffer(&gbuffer.channel(channel, camera), dimensions), filename, channel.name());
+        let preview: Vec<u32> = gbuffer.preview(channel, camera).iter().map(|color| vector_to_color(color.map(linear_to_srgb))).collect();
+        save_image_to_desktop(&buffer_to_image_buffer_rgb(&preview, dimensions), filename, channel.name());
+    }
+}
+
+pub fn radiance_to_image_buffer(radiance: &[Vector3<f32>], dimensions: (u32, u32)) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+    let mut image_buffer = ImageBuffer::new(dimensions.0, dimensions.1);
+    for (x, y, pixel) in image_buffer.enumerate_pixels_mut() {
+        let color = radiance[(x + y * dimensions.0) as usize];
+        *pixel = Rgb([color.x, color.y, color.z]);
+    }
+    image_buffer
+}
+
 pub fn buffer_to_image_buffer_rgba(buffer: &[u32], dimensions: (u32, u32)) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
     let mut image_buffer = ImageBuffer::new(dimensions.0, dimensions.1);
     for (x, y, pixel) in image_buffer.enumerate_pixels_mut() {
@@ -61,39 +137,149 @@ fn rgba_to_u32(rgba: image::Rgba<u8>) -> u32 {
     ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
 }
 
-pub fn displace_plane(plane: &mut Object3D, heightmap: &ImageBuffer<Rgba<u8>, Vec<u8>>, scale: f32) {
-    let dimensions = heightmap.dimensions();
-    let width = dimensions.0;
-    let height = dimensions.1;
-    for x in 0..width {
-        for y in 0..height {
-            match plane.vertices.get_mut((x + y * width) as usize) {
-                Some(vertex) => {
-                    let pixel = heightmap.get_pixel(x, y);
-                    let height = (1.0 - (pixel[0] as f32 / 255.0)) * scale;
-                    vertex[1] = height;
-                    plane.vertices[(x + y * width) as usize] = vertex.clone();
+// grayscale heights in 0..1, white is high like the terrain tool exports them
+pub trait HeightSource {
+    fn dimensions(&self) -> (u32, u32);
+    fn height(&self, x: u32, y: u32) -> f32;
+}
+
+// 8 bit, the red channel
+impl HeightSource for ImageBuffer<Rgba<u8>, Vec<u8>> {
+    fn dimensions(&self) -> (u32, u32) {
+        ImageBuffer::dimensions(self)
+    }
+
+    fn height(&self, x: u32, y: u32) -> f32 {
+        self.get_pixel(x, y)[0] as f32 / 255.0
+    }
+}
+
+// 16 bit grayscale pngs, 256 times finer steps than 8 bit
+impl HeightSource for ImageBuffer<Luma<u16>, Vec<u16>> {
+    fn dimensions(&self) -> (u32, u32) {
+        ImageBuffer::dimensions(self)
+    }
+
+    fn height(&self, x: u32, y: u32) -> f32 {
+        self.get_pixel(x, y)[0] as f32 / 65535.0
+    }
+}
+
+// float heights are used as they are, they may leave 0..1
+impl HeightSource for ImageBuffer<Luma<f32>, Vec<f32>> {
+    fn dimensions(&self) -> (u32, u32) {
+        ImageBuffer::dimensions(self)
+    }
+
+    fn height(&self, x: u32, y: u32) -> f32 {
+        self.get_pixel(x, y)[0]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapFilter {
+    Bilinear,
+    // catmull-rom, sharper and without the creases bilinear leaves between the pixels
+    Bicubic,
+}
+
+// a map sampled at a uv like the renderer samples textures, pixel centers at (i + 0.5) / size and
+// the border pixels repeated beyond them. fetch returns the value of a pixel
+fn sample_map<F: Fn(u32, u32) -> Vector4<f32>>(dimensions: (u32, u32), uv: &Vector2<f32>, filter: MapFilter, fetch: F) -> Vector4<f32> {
+    let (width, height) = (dimensions.0 as i64, dimensions.1 as i64);
+    let x = uv.x * width as f32 - 0.5;
+    let y = uv.y * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |dx: i64, dy: i64| fetch((x0 as i64 + dx).clamp(0, width - 1) as u32, (y0 as i64 + dy).clamp(0, height - 1) as u32);
+    match filter {
+        MapFilter::Bilinear => {
+            let top = texel(0, 0).lerp(&texel(1, 0), fx);
+            let bottom = texel(0, 1).lerp(&texel(1, 1), fx);
+            top.lerp(&bottom, fy)
+        }
+        MapFilter::Bicubic => {
+            let weights = |t: f32| {
+                let (t2, t3) = (t * t, t * t * t);
+                [(-t3 + 2.0 * t2 - t) * 0.5, (3.0 * t3 - 5.0 * t2 + 2.0) * 0.5, (-3.0 * t3 + 4.0 * t2 + t) * 0.5, (t3 - t2) * 0.5]
+            };
+            let (wx, wy) = (weights(fx), weights(fy));
+            let mut sum = Vector4::zeros();
+            for (j, wy) in wy.iter().enumerate() {
+                for (i, wx) in wx.iter().enumerate() {
+                    sum += texel(i as i64 - 1, j as i64 - 1) * (wx * wy);
                 }
-                None => panic!("Could not get vertex"),
             }
+            sum
         }
     }
 }
 
-pub fn colorize_plane(plane: &mut Object3D, colormap: &ImageBuffer<Rgba<u8>, Vec<u8>>) {
-    let dimensions = colormap.dimensions();
-    let width = dimensions.0;
-    let height = dimensions.1;
-    for x in 0..width {
-        for y in 0..height {
-            match plane.colors.get_mut((x + y * width) as usize) {
-                Some(color) => {
-                    let pixel = colormap.get_pixel(x, y);
-                    *color = rgba_to_u32(*pixel);
-                }
-                None => (),
+// the height at a uv of the heightmap, works for any heightmap and plane size
+pub fn sample_height<H: HeightSource + ?Sized>(heightmap: &H, uv: &Vector2<f32>, filter: MapFilter) -> f32 {
+    sample_map(heightmap.dimensions(), uv, filter, |x, y| Vector4::new(heightmap.height(x, y), 0.0, 0.0, 0.0)).x
+}
+
+// moves every vertex up by the heightmap at its uv times the scale. the plane and the heightmap can
+// have any size, the textures made from the heightmap line up because they are sampled the same way
+pub fn displace_plane<H: HeightSource + ?Sized>(plane: &mut Object3D, heightmap: &H, scale: f32, filter: MapFilter) {
+    assert!(plane.uvs.len() == plane.vertices.len(), "Error, displace_plane needs a uv for every vertex");
+    for (vertex, uv) in plane.vertices.iter_mut().zip(plane.uvs.iter()) {
+        vertex[1] = sample_height(heightmap, uv, filter) * scale;
+    }
+    // imported normals and tangents no longer match the displaced surface
+    plane.normals.clear();
+    plane.tangents.clear();
+    plane.invalidate_bounds();
+}
+
+// tangent space normal map with the central differences of the heights. strength is the height
+// of a white pixel in units of the pixel spacing, like the scale of displace_plane
+pub fn height_to_normal_map<H: HeightSource + ?Sized>(heightmap: &H, strength: f32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = heightmap.dimensions();
+    let sample = |x: i64, y: i64| {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        heightmap.height(x, y) * strength
+    };
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let dx = (sample(x + 1, y) - sample(x - 1, y)) / 2.0;
+        let dy = (sample(x, y + 1) - sample(x, y - 1)) / 2.0;
+        let normal = Vector3::new(-dx, -dy, 1.0).normalize();
+        let encode = |c: f32| ((c * 0.5 + 0.5) * 255.0 + 0.5) as u8;
+        Rgba([encode(normal.x), encode(normal.y), encode(normal.z), 255])
+    })
+}
+
+// vertex colors from the color map at the vertex uvs, filtered like displace_plane
+pub fn colorize_plane(plane: &mut Object3D, colormap: &ImageBuffer<Rgba<u8>, Vec<u8>>, filter: MapFilter) {
+    let fetch = |x, y| colormap.get_pixel(x, y).0.map(|channel| channel as f32).into();
+    for (color, uv) in plane.colors.iter_mut().zip(plane.uvs.iter()) {
+        let pixel = sample_map(colormap.dimensions(), uv, filter, fetch).map(|channel| (channel.clamp(0.0, 255.0) + 0.5) as u8);
+        *color = rgba_to_u32(Rgba([pixel.x, pixel.y, pixel.z, pixel.w]));
+    }
+}
+
+// per vertex lambert lighting of the vertex colors for the rasterizer, shadows are optional per light.
+// the colors are sRGB like everything draw_object shows, they are lit in linear space
+pub fn light_plane(plane: &mut Object3D, transform_matrix: &Matrix4<f32>, lights: &[Light], shadows: &[Option<&dyn ShadowCaster>]) {
+    let normal_matrix = render::get_normal_matrix(transform_matrix);
+    let normals = plane.vertex_normals();
+    for ((vertex, normal), color) in plane.vertices.iter().zip(normals.iter()).zip(plane.colors.iter_mut()) {
+        let position = transform_matrix.transform_point(&vertex.xyz().into()).coords;
+        let normal = (normal_matrix * normal).normalize();
+        let mut irradiance = Vector3::zeros();
+        for (i, light) in lights.iter().enumerate() {
+            irradiance += light.ambient(&normal);
+            if let Some(sample) = light.sample(&position) {
+                let visibility = shadows.get(i).copied().flatten().map_or(1.0, |shadow| shadow.visibility(&position, &normal));
+                irradiance += sample.irradiance * (normal.dot(&sample.direction).max(0.0) * visibility);
             }
         }
+        // lambert reflects albedo / pi of the irradiance, like blinn_phong and cook_torrance
+        let radiance = srgb_color_to_linear(*color).component_mul(&irradiance) / PI;
+        *color = (*color & 0xFF000000) | vector_to_color(radiance.map(linear_to_srgb));
     }
 }
 
@@ -108,4 +294,96 @@ pub fn scale_image(buffer: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, target_size: (u3
     *buffer = scaled_image;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::renderer::reader::unit_plane;
+
+    fn sun(intensity: f32) -> Light {
+        Light::Directional { direction: -Vector3::y(), color: Vector3::repeat(1.0), intensity }
+    }
+
+    #[test]
+    fn light_plane_keeps_the_color_under_pi_irradiance() {
+        for color in [0xFFFFFF, 0x808080, 0x6B8E23] {
+            let mut plane = unit_plane(4, 4, color);
+            light_plane(&mut plane, &Matrix4::identity(), &[sun(PI)], &[None]);
+            assert!(plane.colors.iter().all(|&lit| lit & 0xFFFFFF == color), "{:06X}", color);
+        }
+    }
+
+    #[test]
+    fn light_plane_lights_in_linear_space() {
+        let mut plane = unit_plane(4, 4, 0xFFFFFF);
+        light_plane(&mut plane, &Matrix4::identity(), &[sun(0.5 * PI)], &[None]);
+        // half the light is about 188 in sRGB, not 128
+        assert!(plane.colors.iter().all(|&lit| lit & 0xFF == (linear_to_srgb(0.5) * 255.0 + 0.5) as u32));
+    }
+
+    struct Shadowed;
+
+    impl ShadowCaster for Shadowed {
+        fn visibility(&self, _position: &Vector3<f32>, _normal: &Vector3<f32>) -> f32 {
+            0.0
+        }
+    }
+
+    #[test]
+    fn light_plane_keeps_the_ambient_light_in_shadow() {
+        let sky = Light::Hemisphere { up: Vector3::y(), sky_color: Vector3::repeat(1.0), ground_color: Vector3::zeros(), intensity: PI };
+        let mut plane = unit_plane(4, 4, 0xFFFFFF);
+        light_plane(&mut plane, &Matrix4::identity(), &[sun(PI), sky], &[Some(&Shadowed), None]);
+        assert!(plane.colors.iter().all(|&lit| lit & 0xFFFFFF == 0xFFFFFF));
+        let mut plane = unit_plane(4, 4, 0xFFFFFF);
+        light_plane(&mut plane, &Matrix4::identity(), &[sun(PI)], &[Some(&Shadowed)]);
+        assert!(plane.colors.iter().all(|&lit| lit & 0xFFFFFF == 0));
+    }
+
+    #[test]
+    fn image_x_displaces_along_world_x_and_image_y_along_world_z() {
+        let along_x = ImageBuffer::from_fn(16, 12, |x, _| Luma([x as f32 / 16.0]));
+        let along_y = ImageBuffer::from_fn(16, 12, |_, y| Luma([y as f32 / 12.0]));
+        for (map, axis) in [(&along_x, 0), (&along_y, 2)] {
+            // the same size as the map and a coarser plane
+            for (width, depth) in [(16, 12), (9, 5)] {
+                let mut plane = unit_plane(width, depth, 0xFFFFFF);
+                displace_plane(&mut plane, map, 1.0, MapFilter::Bilinear);
+                for a in plane.vertices.iter() {
+                    for b in plane.vertices.iter() {
+                        if a[axis] == b[axis] {
+                            assert!((a.y - b.y).abs() < 1e-6);
+                        }
+                    }
+                }
+                let rising = |a: &&Vector4<f32>, b: &&Vector4<f32>| a[axis].total_cmp(&b[axis]);
+                assert!(plane.vertices.iter().min_by(rising).unwrap().y < plane.vertices.iter().max_by(rising).unwrap().y);
+            }
+        }
+        // a plane as large as the map samples every pixel at its center
+        let mut plane = unit_plane(16, 12, 0xFFFFFF);
+        displace_plane(&mut plane, &along_x, 1.0, MapFilter::Bicubic);
+        for (vertex, uv) in plane.vertices.iter().zip(plane.uvs.iter()) {
+            let pixel = (uv.x * 16.0 - 0.5).round();
+            assert!((vertex.y - pixel / 16.0).abs() < 1e-6);
+            assert!((vertex.x - (pixel - 8.0)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn normal_maps_lean_away_from_rising_heights() {
+        let ramp = ImageBuffer::from_fn(8, 8, |x, _| Luma([x as f32 / 8.0]));
+        let normal_map = height_to_normal_map(&ramp, 8.0);
+        let decode = |x, y| {
+            let pixel = normal_map.get_pixel(x, y);
+            Vector3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 127.5 - Vector3::repeat(1.0)
+        };
+        // one unit up per pixel is a 45 degree slope
+        let inside = decode(4, 4);
+        assert!((inside - Vector3::new(-1.0, 0.0, 1.0).normalize()).norm() < 0.02);
+        // flat images point straight out of the surface
+        let flat = height_to_normal_map(&ImageBuffer::from_pixel(4, 4, Luma([0.5f32])), 8.0);
+        assert!(flat.pixels().all(|pixel| pixel.0 == [128, 128, 255, 255]));
+    }
+}
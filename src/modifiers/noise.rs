@@ -0,0 +1,258 @@
+use super::super::renderer::math::Rng;
+use super::config::{FractalKind, NoiseKind, NoiseSettings};
+
+// coherent 2d noise, about -1..1. a given seed always gives the same values
+pub trait Noise: Sync {
+    fn sample(&self, x: f32, y: f32) -> f32;
+}
+
+// lattice hashing shared by the gradient and value noises, 0..255 shuffled by the seed
+#[derive(Clone, Debug)]
+struct Permutation([u8; 512]);
+
+impl Permutation {
+    fn new(seed: u32) -> Permutation {
+        let mut table: Vec<u8> = (0..=255).collect();
+        let mut rng = Rng::new(seed as u64, 0);
+        for i in (1..table.len()).rev() {
+            let j = (rng.next_u32() as usize) % (i + 1);
+            table.swap(i, j);
+        }
+        let mut permutation = [0; 512];
+        for (i, value) in permutation.iter_mut().enumerate() {
+            *value = table[i % 256];
+        }
+        Permutation(permutation)
+    }
+
+    fn hash(&self, x: i32, y: i32) -> u8 {
+        let x = self.0[(x & 255) as usize] as usize;
+        self.0[x + (y & 255) as usize]
+    }
+}
+
+// dot product with one of 8 gradient directions picked by the hash
+fn gradient(hash: u8, dx: f32, dy: f32) -> f32 {
+    match hash & 7 {
+        0 => dx + dy,
+        1 => -dx + dy,
+        2 => dx - dy,
+        3 => -dx - dy,
+        4 => dx,
+        5 => -dx,
+        6 => dy,
+        _ => -dy,
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// improved perlin gradient noise (Perlin 2002), zero on every integer lattice point
+#[derive(Clone, Debug)]
+pub struct Perlin {
+    permutation: Permutation,
+}
+
+impl Perlin {
+    pub fn new(seed: u32) -> Perlin {
+        Perlin { permutation: Permutation::new(seed) }
+    }
+}
+
+impl Noise for Perlin {
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (ix, iy) = (x0 as i32, y0 as i32);
+        let n00 = gradient(self.permutation.hash(ix, iy), fx, fy);
+        let n10 = gradient(self.permutation.hash(ix + 1, iy), fx - 1.0, fy);
+        let n01 = gradient(self.permutation.hash(ix, iy + 1), fx, fy - 1.0);
+        let n11 = gradient(self.permutation.hash(ix + 1, iy + 1), fx - 1.0, fy - 1.0);
+        let (u, v) = (fade(fx), fade(fy));
+        lerp(lerp(n00, n10, u), lerp(n01, n11, u), v)
+    }
+}
+
+// simplex noise on a skewed triangle grid, fewer directional artifacts than perlin (Gustavson)
+#[derive(Clone, Debug)]
+pub struct Simplex {
+    permutation: Permutation,
+}
+
+impl Simplex {
+    pub fn new(seed: u32) -> Simplex {
+        Simplex { permutation: Permutation::new(seed) }
+    }
+}
+
+impl Noise for Simplex {
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let skew = 0.5 * (3.0f32.sqrt() - 1.0);
+        let unskew = (3.0 - 3.0f32.sqrt()) / 6.0;
+        let s = (x + y) * skew;
+        let (i, j) = ((x + s).floor(), (y + s).floor());
+        let t = (i + j) * unskew;
+        let (x0, y0) = (x - (i - t), y - (j - t));
+        // the lower or the upper triangle of the skewed cell
+        let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+        let corners = [
+            (0, 0, x0, y0),
+            (i1, j1, x0 - i1 as f32 + unskew, y0 - j1 as f32 + unskew),
+            (1, 1, x0 - 1.0 + 2.0 * unskew, y0 - 1.0 + 2.0 * unskew),
+        ];
+        let (i, j) = (i as i32, j as i32);
+        let sum: f32 = corners.iter().map(|&(di, dj, dx, dy)| {
+            let falloff = 0.5 - dx * dx - dy * dy;
+            if falloff <= 0.0 {
+                return 0.0;
+            }
+            falloff.powi(4) * gradient(self.permutation.hash(i + di, j + dj), dx, dy)
+        }).sum();
+        // brings the extremes to about -1..1
+        sum * 70.0
+    }
+}
+
+// random values on the lattice, smoothly interpolated. blockier than the gradient noises
+#[derive(Clone, Debug)]
+pub struct Value {
+    permutation: Permutation,
+}
+
+impl Value {
+    pub fn new(seed: u32) -> Value {
+        Value { permutation: Permutation::new(seed) }
+    }
+
+    fn lattice(&self, x: i32, y: i32) -> f32 {
+        self.permutation.hash(x, y) as f32 / 127.5 - 1.0
+    }
+}
+
+impl Noise for Value {
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (ix, iy) = (x0 as i32, y0 as i32);
+        let (u, v) = (fade(x - x0), fade(y - y0));
+        lerp(
+            lerp(self.lattice(ix, iy), self.lattice(ix + 1, iy), u),
+            lerp(self.lattice(ix, iy + 1), self.lattice(ix + 1, iy + 1), u),
+            v,
+        )
+    }
+}
+
+// cellular noise, the distance to the closest of one random feature point per lattice cell
+// (Worley 1996). -1 on the points and rising towards the cell borders
+#[derive(Clone, Debug)]
+pub struct Worley {
+    seed: u32,
+}
+
+impl Worley {
+    pub fn new(seed: u32) -> Worley {
+        Worley { seed }
+    }
+
+    fn feature_point(&self, x: i32, y: i32) -> (f32, f32) {
+        let hash = hash_cell(x, y, self.seed);
+        ((hash & 0xFFFF) as f32 / 65535.0 + x as f32, (hash >> 16) as f32 / 65535.0 + y as f32)
+    }
+}
+
+impl Noise for Worley {
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let (ix, iy) = (x.floor() as i32, y.floor() as i32);
+        let mut closest = f32::MAX;
+        for cy in iy - 1..=iy + 1 {
+            for cx in ix - 1..=ix + 1 {
+                let (px, py) = self.feature_point(cx, cy);
+                closest = closest.min((px - x) * (px - x) + (py - y) * (py - y));
+            }
+        }
+        closest.sqrt().min(1.0) * 2.0 - 1.0
+    }
+}
+
+// integer hash of a cell, the lowbias32 mixer over the coordinates and seed
+fn hash_cell(x: i32, y: i32, seed: u32) -> u32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6b343) ^ (y as u32).wrapping_mul(0xd8163841) ^ seed.wrapping_mul(0xcb1ab31f);
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x7feb352d);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x846ca68b);
+    hash ^ (hash >> 16)
+}
+
+// fractal brownian motion, octaves of the noise with rising frequency and falling amplitude. the
+// sum is divided by the total amplitude, so the range stays the one of a single octave
+pub fn fbm(noise: &dyn Noise, x: f32, y: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    octave_sum(x, y, octaves, lacunarity, gain, |x, y| noise.sample(x, y))
+}
+
+// folded octaves with rounded bumps and sharp creases, like clouds or rolling hills
+pub fn billow(noise: &dyn Noise, x: f32, y: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    octave_sum(x, y, octaves, lacunarity, gain, |x, y| noise.sample(x, y).abs() * 2.0 - 1.0)
+}
+
+// inverted billow with sharp ridges. every octave is weighted by the previous one, so the detail
+// gathers on the ridges and the valleys stay smooth (Musgrave)
+pub fn ridged(noise: &dyn Noise, x: f32, y: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    let (mut sum, mut amplitude, mut frequency, mut total, mut weight) = (0.0, 1.0, 1.0, 0.0, 1.0);
+    for _ in 0..octaves.max(1) {
+        let ridge = 1.0 - noise.sample(x * frequency, y * frequency).abs();
+        let ridge = ridge * ridge * weight;
+        weight = ridge.clamp(0.0, 1.0);
+        sum += ridge * amplitude;
+        total += amplitude;
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+    sum / total * 2.0 - 1.0
+}
+
+fn octave_sum<F: Fn(f32, f32) -> f32>(x: f32, y: f32, octaves: u32, lacunarity: f32, gain: f32, octave: F) -> f32 {
+    let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+    for _ in 0..octaves.max(1) {
+        sum += octave(x * frequency, y * frequency) * amplitude;
+        total += amplitude;
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+    sum / total
+}
+
+// a noise with its fractal, as configured by NoiseSettings
+pub struct NoiseGenerator {
+    noise: Box<dyn Noise>,
+    settings: NoiseSettings,
+}
+
+impl NoiseGenerator {
+    pub fn new(settings: NoiseSettings, seed: u32) -> NoiseGenerator {
+        let noise: Box<dyn Noise> = match settings.noise {
+            NoiseKind::Perlin => Box::new(Perlin::new(seed)),
+            NoiseKind::Simplex => Box::new(Simplex::new(seed)),
+            NoiseKind::Value => Box::new(Value::new(seed)),
+            NoiseKind::Worley => Box::new(Worley::new(seed)),
+        };
+        NoiseGenerator { noise, settings }
+    }
+
+    // 0..1
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let NoiseSettings { octaves, lacunarity, gain, .. } = self.settings;
+        let value = match self.settings.fractal {
+            FractalKind::Fbm => fbm(self.noise.as_ref(), x, y, octaves, lacunarity, gain),
+            FractalKind::Ridged => ridged(self.noise.as_ref(), x, y, octaves, lacunarity, gain),
+            FractalKind::Billow => billow(self.noise.as_ref(), x, y, octaves, lacunarity, gain),
+        };
+        (value * 0.5 + 0.5).clamp(0.0, 1.0)
+    }
+}
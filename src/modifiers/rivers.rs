@@ -0,0 +1,385 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::f32::consts::SQRT_2;
+
+use image::{ImageBuffer, Rgba};
+
+use super::super::renderer::math::Rng;
+use super::config::MapConfig;
+use super::terrain::Heightfield;
+
+// d8 neighbours and their distance in pixels
+const NEIGHBOURS: [(i32, i32, f32); 8] = [
+    (1, 0, 1.0), (-1, 0, 1.0), (0, 1, 1.0), (0, -1, 1.0),
+    (1, 1, SQRT_2), (-1, 1, SQRT_2), (1, -1, SQRT_2), (-1, -1, SQRT_2),
+];
+
+// depressions shallower than this stay dry when a river runs through, about one step of an 8 bit
+// heightmap
+const LAKE_DEPTH: f32 = 1.0 / 255.0;
+
+// pixel of the flood queue, the lowest comes first and equal heights in index order
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FloodCell {
+    height: f32,
+    index: usize,
+}
+
+impl Eq for FloodCell {}
+
+impl Ord for FloodCell {
+    fn cmp(&self, other: &FloodCell) -> Ordering {
+        self.height.total_cmp(&other.height).then(self.index.cmp(&other.index))
+    }
+}
+
+impl PartialOrd for FloodCell {
+    fn partial_cmp(&self, other: &FloodCell) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn neighbours(width: usize, height: usize, index: usize) -> impl Iterator<Item = (usize, f32)> {
+    let (x, y) = ((index % width) as i32, (index / width) as i32);
+    NEIGHBOURS.iter().filter_map(move |&(dx, dy, distance)| {
+        let (nx, ny) = (x + dx, y + dy);
+        if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+            return None;
+        }
+        Some((ny as usize * width + nx as usize, distance))
+    })
+}
+
+// raises every depression to the height of its outlet, flooding inwards from the border and the
+// sea below sea_level (priority flood, Barnes et al. 2014). the flooded pixels rise by the smallest
+// float step per pixel, so every pixel keeps a lower neighbour and the water reaches an outlet
+pub fn fill_sinks(heightfield: &Heightfield, sea_level: Option<f32>) -> Heightfield {
+    let (width, height) = (heightfield.width, heightfield.height);
+    let mut filled = heightfield.clone();
+    let mut closed = vec![false; filled.heights.len()];
+    let mut queue = BinaryHeap::new();
+    for (index, closed) in closed.iter_mut().enumerate() {
+        let (x, y) = (index % width, index / width);
+        let in_sea = sea_level.is_some_and(|level| filled.heights[index] < level);
+        if x == 0 || y == 0 || x == width - 1 || y == height - 1 || in_sea {
+            *closed = true;
+            queue.push(Reverse(FloodCell { height: filled.heights[index], index }));
+        }
+    }
+    while let Some(Reverse(cell)) = queue.pop() {
+        for (neighbour, _) in neighbours(width, height, cell.index) {
+            if closed[neighbour] {
+                continue;
+            }
+            closed[neighbour] = true;
+            filled.heights[neighbour] = filled.heights[neighbour].max(cell.height.next_up());
+            queue.push(Reverse(FloodCell { height: filled.heights[neighbour], index: neighbour }));
+        }
+    }
+    filled
+}
+
+// the steepest lower neighbour of every pixel (d8), None on the border and in sinks. the heights
+// should be filled, then only the outlets have no direction
+pub fn flow_directions(heightfield: &Heightfield) -> Vec<Option<usize>> {
+    let (width, height) = (heightfield.width, heightfield.height);
+    (0..heightfield.heights.len()).map(|index| {
+        let (x, y) = (index % width, index / width);
+        if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+            return None;
+        }
+        let center = heightfield.heights[index];
+        let mut steepest = None;
+        let mut steepest_slope = 0.0;
+        for (neighbour, distance) in neighbours(width, height, index) {
+            let slope = (center - heightfield.heights[neighbour]) / distance;
+            if slope > steepest_slope {
+                steepest_slope = slope;
+                steepest = Some(neighbour);
+            }
+        }
+        steepest
+    }).collect()
+}
+
+// how many pixels drain through every pixel, itself included. the rain on the high pixels is
+// passed down first, so every pixel is done before its water moves on
+pub fn flow_accumulation(heightfield: &Heightfield, directions: &[Option<usize>]) -> Vec<f32> {
+    let mut order: Vec<usize> = (0..heightfield.heights.len()).collect();
+    order.sort_by(|&a, &b| heightfield.heights[b].total_cmp(&heightfield.heights[a]).then(a.cmp(&b)));
+    let mut accumulation = vec![1.0; heightfield.heights.len()];
+    for index in order {
+        if let Some(downstream) = directions[index] {
+            accumulation[downstream] += accumulation[index];
+        }
+    }
+    accumulation
+}
+
+// rivers, lakes and the sea of a terrain. the renderer gives these pixels a water material
+#[derive(Clone, Debug)]
+pub struct WaterMap {
+    // 1 where there is water, rivers fade out towards their banks
+    pub mask: Heightfield,
+    // height of the water surface, the terrain height where there is none
+    pub level: Heightfield,
+}
+
+impl WaterMap {
+    pub fn new(heightfield: &Heightfield) -> WaterMap {
+        WaterMap { mask: Heightfield::new(heightfield.width, heightfield.height), level: heightfield.clone() }
+    }
+
+    // the sea, everything below the level
+    pub fn flood_below(&mut self, heightfield: &Heightfield, level: f32) {
+        for (index, &height) in heightfield.heights.iter().enumerate() {
+            if height < level {
+                self.mask.heights[index] = 1.0;
+                self.level.heights[index] = level;
+            }
+        }
+    }
+
+    // the terrain with the water filled up to its surface, flat where it is displaced
+    pub fn surface(&self, heightfield: &Heightfield) -> Heightfield {
+        let mut surface = heightfield.clone();
+        for (index, height) in surface.heights.iter_mut().enumerate() {
+            if self.mask.heights[index] >= 0.5 {
+                *height = height.max(self.level.heights[index]);
+            }
+        }
+        surface
+    }
+
+    // metallic roughness texture, roughness in the green channel fades from land to water
+    pub fn roughness_map(&self, land: f32, water: f32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(self.mask.width as u32, self.mask.height as u32, |x, y| {
+            let mask = self.mask.get(x as usize, y as usize).clamp(0.0, 1.0);
+            let roughness = land + (water - land) * mask;
+            Rgba([0, (roughness.clamp(0.0, 1.0) * 255.0 + 0.5) as u8, 0, 255])
+        })
+    }
+}
+
+// river settings of the terrain tool
+#[derive(Clone, Copy, Debug)]
+pub struct RiverSettings {
+    // number of rivers
+    pub amount: u32,
+    pub seed: u32,
+    // longest river in pixels
+    pub iterations: u32,
+    // rivers end when they reach the sea
+    pub sea_level: Option<f32>,
+    // depth of a river carrying the water of the whole map, smaller ones are shallower
+    pub depth: f32,
+    // widest river in pixels, from the middle to the bank
+    pub width: f32,
+}
+
+impl Default for RiverSettings {
+    fn default() -> RiverSettings {
+        RiverSettings { amount: 20, seed: 0, iterations: 256, sea_level: None, depth: 0.02, width: 3.0 }
+    }
+}
+
+impl RiverSettings {
+    pub fn from_config(config: &MapConfig) -> RiverSettings {
+        RiverSettings {
+            amount: config.river_amount,
+            seed: config.river_seed,
+            iterations: config.river_iterations,
+            sea_level: config.as_water.then(|| config.ground_height()),
+            ..Default::default()
+        }
+    }
+}
+
+// springs picked at random, high ones more likely, run down the flow directions until they reach
+// the sea, the border or another river. the beds are carved deeper and wider the more water they
+// carry and always fall towards the mouth. depressions on the way deeper than LAKE_DEPTH become lakes
+pub fn carve_rivers(heightfield: &mut Heightfield, settings: &RiverSettings) -> WaterMap {
+    let (width, height) = (heightfield.width, heightfield.height);
+    let filled = fill_sinks(heightfield, settings.sea_level);
+    let directions = flow_directions(&filled);
+    let accumulation = flow_accumulation(&filled, &directions);
+    let total = accumulation.iter().copied().fold(1.0, f32::max);
+    let mut water = WaterMap::new(heightfield);
+    if let Some(level) = settings.sea_level {
+        water.flood_below(heightfield, level);
+    }
+    let in_sea = |height: f32| settings.sea_level.is_some_and(|level| height < level);
+
+    let mut rng = Rng::new(settings.seed as u64, 0);
+    let mut is_river = vec![false; heightfield.heights.len()];
+    // the path of every river with the height its bed falls to
+    let mut rivers: Vec<Vec<(usize, f32)>> = vec![];
+    let mut attempts = 0;
+    while (rivers.len() as u32) < settings.amount && attempts < settings.amount.saturating_mul(100) {
+        attempts += 1;
+        let spring = (rng.next_u32() as usize) % heightfield.heights.len();
+        if rng.next_f32() > heightfield.heights[spring] || in_sea(heightfield.heights[spring]) || is_river[spring] {
+            continue;
+        }
+        let mut path = vec![];
+        let (mut index, mut level) = (spring, filled.heights[spring]);
+        for _ in 0..settings.iterations {
+            level = level.min(filled.heights[index]);
+            path.push((index, level));
+            is_river[index] = true;
+            match directions[index] {
+                Some(next) if !is_river[next] && !in_sea(heightfield.heights[next]) => index = next,
+                _ => break,
+            }
+        }
+        rivers.push(path);
+    }
+
+    // a river running into a depression fills it up to the outlet
+    let mut lake = vec![];
+    for &(index, _) in rivers.iter().flatten() {
+        if filled.heights[index] - heightfield.heights[index] > LAKE_DEPTH && water.mask.heights[index] < 1.0 {
+            let surface = filled.heights[index];
+            water.mask.heights[index] = 1.0;
+            lake.push(index);
+            while let Some(index) = lake.pop() {
+                water.level.heights[index] = filled.heights[index];
+                for (neighbour, _) in neighbours(width, height, index) {
+                    // the flooded pixels only differ by float steps
+                    let flooded = filled.heights[neighbour] - heightfield.heights[neighbour] > 0.0 && (filled.heights[neighbour] - surface).abs() < 1e-4;
+                    if flooded && water.mask.heights[neighbour] < 1.0 {
+                        water.mask.heights[neighbour] = 1.0;
+                        lake.push(neighbour);
+                    }
+                }
+            }
+        }
+    }
+
+    // the bed is lowered around every pixel of the path with a rounded profile
+    let reach = settings.width.ceil() as i32;
+    for &(index, level) in rivers.iter().flatten() {
+        let strength = (accumulation[index].ln() / total.ln().max(1e-6)).clamp(0.0, 1.0);
+        let radius = 0.75 + (settings.width - 0.75).max(0.0) * strength;
+        let depth = settings.depth * (0.25 + 0.75 * strength);
+        let (x, y) = ((index % width) as i32, (index / width) as i32);
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                let (nx, ny) = (x + dx, y + dy);
+                let distance = ((dx * dx + dy * dy) as f32).sqrt();
+                if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 || distance > radius {
+                    continue;
+                }
+                let neighbour = ny as usize * width + nx as usize;
+                let profile = 1.0 - (distance / radius) * (distance / radius);
+                let bed = level - depth * profile;
+                if bed < heightfield.heights[neighbour] {
+                    heightfield.heights[neighbour] = bed;
+                }
+                // the water stands a bit below the banks
+                let mask = profile.sqrt();
+                if mask > water.mask.heights[neighbour] {
+                    water.mask.heights[neighbour] = mask;
+                    water.level.heights[neighbour] = level - depth * 0.2;
+                }
+            }
+        }
+    }
+    water
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // falls towards x = 0, slightly less steep than a 8 bit step per pixel
+    fn tilted(width: usize, height: usize) -> Heightfield {
+        Heightfield::from_fn(width, height, |x, _| 0.1 + 0.003 * x as f32)
+    }
+
+    fn hills() -> Heightfield {
+        Heightfield::from_fn(48, 40, |x, y| {
+            let (x, y) = (x as f32 * 0.3, y as f32 * 0.25);
+            0.5 + 0.2 * x.sin() * y.cos() + 0.1 * (x * 0.4 - y * 0.6).sin()
+        })
+    }
+
+    #[test]
+    fn a_pit_is_filled_to_its_outlet() {
+        let mut heightfield = tilted(12, 9);
+        let pit = 4 * 12 + 5;
+        heightfield.heights[pit] = 0.0;
+        let filled = fill_sinks(&heightfield, None);
+        // the lowest neighbour is the one towards x = 0
+        let outlet = heightfield.heights[pit - 1];
+        assert!(filled.heights[pit] > outlet && filled.heights[pit] - outlet < 1e-5);
+        for (index, (&before, &after)) in heightfield.heights.iter().zip(filled.heights.iter()).enumerate() {
+            if index != pit {
+                assert_eq!(before, after);
+            }
+        }
+        // the sea drains a pit below its level without filling it
+        let drained = fill_sinks(&heightfield, Some(0.05));
+        assert_eq!(drained.heights[pit], 0.0);
+    }
+
+    #[test]
+    fn filled_pixels_all_drain_to_the_border() {
+        let filled = fill_sinks(&hills(), None);
+        let (width, height) = (filled.width, filled.height);
+        let directions = flow_directions(&filled);
+        for (index, direction) in directions.iter().enumerate() {
+            let (x, y) = (index % width, index / width);
+            let border = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+            assert_eq!(direction.is_none(), border, "{} {}", x, y);
+        }
+        // following the directions always ends on the border
+        for start in 0..directions.len() {
+            let mut index = start;
+            let mut steps = 0;
+            while let Some(next) = directions[index] {
+                assert!(filled.heights[next] < filled.heights[index]);
+                index = next;
+                steps += 1;
+                assert!(steps <= directions.len());
+            }
+        }
+    }
+
+    #[test]
+    fn every_pixel_is_counted_at_exactly_one_outlet() {
+        let (width, height) = (12, 9);
+        let heightfield = tilted(width, height);
+        let directions = flow_directions(&heightfield);
+        let accumulation = flow_accumulation(&heightfield, &directions);
+        // every inner row runs straight down to its pixel at x = 0
+        for y in 1..height - 1 {
+            assert_eq!(accumulation[y * width], (width - 1) as f32);
+        }
+        let at_outlets: f32 = directions.iter().zip(accumulation.iter()).filter(|(direction, _)| direction.is_none()).map(|(_, &count)| count).sum();
+        assert_eq!(at_outlets, (width * height) as f32);
+        let hills = fill_sinks(&hills(), None);
+        let directions = flow_directions(&hills);
+        let accumulation = flow_accumulation(&hills, &directions);
+        let at_outlets: f32 = directions.iter().zip(accumulation.iter()).filter(|(direction, _)| direction.is_none()).map(|(_, &count)| count).sum();
+        assert_eq!(at_outlets, hills.heights.len() as f32);
+    }
+
+    #[test]
+    fn rivers_are_carved_the_same_for_a_seed() {
+        let carve = |seed| {
+            let mut heightfield = hills();
+            let water = carve_rivers(&mut heightfield, &RiverSettings { amount: 5, seed, sea_level: Some(0.35), ..Default::default() });
+            (heightfield, water)
+        };
+        let (first, first_water) = carve(7);
+        let (second, second_water) = carve(7);
+        assert_ne!(first, hills());
+        assert_eq!(first, second);
+        assert_eq!(first_water.mask, second_water.mask);
+        assert_eq!(first_water.level, second_water.level);
+        assert_ne!(carve(8).0, first);
+        // carving only ever lowers the terrain
+        assert!(first.heights.iter().zip(hills().heights.iter()).all(|(carved, original)| carved <= original));
+    }
+}
@@ -0,0 +1,242 @@
+use super::config::BlendMode;
+use super::terrain::{blend, Heightfield};
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0).max(1e-6)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// raises everything below the level to it, like the flat land of the terrain tool. smoothness is
+// the height over which the slopes round off into the flat instead of ending in a kink
+pub fn flatten_below(heightfield: &mut Heightfield, level: f32, smoothness: f32) {
+    heightfield.heights.iter_mut().for_each(|height| *height = smooth_max(*height, level, smoothness));
+}
+
+// lowers everything above the level to it, for plateaus
+pub fn flatten_above(heightfield: &mut Heightfield, level: f32, smoothness: f32) {
+    heightfield.heights.iter_mut().for_each(|height| *height = -smooth_max(-*height, -level, smoothness));
+}
+
+// polynomial smooth maximum (Quilez), the plain maximum for a smoothness of 0
+fn smooth_max(a: f32, b: f32, smoothness: f32) -> f32 {
+    if smoothness <= 0.0 {
+        return a.max(b);
+    }
+    let h = (0.5 + 0.5 * (a - b) / smoothness).clamp(0.0, 1.0);
+    b + (a - b) * h + smoothness * h * (1.0 - h)
+}
+
+// cuts the heights into steps flat terraces. smoothness is the part of every step taken by the slope
+// up to the next one, 0 gives vertical cliffs and 1 rounded hills without flats
+pub fn terrace(heightfield: &mut Heightfield, steps: u32, smoothness: f32) {
+    let steps = steps.max(1) as f32;
+    let smoothness = smoothness.clamp(0.0, 1.0);
+    heightfield.heights.iter_mut().for_each(|height| {
+        let scaled = *height * steps;
+        let step = scaled.floor();
+        let rise = if smoothness > 0.0 { smoothstep(1.0 - smoothness, 1.0, scaled - step) } else { 0.0 };
+        *height = (step + rise) / steps;
+    });
+}
+
+// a curve through points like the curves tool of an image editor. it is interpolated with monotone
+// cubics (Fritsch and Carlson 1980), so it does not overshoot and rising points give a rising curve
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    points: Vec<(f32, f32)>,
+    tangents: Vec<f32>,
+}
+
+impl Curve {
+    // the points are sorted by x, beyond the first and last point the curve stays flat
+    pub fn new(points: &[(f32, f32)]) -> Curve {
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        points.dedup_by(|a, b| a.0 == b.0);
+        let slopes: Vec<f32> = points.windows(2).map(|pair| (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0)).collect();
+        let mut tangents: Vec<f32> = (0..points.len()).map(|i| match i {
+            0 => slopes.first().copied().unwrap_or(0.0),
+            i if i == points.len() - 1 => slopes[i - 1],
+            // zero at peaks and valleys, so the curve does not overshoot them
+            i if slopes[i - 1] * slopes[i] <= 0.0 => 0.0,
+            i => (slopes[i - 1] + slopes[i]) * 0.5,
+        }).collect();
+        for (i, &slope) in slopes.iter().enumerate() {
+            if slope == 0.0 {
+                tangents[i] = 0.0;
+                tangents[i + 1] = 0.0;
+                continue;
+            }
+            let (a, b) = (tangents[i] / slope, tangents[i + 1] / slope);
+            let length = (a * a + b * b).sqrt();
+            if length > 3.0 {
+                tangents[i] = 3.0 * a / length * slope;
+                tangents[i + 1] = 3.0 * b / length * slope;
+            }
+        }
+        Curve { points, tangents }
+    }
+
+    // the straight line through 0 and 1, remapping with it changes nothing
+    pub fn identity() -> Curve {
+        Curve::new(&[(0.0, 0.0), (1.0, 1.0)])
+    }
+
+    pub fn sample(&self, x: f32) -> f32 {
+        let Some(&(first_x, first_y)) = self.points.first() else {
+            return x;
+        };
+        if x <= first_x || self.points.len() == 1 {
+            return first_y;
+        }
+        let next = self.points.iter().position(|point| point.0 > x).unwrap_or(self.points.len());
+        if next == self.points.len() {
+            return self.points[next - 1].1;
+        }
+        let ((x0, y0), (x1, y1)) = (self.points[next - 1], self.points[next]);
+        let width = x1 - x0;
+        let t = (x - x0) / width;
+        let (t2, t3) = (t * t, t * t * t);
+        // cubic hermite basis
+        (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * width * self.tangents[next - 1]
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * width * self.tangents[next]
+    }
+}
+
+// every height replaced by the curve at it
+pub fn remap(heightfield: &mut Heightfield, curve: &Curve) {
+    heightfield.heights.iter_mut().for_each(|height| *height = curve.sample(*height));
+}
+
+// 1 within inner and falling smoothly to 0 at outer. the center is in 0..1 of the size and the
+// radii in parts of half the shorter side, so 1 reaches the middle of the closest border
+pub fn radial_mask(width: usize, height: usize, center: (f32, f32), inner: f32, outer: f32) -> Heightfield {
+    let half = width.min(height) as f32 * 0.5;
+    Heightfield::from_fn(width, height, |x, y| {
+        let dx = (x as f32 + 0.5 - center.0 * width as f32) / half;
+        let dy = (y as f32 + 0.5 - center.1 * height as f32) / half;
+        1.0 - smoothstep(inner, outer, (dx * dx + dy * dy).sqrt())
+    })
+}
+
+// 1 - value, turns the inside of a mask into the outside
+pub fn invert(mask: &mut Heightfield) {
+    mask.heights.iter_mut().for_each(|value| *value = 1.0 - *value);
+}
+
+// blends the layer over the base with the mode, the mask is the opacity of every pixel. with a
+// radial mask this makes islands, craters or a plateau in the middle of the map
+pub fn blend_masked(base: &mut Heightfield, layer: &Heightfield, mask: &Heightfield, mode: BlendMode) {
+    assert!(
+        (base.width, base.height) == (layer.width, layer.height) && (base.width, base.height) == (mask.width, mask.height),
+        "Error, the heightfields of a blend need the same size"
+    );
+    for ((height, &value), &opacity) in base.heights.iter_mut().zip(layer.heights.iter()).zip(mask.heights.iter()) {
+        *height = blend(mode, *height, value, opacity.clamp(0.0, 1.0));
+    }
+}
+
+// blends towards a constant height, e.g. pushes the borders of the map down to the sea floor with
+// an inverted radial mask
+pub fn blend_towards(base: &mut Heightfield, value: f32, mask: &Heightfield, mode: BlendMode) {
+    let layer = Heightfield { width: base.width, height: base.height, heights: vec![value; base.heights.len()] };
+    blend_masked(base, &layer, mask, mode);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0..1 along x
+    fn ramp(width: usize) -> Heightfield {
+        Heightfield::from_fn(width, 3, |x, _| x as f32 / width as f32)
+    }
+
+    #[test]
+    fn the_identity_curve_changes_nothing() {
+        let identity = Curve::identity();
+        for i in 0..=100 {
+            let x = i as f32 / 100.0;
+            assert!((identity.sample(x) - x).abs() < 1e-6);
+        }
+        let mut heightfield = ramp(64);
+        remap(&mut heightfield, &identity);
+        assert!(heightfield.heights.iter().zip(ramp(64).heights.iter()).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn rising_curves_rise_without_overshooting() {
+        let points = [(0.0, 0.0), (0.2, 0.05), (0.5, 0.5), (0.55, 0.9), (0.8, 0.92), (1.0, 1.0)];
+        // given in any order
+        let curve = Curve::new(&[points[3], points[0], points[5], points[1], points[4], points[2]]);
+        for &(x, y) in points.iter() {
+            assert!((curve.sample(x) - y).abs() < 1e-6);
+        }
+        let samples: Vec<f32> = (0..=1000).map(|i| curve.sample(i as f32 / 1000.0)).collect();
+        assert!(samples.windows(2).all(|pair| pair[1] >= pair[0] - 1e-6));
+        // between two points the curve stays between their heights
+        for pair in points.windows(2) {
+            for i in 0..=20 {
+                let y = curve.sample(pair[0].0 + (pair[1].0 - pair[0].0) * i as f32 / 20.0);
+                assert!(y >= pair[0].1 - 1e-6 && y <= pair[1].1 + 1e-6);
+            }
+        }
+        // flat outside of the points
+        let inner = Curve::new(&[(0.2, 0.3), (0.8, 0.6)]);
+        assert_eq!(inner.sample(0.0), 0.3);
+        assert_eq!(inner.sample(1.0), 0.6);
+    }
+
+    #[test]
+    fn sharp_terraces_have_one_flat_level_per_step() {
+        let mut heightfield = ramp(100);
+        terrace(&mut heightfield, 4, 0.0);
+        let mut levels: Vec<f32> = heightfield.heights.clone();
+        levels.sort_by(f32::total_cmp);
+        levels.dedup();
+        assert_eq!(levels, [0.0, 0.25, 0.5, 0.75]);
+        // smooth terraces keep the heights rising
+        let mut smooth = ramp(100);
+        terrace(&mut smooth, 4, 0.5);
+        assert!(smooth.heights[..100].windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(smooth.heights[..100].iter().zip(heightfield.heights.iter()).all(|(smooth, sharp)| smooth >= sharp));
+    }
+
+    #[test]
+    fn flattening_without_smoothness_is_the_plain_maximum() {
+        let mut below = ramp(50);
+        flatten_below(&mut below, 0.3, 0.0);
+        assert!(below.heights.iter().zip(ramp(50).heights.iter()).all(|(&flat, &original)| flat == original.max(0.3)));
+        let mut above = ramp(50);
+        flatten_above(&mut above, 0.3, 0.0);
+        assert!(above.heights.iter().zip(ramp(50).heights.iter()).all(|(&flat, &original)| flat == original.min(0.3)));
+        // smoothing rounds the kink off but leaves heights far from the level alone
+        let mut smooth = ramp(50);
+        flatten_below(&mut smooth, 0.3, 0.1);
+        for (&smooth, &original) in smooth.heights.iter().zip(ramp(50).heights.iter()) {
+            assert!(smooth >= original.max(0.3));
+            if (original - 0.3).abs() > 0.1 {
+                assert!((smooth - original.max(0.3)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn radial_masks_fade_from_the_center() {
+        let mut mask = radial_mask(32, 32, (0.5, 0.5), 0.2, 0.8);
+        assert_eq!(mask.get(16, 16), 1.0);
+        assert_eq!(mask.get(0, 0), 0.0);
+        assert!(mask.heights.iter().all(|value| (0.0..=1.0).contains(value)));
+        let mut base = Heightfield::from_fn(32, 32, |_, _| 0.8);
+        blend_towards(&mut base, 0.1, &mask, BlendMode::Normal);
+        assert!((base.get(16, 16) - 0.1).abs() < 1e-6 && (base.get(0, 0) - 0.8).abs() < 1e-6);
+        // inverted it keeps the middle and pushes the border down
+        invert(&mut mask);
+        let mut island = Heightfield::from_fn(32, 32, |_, _| 0.8);
+        blend_masked(&mut island, &Heightfield::new(32, 32), &mask, BlendMode::Normal);
+        assert!((island.get(16, 16) - 0.8).abs() < 1e-6 && island.get(0, 0).abs() < 1e-6);
+        assert!(island.get(8, 16) > island.get(2, 16));
+    }
+}
@@ -0,0 +1,284 @@
+use std::thread;
+use image::{ImageBuffer, Rgba};
+
+use super::super::renderer::math::lerp_color;
+use super::modifiers::HeightSource;
+use super::config::{BlendMode, ErosionMode, MapConfig, NoiseLayer, NoiseSettings};
+use super::erosion::{ErosionMaps, HydraulicErosion, ThermalErosion};
+use super::noise::NoiseGenerator;
+use super::rivers::{carve_rivers, RiverSettings, WaterMap};
+use super::shaping::flatten_below;
+
+// heights in row major order, 0..1 with 1 the highest point
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heightfield {
+    pub width: usize,
+    pub height: usize,
+    pub heights: Vec<f32>,
+}
+
+impl Heightfield {
+    pub fn new(width: usize, height: usize) -> Heightfield {
+        Heightfield { width, height, heights: vec![0.0; width * height] }
+    }
+
+    // evaluates the rows on all cores, big maps take a while otherwise
+    pub fn from_fn<F: Fn(usize, usize) -> f32 + Sync>(width: usize, height: usize, height_at: F) -> Heightfield {
+        Heightfield::from_fn_threaded(width, height, 0, height_at)
+    }
+
+    // like from_fn on the given number of threads, 0 uses all cores
+    pub fn from_fn_threaded<F: Fn(usize, usize) -> f32 + Sync>(width: usize, height: usize, threads: usize, height_at: F) -> Heightfield {
+        let mut heights = vec![0.0; width * height];
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let rows_per_thread = height.div_ceil(threads).max(1);
+        thread::scope(|scope| {
+            for (chunk, rows) in heights.chunks_mut(rows_per_thread * width.max(1)).enumerate() {
+                let height_at = &height_at;
+                scope.spawn(move || {
+                    for (i, value) in rows.iter_mut().enumerate() {
+                        *value = height_at(i % width, chunk * rows_per_thread + i / width);
+                    }
+                });
+            }
+        });
+        Heightfield { width, height, heights }
+    }
+
+    // the red channel, white is high like the terrain tool exports it
+    pub fn from_image(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Heightfield {
+        let (width, height) = image.dimensions();
+        Heightfield::from_fn(width as usize, height as usize, |x, y| image.get_pixel(x as u32, y as u32)[0] as f32 / 255.0)
+    }
+
+    // grayscale like from_image reads it, heights outside 0..1 are clamped
+    pub fn to_image(&self) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let value = (self.get(x as usize, y as usize).clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.heights[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: f32) {
+        self.heights[y * self.width + x] = value;
+    }
+
+    // stretches the heights to exactly 0..1
+    pub fn normalize(&mut self) {
+        let minimum = self.heights.iter().copied().fold(f32::MAX, f32::min);
+        let maximum = self.heights.iter().copied().fold(f32::MIN, f32::max);
+        let range = (maximum - minimum).max(1e-6);
+        self.heights.iter_mut().for_each(|height| *height = (*height - minimum) / range);
+    }
+}
+
+// full float precision for displace_plane, no detour through an 8 bit image
+impl HeightSource for Heightfield {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width as u32, self.height as u32)
+    }
+
+    fn height(&self, x: u32, y: u32) -> f32 {
+        self.get(x as usize, y as usize)
+    }
+}
+
+// combines a layer value with the base like the image editor blend modes, both in 0..1.
+// opacity fades between the base and the blended result
+pub fn blend(mode: BlendMode, base: f32, layer: f32, opacity: f32) -> f32 {
+    let blended = match mode {
+        BlendMode::Normal => layer,
+        BlendMode::Add => base + layer,
+        BlendMode::Subtract => base - layer,
+        BlendMode::Multiply => base * layer,
+        BlendMode::Screen => 1.0 - (1.0 - base) * (1.0 - layer),
+        BlendMode::Overlay => if base < 0.5 { 2.0 * base * layer } else { 1.0 - 2.0 * (1.0 - base) * (1.0 - layer) },
+        BlendMode::Darken => base.min(layer),
+        BlendMode::Lighten => base.max(layer),
+        BlendMode::Difference => (base - layer).abs(),
+        // the pegtop formula, a gentler overlay without a kink at 0.5
+        BlendMode::SoftLight => (1.0 - 2.0 * layer) * base * base + 2.0 * layer * base,
+    };
+    base + (blended - base) * opacity
+}
+
+// noise in 0..1 at a heightmap pixel
+fn noise_layer(noise: &NoiseGenerator, x: usize, y: usize, scale: f32, offset: (f32, f32)) -> f32 {
+    noise.sample((x as f32 + offset.0) * scale, (y as f32 + offset.1) * scale)
+}
+
+// composes layers of noise over the base, each blended with its mode and opacity. the result is
+// not normalized, see generate_heightfield. a thread count of 0 uses all cores
+pub fn layered_noise(base: (&NoiseSettings, u32), scale: f32, offset: (f32, f32), layers: &[NoiseLayer], dimensions: (usize, usize), threads: usize) -> Heightfield {
+    let base_noise = NoiseGenerator::new(*base.0, base.1);
+    let layer_noises: Vec<_> = layers.iter().map(|layer| (layer, NoiseGenerator::new(layer.noise, layer.seed))).collect();
+    Heightfield::from_fn_threaded(dimensions.0, dimensions.1, threads, |x, y| {
+        let height = noise_layer(&base_noise, x, y, scale, offset);
+        layer_noises.iter().fold(height, |height, (layer, noise)| {
+            let value = noise_layer(noise, x, y, layer.scale, (layer.offset_x, layer.offset_y));
+            blend(layer.blend_mode, height, value, layer.opacity)
+        })
+    })
+}
+
+// a generated heightfield and what the simulations left besides the heights
+#[derive(Clone, Debug)]
+pub struct Terrain {
+    pub heightfield: Heightfield,
+    // where the hydraulic erosion moved water and sediment
+    pub erosion: Option<ErosionMaps>,
+    // rivers, lakes and the sea if the config asks for rivers or water
+    pub water: Option<WaterMap>,
+}
+
+// the base noise with every layer of the config blended over it, stretched to 0..1 and eroded.
+// the same config and size always give the same heights
+pub fn generate_heightfield(config: &MapConfig, dimensions: (usize, usize)) -> Heightfield {
+    eroded_noise(config, dimensions).0
+}
+
+// generate_heightfield with the flow and sediment maps if the config asks for hydraulic erosion
+fn eroded_noise(config: &MapConfig, dimensions: (usize, usize)) -> (Heightfield, Option<ErosionMaps>) {
+    let mut heightfield = layered_noise((&config.noise, config.seed), config.scale, (config.offset_x, config.offset_y), &config.layers, dimensions, config.threads);
+    heightfield.normalize();
+    let erosion = match config.erosion_mode {
+        ErosionMode::None => None,
+        ErosionMode::Thermal => {
+            ThermalErosion::from_config(config).apply(&mut heightfield);
+            None
+        }
+        ErosionMode::Hydraulic => Some(HydraulicErosion::from_config(config, dimensions).apply_with_maps(&mut heightfield)),
+    };
+    (heightfield, erosion)
+}
+
+// like generate_heightfield, then the land is flattened, the rivers are carved and the water is mapped
+pub fn generate_terrain(config: &MapConfig, dimensions: (usize, usize)) -> Terrain {
+    let (mut heightfield, erosion) = eroded_noise(config, dimensions);
+    // the flat land of the tool, with as_water the sea covers these heights instead
+    if config.flatten_enabled && !config.as_water {
+        flatten_below(&mut heightfield, config.ground_height(), 0.02);
+    }
+    let water = if config.calculate_rivers {
+        Some(carve_rivers(&mut heightfield, &RiverSettings::from_config(config)))
+    } else if config.as_water {
+        let mut water = WaterMap::new(&heightfield);
+        water.flood_below(&heightfield, config.ground_height());
+        Some(water)
+    } else {
+        None
+    };
+    Terrain { heightfield, erosion, water }
+}
+
+// generated heightmap as image for displace_plane
+pub fn generate_heightmap(config: &MapConfig, dimensions: (usize, usize)) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    generate_heightfield(config, dimensions).to_image()
+}
+
+// colors by height for terrain without a painted color map. water_level is a height in 0..1,
+// below it the colors go from shallow to deep water
+pub fn colorize_heightfield(heightfield: &Heightfield, water_level: Option<f32>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    // height and sRGB color of the land above the water
+    let land = [(0.0, 0xC2B280), (0.08, 0x6B8E23), (0.35, 0x556B2F), (0.55, 0x8B5A2B), (0.75, 0x9A9A9A), (0.9, 0xFFFFFF)];
+    let gradient = |stops: &[(f32, u32)], t: f32| {
+        let next = stops.iter().position(|stop| stop.0 > t).unwrap_or(stops.len());
+        if next == 0 {
+            return stops[0].1;
+        }
+        if next == stops.len() {
+            return stops[stops.len() - 1].1;
+        }
+        let ((h0, c0), (h1, c1)) = (stops[next - 1], stops[next]);
+        lerp_color(c0, c1, (t - h0) / (h1 - h0))
+    };
+    ImageBuffer::from_fn(heightfield.width as u32, heightfield.height as u32, |x, y| {
+        let height = heightfield.get(x as usize, y as usize);
+        let color = match water_level {
+            Some(level) if height < level => lerp_color(0x1E90FF, 0x0A2A6B, (1.0 - height / level.max(1e-6)).clamp(0.0, 1.0)),
+            Some(level) => gradient(&land, (height - level) / (1.0 - level).max(1e-6)),
+            None => gradient(&land, height),
+        };
+        Rgba([(color >> 16) as u8, (color >> 8) as u8, color as u8, 255])
+    })
+}
+
+// darkens the color map where water ran and lightens it where sediment settled, the maps need the
+// size of the image
+pub fn tint_erosion(colormap: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, maps: &ErosionMaps) {
+    for (x, y, pixel) in colormap.enumerate_pixels_mut() {
+        let color = ((pixel[0] as u32) << 16) | ((pixel[1] as u32) << 8) | pixel[2] as u32;
+        let color = lerp_color(color, 0xA08C6E, maps.sediment.get(x as usize, y as usize) * 0.5);
+        let color = lerp_color(color, 0x2F3A2A, maps.flow.get(x as usize, y as usize) * 0.6);
+        *pixel = Rgba([(color >> 16) as u8, (color >> 8) as u8, color as u8, pixel[3]]);
+    }
+}
+
+// colors the rivers and lakes like the sea of colorize_heightfield, darker the deeper they are
+pub fn tint_water(colormap: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, heightfield: &Heightfield, water: &WaterMap) {
+    for (x, y, pixel) in colormap.enumerate_pixels_mut() {
+        let (x, y) = (x as usize, y as usize);
+        let mask = water.mask.get(x, y);
+        if mask <= 0.0 {
+            continue;
+        }
+        let depth = (water.level.get(x, y) - heightfield.get(x, y)).max(0.0);
+        let color = ((pixel[0] as u32) << 16) | ((pixel[1] as u32) << 8) | pixel[2] as u32;
+        let color = lerp_color(color, lerp_color(0x1E90FF, 0x0A2A6B, (depth / 0.1).min(1.0)), mask.min(1.0));
+        *pixel = Rgba([(color >> 16) as u8, (color >> 8) as u8, color as u8, pixel[3]]);
+    }
+}
+
+// the heightmap at the export size and filter of the config
+pub fn export_heightmap(config: &MapConfig, heightfield: &Heightfield) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let image = heightfield.to_image();
+    let (width, height) = config.export_size(image.dimensions());
+    if (width, height) == image.dimensions() {
+        return image;
+    }
+    image::imageops::resize(&image, width, height, config.export_filter.filter_type())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::config::{FractalKind, NoiseKind};
+
+    fn map_config() -> MapConfig {
+        MapConfig::load(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/map_config.json")).unwrap()
+    }
+
+    #[test]
+    fn generation_is_deterministic_for_a_seed() {
+        let config = map_config();
+        let dimensions = (96, 64);
+        let heightfield = generate_heightfield(&config, dimensions);
+        assert_eq!(generate_heightfield(&config, dimensions), heightfield);
+        for threads in [1, 3, 7] {
+            assert_eq!(generate_heightfield(&MapConfig { threads, ..config.clone() }, dimensions), heightfield);
+        }
+        assert_ne!(generate_heightfield(&MapConfig { seed: config.seed + 1, ..config }, dimensions), heightfield);
+    }
+
+    #[test]
+    fn every_noise_is_deterministic_for_a_seed() {
+        let layers = map_config().layers;
+        for noise in [NoiseKind::Perlin, NoiseKind::Simplex, NoiseKind::Value, NoiseKind::Worley] {
+            for fractal in [FractalKind::Fbm, FractalKind::Ridged, FractalKind::Billow] {
+                let settings = NoiseSettings { noise, fractal, ..Default::default() };
+                let generate = |threads| layered_noise((&settings, 7), 0.05, (3.0, 5.0), &layers, (48, 40), threads);
+                let heightfield = generate(0);
+                assert!(heightfield.heights.iter().all(|height| height.is_finite()));
+                assert_eq!(generate(1), heightfield, "{:?} {:?}", noise, fractal);
+                assert_eq!(generate(5), heightfield, "{:?} {:?}", noise, fractal);
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use nalgebra::{Matrix4, Vector3, Vector4};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vector3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn from_points<'a, I: IntoIterator<Item = &'a Vector4<f32>>>(points: I) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for point in points {
+            aabb.grow(Vector3::new(point.x, point.y, point.z));
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, point: Vector3<f32>) {
+        self.min = self.min.inf(&point);
+        self.max = self.max.sup(&point);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extents(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let e = self.extents();
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && self.max.x >= other.min.x
+            && self.min.y <= other.max.y && self.max.y >= other.min.y
+            && self.min.z <= other.max.z && self.max.z >= other.min.z
+    }
+
+    pub fn corners(&self) -> [Vector3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vector3::new(a.x, a.y, a.z),
+            Vector3::new(b.x, a.y, a.z),
+            Vector3::new(a.x, b.y, a.z),
+            Vector3::new(b.x, b.y, a.z),
+            Vector3::new(a.x, a.y, b.z),
+            Vector3::new(b.x, a.y, b.z),
+            Vector3::new(a.x, b.y, b.z),
+            Vector3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    // the box enclosing all eight transformed corners, so it stays axis aligned in the new space
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let mut aabb = Aabb::empty();
+        for corner in self.corners().iter() {
+            aabb.grow(matrix.transform_point(&(*corner).into()).coords);
+        }
+        aabb
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BoundingSphere {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    pub fn from_points<'a, I: IntoIterator<Item = &'a Vector4<f32>> + Clone>(points: I) -> BoundingSphere {
+        let center = Aabb::from_points(points.clone()).center();
+        let radius = points.into_iter()
+            .map(|p| (Vector3::new(p.x, p.y, p.z) - center).norm())
+            .fold(0.0, f32::max);
+        BoundingSphere { center, radius }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> BoundingSphere {
+        let center = matrix.transform_point(&self.center.into()).coords;
+        let max_scale = (0..3)
+            .map(|i| matrix.fixed_view::<3, 1>(0, i).norm())
+            .fold(0.0, f32::max);
+        BoundingSphere { center, radius: self.radius * max_scale }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+}
+
+impl Bounds {
+    pub fn from_vertices(vertices: &[Vector4<f32>]) -> Bounds {
+        Bounds {
+            aabb: Aabb::from_points(vertices),
+            sphere: BoundingSphere::from_points(vertices),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Plane {
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+impl Plane {
+    fn from_row(row: Vector4<f32>) -> Plane {
+        let normal = Vector3::new(row.x, row.y, row.z);
+        let length = normal.norm();
+        Plane { normal: normal / length, distance: row.w / length }
+    }
+
+    pub fn signed_distance(&self, point: &Vector3<f32>) -> f32 {
+        self.normal.dot(point) + self.distance
+    }
+}
+
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    // Gribb/Hartmann extraction, the plane normals point into the frustum
+    pub fn from_matrix(view_projection: &Matrix4<f32>) -> Frustum {
+        let row = |i: usize| -> Vector4<f32> { view_projection.row(i).transpose() };
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        Frustum {
+            planes: [
+                Plane::from_row(r3 + r0),
+                Plane::from_row(r3 - r0),
+                Plane::from_row(r3 + r1),
+                Plane::from_row(r3 - r1),
+                Plane::from_row(r3 + r2),
+                Plane::from_row(r3 - r2),
+            ],
+        }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|plane| plane.signed_distance(&sphere.center) >= -sphere.radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane normal
+            let positive = Vector3::new(
+                if plane.normal.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.normal.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.normal.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.signed_distance(&positive) >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::render::Camera;
+
+    fn camera() -> Camera {
+        Camera {
+            fov: 90.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(0.0, 0.0, -5.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+
+    fn cube_at(center: Vector3<f32>, half_size: f32) -> Aabb {
+        Aabb { min: center - Vector3::repeat(half_size), max: center + Vector3::repeat(half_size) }
+    }
+
+    #[test]
+    fn frustum_keeps_boxes_in_view() {
+        let frustum = camera().get_frustum(1.0);
+        assert!(frustum.intersects_aabb(&cube_at(Vector3::zeros(), 1.0)));
+        // only a corner reaches into the view
+        assert!(frustum.intersects_aabb(&cube_at(Vector3::new(5.5, 0.0, 0.0), 1.0)));
+        assert!(frustum.intersects_sphere(&BoundingSphere { center: Vector3::new(0.0, 0.0, 50.0), radius: 1.0 }));
+    }
+
+    #[test]
+    fn frustum_culls_boxes_behind_and_beside_the_camera() {
+        let frustum = camera().get_frustum(1.0);
+        assert!(!frustum.intersects_aabb(&cube_at(Vector3::new(0.0, 0.0, -10.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube_at(Vector3::new(20.0, 0.0, 0.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube_at(Vector3::new(0.0, 0.0, 200.0), 1.0)));
+        assert!(!frustum.intersects_sphere(&BoundingSphere { center: Vector3::new(0.0, 0.0, -10.0), radius: 1.0 }));
+    }
+
+    #[test]
+    fn frustum_planes_point_inwards() {
+        let frustum = camera().get_frustum(1.0);
+        assert!(frustum.planes.iter().all(|plane| plane.signed_distance(&Vector3::zeros()) > 0.0));
+    }
+
+    #[test]
+    fn transformed_box_encloses_the_rotated_corners() {
+        let rotation = Matrix4::from_euler_angles(0.0, std::f32::consts::FRAC_PI_4, 0.0);
+        let aabb = cube_at(Vector3::zeros(), 1.0).transformed(&rotation);
+        let half_diagonal = 2.0f32.sqrt();
+        assert!((aabb.max.x - half_diagonal).abs() < 1e-5 && (aabb.min.z + half_diagonal).abs() < 1e-5);
+        assert!((aabb.max.y - 1.0).abs() < 1e-5);
+        assert!(Aabb::empty().transformed(&rotation).is_empty());
+    }
+
+    #[test]
+    fn bounding_sphere_scales_with_the_largest_axis() {
+        let vertices = [Vector4::new(-1.0, 0.0, 0.0, 1.0), Vector4::new(1.0, 0.0, 0.0, 1.0)];
+        let sphere = BoundingSphere::from_points(&vertices);
+        assert!(sphere.center.norm() < 1e-6 && (sphere.radius - 1.0).abs() < 1e-6);
+        let scaled = sphere.transformed(&Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 3.0, 2.0)).append_translation(&Vector3::x()));
+        assert!((scaled.center - Vector3::x()).norm() < 1e-6 && (scaled.radius - 3.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,713 @@
+use nalgebra::{Matrix4, Vector3};
+
+use super::bounds::Aabb;
+use super::render::Object3D;
+
+const SAH_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Ray {
+        Ray { origin, direction }
+    }
+
+    pub fn at(&self, t: f32) -> Vector3<f32> {
+        self.origin + self.direction * t
+    }
+
+    pub fn transformed(&self, matrix: &Matrix4<f32>) -> Ray {
+        Ray {
+            origin: matrix.transform_point(&self.origin.into()).coords,
+            direction: matrix.transform_vector(&self.direction),
+        }
+    }
+
+    // slab test, returns the entry distance if the box is hit before t_max
+    fn intersect_aabb(&self, inv_direction: &Vector3<f32>, aabb: &Aabb, t_max: f32) -> Option<f32> {
+        let t0 = (aabb.min - self.origin).component_mul(inv_direction);
+        let t1 = (aabb.max - self.origin).component_mul(inv_direction);
+        let t_near = t0.inf(&t1).max().max(0.0);
+        let t_far = t0.sup(&t1).min().min(t_max);
+        if t_near <= t_far {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub t: f32,
+    pub triangle: usize,
+    // barycentric weights of the second and third triangle vertex
+    pub u: f32,
+    pub v: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClosestPoint {
+    pub point: Vector3<f32>,
+    pub distance: f32,
+    pub triangle: usize,
+}
+
+pub fn triangle_positions(object: &Object3D, triangle: usize) -> [Vector3<f32>; 3] {
+    let (a, b, c) = object.triangles[triangle];
+    [object.vertices[a].xyz(), object.vertices[b].xyz(), object.vertices[c].xyz()]
+}
+
+// Moeller-Trumbore, double sided
+pub fn intersect_triangle(ray: &Ray, positions: &[Vector3<f32>; 3], t_max: f32) -> Option<(f32, f32, f32)> {
+    let edge1 = positions[1] - positions[0];
+    let edge2 = positions[2] - positions[0];
+    let p = ray.direction.cross(&edge2);
+    let det = edge1.dot(&p);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - positions[0];
+    let u = s.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(&edge1);
+    let v = ray.direction.dot(&q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(&q) * inv_det;
+    if t > 1e-5 && t < t_max {
+        Some((t, u, v))
+    } else {
+        None
+    }
+}
+
+// Ericson, Real-Time Collision Detection 5.1.5
+pub fn closest_point_on_triangle(point: &Vector3<f32>, positions: &[Vector3<f32>; 3]) -> Vector3<f32> {
+    let [a, b, c] = *positions;
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+    let d1 = ab.dot(&ap);
+    let d2 = ac.dot(&ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = point - b;
+    let d3 = ab.dot(&bp);
+    let d4 = ac.dot(&bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = point - c;
+    let d5 = ab.dot(&cp);
+    let d6 = ac.dot(&cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+fn distance_squared_to_aabb(point: &Vector3<f32>, aabb: &Aabb) -> f32 {
+    let clamped = point.sup(&aabb.min).inf(&aabb.max);
+    (clamped - point).norm_squared()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    aabb: Aabb,
+    // first primitive for leaves, left child for inner nodes, the right child always follows the left one
+    first: usize,
+    count: usize,
+}
+
+impl BvhNode {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+// hierarchy over anything that has a bounding box, shared by the mesh and the scene level
+#[derive(Clone, Debug, Default)]
+struct Bvh {
+    nodes: Vec<BvhNode>,
+    primitives: Vec<usize>,
+}
+
+impl Bvh {
+    fn build(aabbs: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            primitives: (0..aabbs.len()).collect(),
+        };
+        if aabbs.is_empty() {
+            return bvh;
+        }
+        let centroids: Vec<_> = aabbs.iter().map(|aabb| aabb.center()).collect();
+        bvh.nodes.push(BvhNode { aabb: Aabb::empty(), first: 0, count: aabbs.len() });
+        bvh.subdivide(0, aabbs, &centroids);
+        bvh
+    }
+
+    fn subdivide(&mut self, node_index: usize, aabbs: &[Aabb], centroids: &[Vector3<f32>]) {
+        let BvhNode { first, count, .. } = self.nodes[node_index];
+        let range = first..first + count;
+        let mut aabb = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &primitive in self.primitives[range.clone()].iter() {
+            aabb = aabb.union(&aabbs[primitive]);
+            centroid_bounds.grow(centroids[primitive]);
+        }
+        self.nodes[node_index].aabb = aabb;
+        if count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let Some((axis, split, cost)) = Self::find_sah_split(&self.primitives[range.clone()], aabbs, centroids, &centroid_bounds) else {
+            return;
+        };
+        // both sides are weighted by the area of this node, the chance of a ray hitting it
+        if TRAVERSAL_COST * aabb.surface_area() + cost >= INTERSECTION_COST * count as f32 * aabb.surface_area() {
+            return;
+        }
+
+        // partition the primitives of this node around the split plane
+        let mut i = first;
+        let mut j = first + count;
+        while i < j {
+            if centroids[self.primitives[i]][axis] < split {
+                i += 1;
+            } else {
+                j -= 1;
+                self.primitives.swap(i, j);
+            }
+        }
+        let left_count = i - first;
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode { aabb: Aabb::empty(), first, count: left_count });
+        self.nodes.push(BvhNode { aabb: Aabb::empty(), first: i, count: count - left_count });
+        self.nodes[node_index].first = left;
+        self.nodes[node_index].count = 0;
+        self.subdivide(left, aabbs, centroids);
+        self.subdivide(left + 1, aabbs, centroids);
+    }
+
+    // binned surface area heuristic, returns axis, split position and the estimated cost of the
+    // children weighted by their surface area
+    #[allow(clippy::needless_range_loop)]
+    fn find_sah_split(primitives: &[usize], aabbs: &[Aabb], centroids: &[Vector3<f32>], centroid_bounds: &Aabb) -> Option<(usize, f32, f32)> {
+        let mut best: Option<(usize, f32, f32)> = None;
+        for axis in 0..3 {
+            let min = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - min;
+            if extent <= f32::EPSILON {
+                continue;
+            }
+            let mut bins = [(Aabb::empty(), 0usize); SAH_BINS];
+            let scale = SAH_BINS as f32 / extent;
+            for &primitive in primitives.iter() {
+                let bin = (((centroids[primitive][axis] - min) * scale) as usize).min(SAH_BINS - 1);
+                bins[bin].0 = bins[bin].0.union(&aabbs[primitive]);
+                bins[bin].1 += 1;
+            }
+
+            let mut left_area = [0.0; SAH_BINS - 1];
+            let mut left_count = [0; SAH_BINS - 1];
+            let mut running = (Aabb::empty(), 0);
+            for i in 0..SAH_BINS - 1 {
+                running.0 = running.0.union(&bins[i].0);
+                running.1 += bins[i].1;
+                left_area[i] = running.0.surface_area();
+                left_count[i] = running.1;
+            }
+            let mut running = (Aabb::empty(), 0);
+            for i in (1..SAH_BINS).rev() {
+                running.0 = running.0.union(&bins[i].0);
+                running.1 += bins[i].1;
+                let cost = INTERSECTION_COST * (left_count[i - 1] as f32 * left_area[i - 1] + running.1 as f32 * running.0.surface_area());
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, min + i as f32 / scale, cost));
+                }
+            }
+        }
+        best
+    }
+
+    // keeps the topology and only recomputes the boxes, children are always stored after their parent
+    fn refit(&mut self, aabbs: &[Aabb]) {
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_index];
+            self.nodes[node_index].aabb = if node.is_leaf() {
+                self.primitives[node.first..node.first + node.count].iter()
+                    .fold(Aabb::empty(), |aabb, &primitive| aabb.union(&aabbs[primitive]))
+            } else {
+                self.nodes[node.first].aabb.union(&self.nodes[node.first + 1].aabb)
+            };
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |node| node.aabb)
+    }
+
+    // front to back traversal, the callback returns the new maximum distance of the ray
+    fn traverse_ray<F: FnMut(usize, f32) -> f32>(&self, ray: &Ray, t_max: f32, mut intersect: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_direction = ray.direction.map(|d| 1.0 / d);
+        let mut t_max = t_max;
+        let mut stack = vec![0usize];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if ray.intersect_aabb(&inv_direction, &node.aabb, t_max).is_none() {
+                continue;
+            }
+            if node.is_leaf() {
+                for &primitive in self.primitives[node.first..node.first + node.count].iter() {
+                    t_max = intersect(primitive, t_max);
+                }
+                continue;
+            }
+            let left = ray.intersect_aabb(&inv_direction, &self.nodes[node.first].aabb, t_max);
+            let right = ray.intersect_aabb(&inv_direction, &self.nodes[node.first + 1].aabb, t_max);
+            match (left, right) {
+                (Some(l), Some(r)) if l <= r => {
+                    stack.push(node.first + 1);
+                    stack.push(node.first);
+                }
+                (Some(_), Some(_)) => {
+                    stack.push(node.first);
+                    stack.push(node.first + 1);
+                }
+                (Some(_), None) => stack.push(node.first),
+                (None, Some(_)) => stack.push(node.first + 1),
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn traverse_aabb<F: FnMut(usize)>(&self, aabb: &Aabb, mut visit: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0usize];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.aabb.intersects(aabb) {
+                continue;
+            }
+            if node.is_leaf() {
+                self.primitives[node.first..node.first + node.count].iter().for_each(|&primitive| visit(primitive));
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+    }
+
+    // nearest first traversal, the callback returns the new best squared distance
+    fn traverse_closest<F: FnMut(usize, f32) -> f32>(&self, point: &Vector3<f32>, mut visit: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut best = f32::MAX;
+        let mut stack = vec![0usize];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if distance_squared_to_aabb(point, &node.aabb) > best {
+                continue;
+            }
+            if node.is_leaf() {
+                for &primitive in self.primitives[node.first..node.first + node.count].iter() {
+                    best = visit(primitive, best);
+                }
+                continue;
+            }
+            let left = distance_squared_to_aabb(point, &self.nodes[node.first].aabb);
+            let right = distance_squared_to_aabb(point, &self.nodes[node.first + 1].aabb);
+            if left <= right {
+                stack.push(node.first + 1);
+                stack.push(node.first);
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+    }
+}
+
+fn triangle_aabbs(object: &Object3D) -> Vec<Aabb> {
+    object.triangles.iter()
+        .map(|&(a, b, c)| Aabb::from_points([&object.vertices[a], &object.vertices[b], &object.vertices[c]]))
+        .collect()
+}
+
+// bottom level hierarchy over Object3D::triangles, in object space
+#[derive(Clone, Debug, Default)]
+pub struct MeshBvh {
+    bvh: Bvh,
+}
+
+impl MeshBvh {
+    pub fn build(object: &Object3D) -> MeshBvh {
+        MeshBvh { bvh: Bvh::build(&triangle_aabbs(object)) }
+    }
+
+    // cheaper than a rebuild after displace_plane and co. but the tree quality degrades with large changes
+    pub fn refit(&mut self, object: &Object3D) {
+        self.bvh.refit(&triangle_aabbs(object));
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    pub fn intersect_ray(&self, object: &Object3D, ray: &Ray, t_max: f32) -> Option<Hit> {
+        let mut closest = None;
+        self.bvh.traverse_ray(ray, t_max, |triangle, t_max| {
+            match intersect_triangle(ray, &triangle_positions(object, triangle), t_max) {
+                Some((t, u, v)) => {
+                    closest = Some(Hit { t, triangle, u, v });
+                    t
+                }
+                None => t_max,
+            }
+        });
+        closest
+    }
+
+    // any hit query, enough for shadow rays
+    pub fn is_occluded(&self, object: &Object3D, ray: &Ray, t_max: f32) -> bool {
+        let mut occluded = false;
+        self.bvh.traverse_ray(ray, t_max, |triangle, t_max| {
+            if !occluded && intersect_triangle(ray, &triangle_positions(object, triangle), t_max).is_some() {
+                occluded = true;
+            }
+            if occluded { 0.0 } else { t_max }
+        });
+        occluded
+    }
+
+    pub fn closest_point(&self, object: &Object3D, point: &Vector3<f32>) -> Option<ClosestPoint> {
+        let mut closest = None;
+        self.bvh.traverse_closest(point, |triangle, best| {
+            let candidate = closest_point_on_triangle(point, &triangle_positions(object, triangle));
+            let distance = (candidate - point).norm_squared();
+            if distance < best {
+                closest = Some(ClosestPoint { point: candidate, distance: distance.sqrt(), triangle });
+                distance
+            } else {
+                best
+            }
+        });
+        closest
+    }
+
+    // triangles whose bounding box overlaps the given box
+    pub fn overlap_aabb(&self, object: &Object3D, aabb: &Aabb) -> Vec<usize> {
+        let mut triangles = vec![];
+        self.bvh.traverse_aabb(aabb, |triangle| {
+            // a leaf box overlapping does not mean all of its triangles do
+            let (a, b, c) = object.triangles[triangle];
+            if Aabb::from_points([&object.vertices[a], &object.vertices[b], &object.vertices[c]]).intersects(aabb) {
+                triangles.push(triangle);
+            }
+        });
+        triangles
+    }
+}
+
+pub struct Instance<'a> {
+    pub object: &'a Object3D,
+    pub bvh: &'a MeshBvh,
+    pub transform: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+}
+
+impl<'a> Instance<'a> {
+    pub fn new(object: &'a Object3D, bvh: &'a MeshBvh, transform: Matrix4<f32>) -> Instance<'a> {
+        let inverse = transform.try_inverse().unwrap_or_else(Matrix4::identity);
+        Instance { object, bvh, transform, inverse }
+    }
+
+    // normals need the inverse transpose to stay perpendicular under non uniform scaling
+    pub fn normal_to_world(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        // only the linear part, the translation of the inverse must not end up in the bottom row
+        (self.inverse.fixed_view::<3, 3>(0, 0).transpose() * normal).normalize()
+    }
+
+    pub fn world_bounds(&self) -> Aabb {
+        self.bvh.bounds().transformed(&self.transform)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SceneHit {
+    pub instance: usize,
+    pub hit: Hit,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SceneClosestPoint {
+    pub instance: usize,
+    pub closest: ClosestPoint,
+}
+
+// top level hierarchy over placed meshes, queries are in world space
+pub struct SceneBvh<'a> {
+    pub instances: Vec<Instance<'a>>,
+    bvh: Bvh,
+}
+
+impl<'a> SceneBvh<'a> {
+    pub fn build(instances: Vec<Instance<'a>>) -> SceneBvh<'a> {
+        let aabbs: Vec<_> = instances.iter().map(|instance| instance.world_bounds()).collect();
+        SceneBvh { bvh: Bvh::build(&aabbs), instances }
+    }
+
+    // has to be called after instances were moved by set_transform. the instances borrow their
+    // meshes, so a deformed mesh needs the scene to be dropped, its MeshBvh refitted and the scene
+    // built again, which is cheap for a few instances
+    pub fn refit(&mut self) {
+        let aabbs: Vec<_> = self.instances.iter().map(|instance| instance.world_bounds()).collect();
+        self.bvh.refit(&aabbs);
+    }
+
+    pub fn set_transform(&mut self, instance: usize, transform: Matrix4<f32>) {
+        let object = self.instances[instance].object;
+        let bvh = self.instances[instance].bvh;
+        self.instances[instance] = Instance::new(object, bvh, transform);
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    pub fn intersect_ray(&self, ray: &Ray, t_max: f32) -> Option<SceneHit> {
+        let mut closest = None;
+        self.bvh.traverse_ray(ray, t_max, |instance_index, t_max| {
+            let instance = &self.instances[instance_index];
+            // the direction is not normalized in object space, so t stays comparable between instances
+            let local_ray = ray.transformed(&instance.inverse);
+            match instance.bvh.intersect_ray(instance.object, &local_ray, t_max) {
+                Some(hit) => {
+                    closest = Some(SceneHit { instance: instance_index, hit });
+                    hit.t
+                }
+                None => t_max,
+            }
+        });
+        closest
+    }
+
+    pub fn is_occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        let mut occluded = false;
+        self.bvh.traverse_ray(ray, t_max, |instance_index, t_max| {
+            let instance = &self.instances[instance_index];
+            if !occluded && instance.bvh.is_occluded(instance.object, &ray.transformed(&instance.inverse), t_max) {
+                occluded = true;
+            }
+            if occluded { 0.0 } else { t_max }
+        });
+        occluded
+    }
+
+    // searches each mesh in its object space, exact for rigid and uniformly scaled instances
+    pub fn closest_point(&self, point: &Vector3<f32>) -> Option<SceneClosestPoint> {
+        let mut closest = None;
+        self.bvh.traverse_closest(point, |instance_index, best| {
+            let instance = &self.instances[instance_index];
+            let local_point = instance.inverse.transform_point(&(*point).into()).coords;
+            let Some(local) = instance.bvh.closest_point(instance.object, &local_point) else {
+                return best;
+            };
+            let world_point = instance.transform.transform_point(&local.point.into()).coords;
+            let distance = (world_point - point).norm_squared();
+            if distance < best {
+                closest = Some(SceneClosestPoint {
+                    instance: instance_index,
+                    closest: ClosestPoint { point: world_point, distance: distance.sqrt(), triangle: local.triangle },
+                });
+                distance
+            } else {
+                best
+            }
+        });
+        closest
+    }
+
+    // (instance, triangle) pairs whose world space bounding box overlaps the given box
+    pub fn overlap_aabb(&self, aabb: &Aabb) -> Vec<(usize, usize)> {
+        let mut overlaps = vec![];
+        self.bvh.traverse_aabb(aabb, |instance_index| {
+            let instance = &self.instances[instance_index];
+            let local_aabb = aabb.transformed(&instance.inverse);
+            for triangle in instance.bvh.overlap_aabb(instance.object, &local_aabb) {
+                let (a, b, c) = instance.object.triangles[triangle];
+                let world_aabb = Aabb::from_points(
+                    [&instance.object.vertices[a], &instance.object.vertices[b], &instance.object.vertices[c]]
+                        .map(|v| instance.transform * v)
+                        .iter(),
+                );
+                if world_aabb.intersects(aabb) {
+                    overlaps.push((instance_index, triangle));
+                }
+            }
+        });
+        overlaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::math::Rng;
+    use super::super::reader::{unit_plane, unit_sphere};
+
+    fn bumpy_plane() -> Object3D {
+        let mut plane = unit_plane(16, 16, 0xFFFFFF);
+        plane.vertices.iter_mut().for_each(|vertex| vertex.y = (vertex.x * 0.7).sin() * (vertex.z * 0.5).cos());
+        plane
+    }
+
+    fn brute_force_ray(object: &Object3D, ray: &Ray, t_max: f32) -> Option<(usize, f32)> {
+        (0..object.triangles.len())
+            .filter_map(|triangle| intersect_triangle(ray, &triangle_positions(object, triangle), t_max).map(|(t, _, _)| (triangle, t)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    // rays from all around aimed into the box, a part of them misses the mesh inside
+    fn random_rays(count: usize, target: &Aabb) -> Vec<Ray> {
+        let mut rng = Rng::new(3, 0);
+        (0..count).map(|_| {
+            let origin = Vector3::new(rng.range(-12.0, 12.0), rng.range(-4.0, 6.0), rng.range(-12.0, 12.0));
+            let target = Vector3::new(rng.range(target.min.x, target.max.x), rng.range(target.min.y, target.max.y), rng.range(target.min.z, target.max.z));
+            Ray::new(origin, (target - origin).normalize())
+        }).collect()
+    }
+
+    #[test]
+    fn mesh_ray_hits_match_brute_force() {
+        for object in [bumpy_plane(), unit_sphere(0xFFFFFF)] {
+            let bvh = MeshBvh::build(&object);
+            let mut hits = 0;
+            for ray in random_rays(300, &bvh.bounds()) {
+                let expected = brute_force_ray(&object, &ray, f32::MAX);
+                let hit = bvh.intersect_ray(&object, &ray, f32::MAX);
+                assert_eq!(hit.is_some(), expected.is_some());
+                assert_eq!(bvh.is_occluded(&object, &ray, f32::MAX), expected.is_some());
+                if let (Some(hit), Some((_, t))) = (hit, expected) {
+                    assert!((hit.t - t).abs() < 1e-4);
+                    hits += 1;
+                }
+            }
+            assert!(hits > 50);
+        }
+    }
+
+    #[test]
+    fn closest_points_and_overlaps_match_brute_force() {
+        let object = bumpy_plane();
+        let bvh = MeshBvh::build(&object);
+        let mut rng = Rng::new(5, 0);
+        for _ in 0..200 {
+            let point = Vector3::new(rng.range(-10.0, 10.0), rng.range(-3.0, 3.0), rng.range(-10.0, 10.0));
+            let expected = (0..object.triangles.len())
+                .map(|triangle| (closest_point_on_triangle(&point, &triangle_positions(&object, triangle)) - point).norm())
+                .fold(f32::MAX, f32::min);
+            assert!((bvh.closest_point(&object, &point).unwrap().distance - expected).abs() < 1e-4);
+        }
+        let query = Aabb { min: Vector3::new(-2.0, -1.0, -3.0), max: Vector3::new(1.0, 0.5, 0.0) };
+        let mut overlaps = bvh.overlap_aabb(&object, &query);
+        overlaps.sort();
+        let aabbs = triangle_aabbs(&object);
+        let expected: Vec<_> = (0..object.triangles.len()).filter(|&triangle| aabbs[triangle].intersects(&query)).collect();
+        assert_eq!(overlaps, expected);
+    }
+
+    #[test]
+    fn refit_follows_moved_vertices() {
+        let mut object = bumpy_plane();
+        let mut bvh = MeshBvh::build(&object);
+        object.vertices.iter_mut().for_each(|vertex| vertex.y += 5.0);
+        bvh.refit(&object);
+        assert!((bvh.bounds().min.y - object.vertices.iter().map(|vertex| vertex.y).fold(f32::MAX, f32::min)).abs() < 1e-6);
+        for ray in random_rays(200, &bvh.bounds()) {
+            assert_eq!(bvh.intersect_ray(&object, &ray, f32::MAX).map(|hit| hit.triangle), brute_force_ray(&object, &ray, f32::MAX).map(|hit| hit.0));
+        }
+    }
+
+    #[test]
+    fn scene_hits_match_the_instances_in_world_space() {
+        let (plane, sphere) = (bumpy_plane(), unit_sphere(0xFFFFFF));
+        let (plane_bvh, sphere_bvh) = (MeshBvh::build(&plane), MeshBvh::build(&sphere));
+        let transforms = [
+            Matrix4::new_translation(&Vector3::new(0.0, -2.0, 0.0)),
+            Matrix4::new_translation(&Vector3::new(2.0, 1.0, 0.0)) * Matrix4::new_nonuniform_scaling(&Vector3::new(2.0, 1.0, 1.5)),
+            Matrix4::new_translation(&Vector3::new(-3.0, 0.5, 2.0)),
+        ];
+        let scene = SceneBvh::build(vec![
+            Instance::new(&plane, &plane_bvh, transforms[0]),
+            Instance::new(&sphere, &sphere_bvh, transforms[1]),
+            Instance::new(&sphere, &sphere_bvh, transforms[2]),
+        ]);
+        // every instance baked into world space triangles
+        let baked: Vec<_> = scene.instances.iter()
+            .map(|instance| Object3D::new(instance.object.vertices.iter().map(|vertex| instance.transform * vertex).collect(), vec![], vec![], instance.object.triangles.clone()))
+            .collect();
+        for ray in random_rays(300, &scene.bounds()) {
+            let expected = baked.iter().enumerate()
+                .filter_map(|(index, world)| brute_force_ray(world, &ray, f32::MAX).map(|(_, t)| (index, t)))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            let hit = scene.intersect_ray(&ray, f32::MAX);
+            assert_eq!(hit.map(|hit| hit.instance), expected.map(|hit| hit.0));
+            if let (Some(hit), Some((_, t))) = (hit, expected) {
+                assert!((hit.hit.t - t).abs() < 1e-3);
+            }
+            assert_eq!(scene.is_occluded(&ray, f32::MAX), expected.is_some());
+        }
+    }
+
+    #[test]
+    fn instance_normals_stay_perpendicular_under_non_uniform_scaling() {
+        let sphere = unit_sphere(0xFFFFFF);
+        let bvh = MeshBvh::build(&sphere);
+        let transform = Matrix4::new_translation(&Vector3::new(4.0, -2.0, 1.0)) * Matrix4::new_nonuniform_scaling(&Vector3::new(3.0, 1.0, 0.5));
+        let instance = Instance::new(&sphere, &bvh, transform);
+        // the surface tangent (1, -1, 0) of the plane x + y = 1 stays a tangent after the transform
+        let (normal, tangent) = (Vector3::new(1.0, 1.0, 0.0).normalize(), Vector3::new(1.0, -1.0, 0.0));
+        let world_tangent = transform.fixed_view::<3, 3>(0, 0) * tangent;
+        let world_normal = instance.normal_to_world(&normal);
+        assert!(world_normal.dot(&world_tangent).abs() < 1e-5);
+        assert!((world_normal.norm() - 1.0).abs() < 1e-5);
+    }
+}
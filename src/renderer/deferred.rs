@@ -0,0 +1,295 @@
+use nalgebra::{Matrix4, Vector3, Vector4};
+
+use super::environment::ImageBasedLight;
+use super::framebuffer::Framebuffer;
+use super::light::Light;
+use super::material::{MaterialSet, SurfaceParameters};
+use super::math::srgb_to_linear;
+use super::raytracer::render_tiles;
+use super::render::{draw_object_fragments, Camera, Object3D};
+use super::shader::{pbr_lighting, PbrShader};
+use super::shadow::ShadowCaster;
+
+// object id of the pixels nothing was drawn to
+pub const NO_OBJECT: u32 = u32::MAX;
+
+// render targets of the deferred path. draw_object_deferred fills them with the surfaces and
+// shade_deferred lights every pixel once afterwards, however many lights and triangles there are
+pub struct GBuffer {
+    pub dimensions: (usize, usize),
+    // linear base color
+    pub albedo: Vec<Vector3<f32>>,
+    // world space after normal mapping, zero where nothing was drawn
+    pub normal: Vec<Vector3<f32>>,
+    // world space
+    pub position: Vec<Vector3<f32>>,
+    // ndc depth like Framebuffer::depth
+    pub depth: Vec<f32>,
+    // occlusion, roughness and metallic, packed in that order like glTF does
+    pub material: Vec<Vector3<f32>>,
+    pub emissive: Vec<Vector3<f32>>,
+    pub object_id: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GBufferChannel {
+    Albedo,
+    Normal,
+    Position,
+    Depth,
+    Material,
+    Emissive,
+    ObjectId,
+}
+
+impl GBufferChannel {
+    pub const ALL: [GBufferChannel; 7] = [
+        GBufferChannel::Albedo,
+        GBufferChannel::Normal,
+        GBufferChannel::Position,
+        GBufferChannel::Depth,
+        GBufferChannel::Material,
+        GBufferChannel::Emissive,
+        GBufferChannel::ObjectId,
+    ];
+
+    // used for file names
+    pub fn name(&self) -> &'static str {
+        match self {
+            GBufferChannel::Albedo => "albedo",
+            GBufferChannel::Normal => "normal",
+            GBufferChannel::Position => "position",
+            GBufferChannel::Depth => "depth",
+            GBufferChannel::Material => "material",
+            GBufferChannel::Emissive => "emissive",
+            GBufferChannel::ObjectId => "object_id",
+        }
+    }
+}
+
+impl GBuffer {
+    pub fn new(dimensions: (usize, usize)) -> GBuffer {
+        let size = dimensions.0 * dimensions.1;
+        GBuffer {
+            dimensions,
+            albedo: vec![Vector3::zeros(); size],
+            normal: vec![Vector3::zeros(); size],
+            position: vec![Vector3::zeros(); size],
+            depth: vec![f32::MAX; size],
+            material: vec![Vector3::zeros(); size],
+            emissive: vec![Vector3::zeros(); size],
+            object_id: vec![NO_OBJECT; size],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.albedo.iter_mut().for_each(|value| *value = Vector3::zeros());
+        self.normal.iter_mut().for_each(|value| *value = Vector3::zeros());
+        self.position.iter_mut().for_each(|value| *value = Vector3::zeros());
+        self.depth.iter_mut().for_each(|value| *value = f32::MAX);
+        self.material.iter_mut().for_each(|value| *value = Vector3::zeros());
+        self.emissive.iter_mut().for_each(|value| *value = Vector3::zeros());
+        self.object_id.iter_mut().for_each(|value| *value = NO_OBJECT);
+    }
+
+    pub fn is_covered(&self, index: usize) -> bool {
+        self.object_id[index] != NO_OBJECT
+    }
+
+    // the material as it was evaluated in the geometry pass, None where nothing was drawn
+    pub fn surface(&self, index: usize) -> Option<SurfaceParameters> {
+        if !self.is_covered(index) {
+            return None;
+        }
+        let (albedo, material) = (self.albedo[index], self.material[index]);
+        Some(SurfaceParameters {
+            base_color: Vector4::new(albedo.x, albedo.y, albedo.z, 1.0),
+            metallic: material.z,
+            roughness: material.y,
+            emissive: self.emissive[index],
+            occlusion: material.x,
+            normal: Vector3::z(),
+        })
+    }
+
+    // the raw values for compositing, e.g. saved as exr. depth is the view space distance along
+    // the camera axis and the background gets the far plane, ids are -1 there
+    pub fn channel(&self, channel: GBufferChannel, camera: &Camera) -> Vec<Vector3<f32>> {
+        let (_, _, forward) = camera.get_basis();
+        let camera_position = camera.position.xyz();
+        (0..self.dimensions.0 * self.dimensions.1).map(|index| match channel {
+            GBufferChannel::Albedo => self.albedo[index],
+            GBufferChannel::Normal => self.normal[index],
+            GBufferChannel::Position => self.position[index],
+            GBufferChannel::Depth => {
+                let distance = if self.is_covered(index) { (self.position[index] - camera_position).dot(&forward) } else { camera.far };
+                Vector3::repeat(distance)
+            }
+            GBufferChannel::Material => self.material[index],
+            GBufferChannel::Emissive => self.emissive[index],
+            GBufferChannel::ObjectId => Vector3::repeat(if self.is_covered(index) { self.object_id[index] as f32 } else { -1.0 }),
+        }).collect()
+    }
+
+    // the channel brought into 0..1 for looking at it. like the debug shaders the values are
+    // linearized, so they come out unchanged after the sRGB output transform with ToneMapping::Clamp
+    pub fn preview(&self, channel: GBufferChannel, camera: &Camera) -> Vec<Vector3<f32>> {
+        let raw = self.channel(channel, camera);
+        let covered = |index: &usize| self.is_covered(*index);
+        let indices = 0..raw.len();
+        // positions and depths are stretched over the range of the drawn pixels
+        let (minimum, maximum) = indices.clone().filter(covered).fold(
+            (Vector3::repeat(f32::MAX), Vector3::repeat(f32::MIN)),
+            |(minimum, maximum), index| (minimum.inf(&raw[index]), maximum.sup(&raw[index])),
+        );
+        let extent = (maximum - minimum).map(|extent| extent.max(1e-6));
+        indices.map(|index| {
+            if !covered(&index) {
+                return Vector3::zeros();
+            }
+            let display = match channel {
+                GBufferChannel::Albedo | GBufferChannel::Emissive => return raw[index].map(|value| value.clamp(0.0, 1.0)),
+                GBufferChannel::Normal => raw[index] * 0.5 + Vector3::repeat(0.5),
+                GBufferChannel::Position => (raw[index] - minimum).component_div(&extent),
+                // white is close
+                GBufferChannel::Depth => Vector3::repeat(1.0 - (raw[index].x - minimum.x) / extent.x),
+                GBufferChannel::Material => raw[index],
+                GBufferChannel::ObjectId => id_color(self.object_id[index]),
+            };
+            display.map(|value| srgb_to_linear(value.clamp(0.0, 1.0)))
+        }).collect()
+    }
+}
+
+// a stable and well spread color for every id
+fn id_color(id: u32) -> Vector3<f32> {
+    let hash = id.wrapping_add(1).wrapping_mul(2654435761);
+    Vector3::new((hash >> 24) as f32, ((hash >> 16) & 0xFF) as f32, ((hash >> 8) & 0xFF) as f32) / 255.0
+}
+
+// geometry pass, rasterizes the object with its materials into the g-buffer and tags its pixels
+// with object_id. returns false if the object was culled by the camera frustum
+pub fn draw_object_deferred(gbuffer: &mut GBuffer, object: &Object3D, camera: &Camera, transform_matrix: &Matrix4<f32>, materials: &MaterialSet, object_id: u32) -> bool {
+    let shader = PbrShader::new(object, materials, &[], &[]);
+    let GBuffer { dimensions, albedo, normal, position, depth, material, emissive, object_id: ids } = gbuffer;
+    draw_object_fragments(depth, *dimensions, object, camera, transform_matrix, &shader, |_, index, input, varyings| {
+        let Some((surface, surface_normal, alpha)) = shader.surface(input, varyings) else {
+            return false;
+        };
+        // a pixel only holds one surface, blended ones are kept where they are mostly opaque.
+        // they look better drawn forward over the lit image
+        if alpha < 0.5 {
+            return false;
+        }
+        albedo[index] = surface.base_color.xyz();
+        normal[index] = surface_normal;
+        position[index] = varyings.0;
+        material[index] = Vector3::new(surface.occlusion, surface.roughness, surface.metallic);
+        emissive[index] = surface.emissive;
+        ids[index] = object_id;
+        true
+    })
+}
+
+// lighting pass, shades every covered pixel of the g-buffer on worker threads like PbrShader would.
+// depth and normals go to the framebuffer too so post processing works like after forward
+// rendering, pixels without a surface keep their color
+pub fn shade_deferred(gbuffer: &GBuffer, framebuffer: &mut Framebuffer, camera: &Camera, lights: &[Light], shadows: &[Option<&dyn ShadowCaster>], environment: Option<&ImageBasedLight>) {
+    assert!(gbuffer.dimensions == framebuffer.dimensions, "Error, the g-buffer and the framebuffer need the same size");
+    let camera_position = camera.position.xyz();
+    let colors = render_tiles(gbuffer.dimensions, 32, 0, |x, y| {
+        let index = y * gbuffer.dimensions.0 + x;
+        let surface = gbuffer.surface(index)?;
+        Some(pbr_lighting(lights, shadows, environment, &gbuffer.position[index], &gbuffer.normal[index], &camera_position, &surface))
+    });
+    for (index, color) in colors.into_iter().enumerate() {
+        let Some(color) = color else {
+            continue;
+        };
+        framebuffer.color[index] = Vector4::new(color.x, color.y, color.z, 1.0);
+        framebuffer.depth[index] = gbuffer.depth[index];
+        framebuffer.normals[index] = gbuffer.normal[index];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::light::Attenuation;
+    use super::super::material::Material;
+    use super::super::reader::{unit_cube, unit_sphere};
+    use super::super::render::draw_object_shaded;
+
+    fn camera() -> Camera {
+        Camera {
+            fov: 60.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(0.0, 0.0, -5.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn deferred_shading_matches_forward_shading() {
+        let lights = [
+            Light::Directional { direction: Vector3::new(0.3, -1.0, 0.5), color: Vector3::new(1.0, 0.9, 0.8), intensity: 3.0 },
+            Light::Point { position: Vector3::new(-2.0, 1.0, -3.0), color: Vector3::new(0.2, 0.5, 1.0), intensity: 5.0, range: 10.0, attenuation: Attenuation::Smooth },
+        ];
+        let sphere = unit_sphere(0xC08040);
+        let cube = unit_cube(0x40A0FF);
+        let rough = MaterialSet::single(Material { roughness: 0.7, ..Default::default() });
+        let metal = MaterialSet::single(Material { metallic: 1.0, roughness: 0.3, ..Default::default() });
+        let draws = [
+            (&sphere, Matrix4::new_translation(&Vector3::new(-0.8, 0.0, 0.0)), &rough),
+            (&cube, Matrix4::new_translation(&Vector3::new(1.0, 0.2, 1.0)) * Matrix4::new_scaling(0.7), &metal),
+        ];
+
+        let mut forward = Framebuffer::new((48, 32));
+        forward.clear(0x000000);
+        let mut deferred = Framebuffer::new((48, 32));
+        deferred.clear(0x000000);
+        let mut gbuffer = GBuffer::new((48, 32));
+        for (id, (object, transform, materials)) in draws.iter().enumerate() {
+            draw_object_shaded(&mut forward, object, &camera(), transform, &PbrShader::new(object, materials, &lights, &[]));
+            draw_object_deferred(&mut gbuffer, object, &camera(), transform, materials, id as u32);
+        }
+        shade_deferred(&gbuffer, &mut deferred, &camera(), &lights, &[], None);
+
+        assert!(gbuffer.object_id.iter().filter(|&&id| id != NO_OBJECT).count() > 100);
+        assert_eq!(forward.depth, deferred.depth);
+        for (forward, deferred) in forward.color.iter().zip(deferred.color.iter()) {
+            assert!((forward - deferred).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn channels_hold_ids_and_view_depth() {
+        let mut gbuffer = GBuffer::new((32, 32));
+        let cube = unit_cube(0xFFFFFF);
+        let materials = MaterialSet::single(Material::default());
+        draw_object_deferred(&mut gbuffer, &cube, &camera(), &Matrix4::identity(), &materials, 7);
+        draw_object_deferred(&mut gbuffer, &cube, &camera(), &Matrix4::new_translation(&Vector3::new(3.0, 0.0, 2.0)), &materials, 3);
+
+        let center = 16 * 32 + 16;
+        let ids = gbuffer.channel(GBufferChannel::ObjectId, &camera());
+        assert_eq!(ids[center].x, 7.0);
+        assert!(ids.iter().any(|id| id.x == 3.0));
+        assert_eq!(ids[0].x, -1.0);
+        // the front face of the cube is 4 units ahead, the background at the far plane
+        let depth = gbuffer.channel(GBufferChannel::Depth, &camera());
+        assert!((depth[center].x - 4.0).abs() < 1e-3);
+        assert_eq!(depth[0].x, camera().far);
+        // the cube has smooth normals, they still face the camera
+        assert!((gbuffer.normal[center].norm() - 1.0).abs() < 1e-4 && gbuffer.normal[center].z < -0.5);
+
+        for channel in GBufferChannel::ALL {
+            let preview = gbuffer.preview(channel, &camera());
+            assert!(preview.iter().all(|value| value.iter().all(|c| (0.0..=1.0).contains(c))), "{}", channel.name());
+            assert_eq!(preview[0], Vector3::zeros());
+        }
+        gbuffer.clear();
+        assert!(!(0..32 * 32).any(|index| gbuffer.is_covered(index)));
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs;
+use std::thread;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use super::framebuffer::Framebuffer;
+use super::material::ggx_distribution;
+use super::math::srgb_to_linear;
+use super::raytracer::reflect;
+use super::render::Camera;
+use super::texture::{Texture, TextureFilter};
+use super::tonemap::ToneMapper;
+
+// radiance arriving from infinitely far away, for rays leaving the scene and the sky behind everything
+pub trait Environment: Sync {
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32>;
+}
+
+pub struct ConstantEnvironment(pub Vector3<f32>);
+
+impl Environment for ConstantEnvironment {
+    fn radiance(&self, _direction: &Vector3<f32>) -> Vector3<f32> {
+        self.0
+    }
+}
+
+// procedural sky blending from the horizon color up to the zenith and down to the ground, linear colors
+#[derive(Clone, Copy, Debug)]
+pub struct GradientSky {
+    pub zenith: Vector3<f32>,
+    pub horizon: Vector3<f32>,
+    pub ground: Vector3<f32>,
+    // below 1 the horizon band gets narrower
+    pub exponent: f32,
+    pub intensity: f32,
+}
+
+impl Default for GradientSky {
+    fn default() -> GradientSky {
+        GradientSky {
+            zenith: Vector3::new(0.08, 0.22, 0.6),
+            horizon: Vector3::new(0.65, 0.75, 0.85),
+            ground: Vector3::new(0.12, 0.1, 0.08),
+            exponent: 0.5,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Environment for GradientSky {
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let height = direction.y / direction.norm().max(1e-6);
+        let color = if height >= 0.0 {
+            self.horizon.lerp(&self.zenith, height.powf(self.exponent))
+        } else {
+            self.horizon.lerp(&self.ground, (-height).powf(self.exponent))
+        };
+        color * self.intensity
+    }
+}
+
+// equirectangular panorama with linear texels. u goes around the y axis starting and ending at -z,
+// v from straight up to straight down
+pub struct Panorama<'a>(pub &'a Texture);
+
+impl<'a> Environment for Panorama<'a> {
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let direction = direction.normalize();
+        let u = direction.x.atan2(direction.z) / (2.0 * PI) + 0.5;
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        self.0.sample(&Vector2::new(u, v), TextureFilter::Bilinear).xyz()
+    }
+}
+
+// face order of a cube map, the usual px, nx, py, ny, pz, nz file naming
+pub const CUBE_FACES: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+
+// every face looks like a camera at the center facing along its axis would see it, with y up on
+// the side faces. the up and down faces are seen with +z as the front, like tilting the head
+fn face_coordinates(direction: &Vector3<f32>) -> (usize, Vector2<f32>) {
+    let absolute = direction.abs();
+    let (face, right, down, major) = if absolute.x >= absolute.y && absolute.x >= absolute.z {
+        if direction.x > 0.0 { (0, -direction.z, -direction.y, absolute.x) } else { (1, direction.z, -direction.y, absolute.x) }
+    } else if absolute.y >= absolute.z {
+        if direction.y > 0.0 { (2, direction.x, direction.z, absolute.y) } else { (3, direction.x, -direction.z, absolute.y) }
+    } else if direction.z > 0.0 {
+        (4, direction.x, -direction.y, absolute.z)
+    } else {
+        (5, -direction.x, -direction.y, absolute.z)
+    };
+    (face, Vector2::new((right / major + 1.0) / 2.0, (down / major + 1.0) / 2.0))
+}
+
+// inverse of face_coordinates, not normalized
+fn face_direction(face: usize, uv: &Vector2<f32>) -> Vector3<f32> {
+    let (right, down) = (uv.x * 2.0 - 1.0, uv.y * 2.0 - 1.0);
+    match face {
+        0 => Vector3::new(1.0, -down, -right),
+        1 => Vector3::new(-1.0, -down, right),
+        2 => Vector3::new(right, 1.0, down),
+        3 => Vector3::new(right, -1.0, -down),
+        4 => Vector3::new(right, -down, 1.0),
+        _ => Vector3::new(-right, -down, -1.0),
+    }
+}
+
+// six square faces with linear texels, see CUBE_FACES for the order
+#[derive(Clone, Debug)]
+pub struct CubeMap {
+    pub faces: Vec<Texture>,
+}
+
+impl CubeMap {
+    pub fn from_faces(faces: Vec<Texture>) -> CubeMap {
+        assert!(faces.len() == 6, "Error, a cube map needs 6 faces");
+        assert!(faces.iter().all(|face| face.width == faces[0].width && face.height == faces[0].width), "Error, cube map faces have to be square and equally large");
+        CubeMap { faces }
+    }
+
+    // bakes any environment, e.g. a procedural sky, at size x size texels per face
+    pub fn from_environment(environment: &dyn Environment, size: usize) -> CubeMap {
+        // one thread per face
+        let faces = thread::scope(|scope| {
+            let handles: Vec<_> = (0..6).map(|face| scope.spawn(move || {
+                let texels = (0..size * size).map(|i| {
+                    let uv = Vector2::new(((i % size) as f32 + 0.5) / size as f32, ((i / size) as f32 + 0.5) / size as f32);
+                    let radiance = environment.radiance(&face_direction(face, &uv).normalize());
+                    Vector4::new(radiance.x, radiance.y, radiance.z, 1.0)
+                }).collect();
+                Texture { width: size, height: size, texels, mip_levels: vec![] }
+            })).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        CubeMap { faces }
+    }
+
+    // one image per face in dir, named after CUBE_FACES with any extension, e.g. px.png or nz.hdr.
+    // float images are used as they are, everything else is treated as sRGB and linearized
+    pub fn load_faces(dir: &str) -> Result<CubeMap, Box<dyn Error>> {
+        let mut paths = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                paths.insert(stem.to_string(), path.clone());
+            }
+        }
+        let mut faces = vec![];
+        for face in CUBE_FACES {
+            let path = paths.get(face).ok_or_else(|| format!("no {} face in {}", face, dir))?;
+            let image = image::open(path)?;
+            let texture = match image {
+                DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => Texture::from_float_image(&image.to_rgba32f()),
+                _ => {
+                    let mut texture = Texture::from_image(&image.to_rgba8());
+                    texture.texels.iter_mut().for_each(|texel| *texel = Vector4::new(srgb_to_linear(texel.x), srgb_to_linear(texel.y), srgb_to_linear(texel.z), texel.w));
+                    texture
+                }
+            };
+            faces.push(texture);
+        }
+        if faces.iter().any(|face| face.width != faces[0].width || face.height != faces[0].width) {
+            return Err(format!("the faces in {} have to be square and equally large", dir).into());
+        }
+        Ok(CubeMap { faces })
+    }
+
+    pub fn from_equirectangular(panorama: &Texture, size: usize) -> CubeMap {
+        CubeMap::from_environment(&Panorama(panorama), size)
+    }
+
+    pub fn size(&self) -> usize {
+        self.faces[0].width
+    }
+
+    pub fn generate_mipmaps(&mut self) {
+        for face in self.faces.iter_mut() {
+            face.generate_mipmaps(FilterType::Triangle);
+        }
+    }
+
+    fn sample_level(&self, face: usize, uv: &Vector2<f32>, level: usize) -> Vector3<f32> {
+        let texture = self.faces[face].level(level);
+        // texture lookups wrap around, which would bleed the opposite edge of the same face in
+        let inset = Vector2::new(0.5 / texture.width as f32, 0.5 / texture.height as f32);
+        let uv = Vector2::new(uv.x.clamp(inset.x, 1.0 - inset.x), uv.y.clamp(inset.y, 1.0 - inset.y));
+        texture.sample(&uv, TextureFilter::Bilinear).xyz()
+    }
+
+    // lod selects between the mip levels like TextureFilter::Trilinear does
+    pub fn sample(&self, direction: &Vector3<f32>, lod: f32) -> Vector3<f32> {
+        let (face, uv) = face_coordinates(direction);
+        let lod = lod.clamp(0.0, (self.faces[face].level_count() - 1) as f32);
+        let level = lod.floor() as usize;
+        let sample = self.sample_level(face, &uv, level);
+        let t = lod - level as f32;
+        if t <= 0.0 {
+            return sample;
+        }
+        sample.lerp(&self.sample_level(face, &uv, level + 1), t)
+    }
+}
+
+impl Environment for CubeMap {
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        self.sample(direction, 0.0)
+    }
+}
+
+// order 2 spherical harmonics, enough to store diffuse irradiance (Ramamoorthi and Hanrahan)
+#[derive(Clone, Copy, Debug)]
+pub struct SphericalHarmonics {
+    pub coefficients: [Vector3<f32>; 9],
+}
+
+fn sh_basis(d: &Vector3<f32>) -> [f32; 9] {
+    [
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+impl SphericalHarmonics {
+    // integrates the environment over a latitude longitude grid with resolution rows
+    pub fn project(environment: &dyn Environment, resolution: usize) -> SphericalHarmonics {
+        let mut coefficients = [Vector3::zeros(); 9];
+        let rows = resolution.max(4);
+        let columns = rows * 2;
+        let (d_theta, d_phi) = (PI / rows as f32, 2.0 * PI / columns as f32);
+        for row in 0..rows {
+            let theta = (row as f32 + 0.5) * d_theta;
+            let solid_angle = theta.sin() * d_theta * d_phi;
+            for column in 0..columns {
+                let phi = (column as f32 + 0.5) * d_phi;
+                let direction = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                let radiance = environment.radiance(&direction) * solid_angle;
+                for (coefficient, basis) in coefficients.iter_mut().zip(sh_basis(&direction)) {
+                    *coefficient += radiance * basis;
+                }
+            }
+        }
+        SphericalHarmonics { coefficients }
+    }
+
+    // irradiance arriving at a surface facing normal, the clamped cosine convolution of the radiance
+    pub fn irradiance(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        let bands = [PI, 2.0 * PI / 3.0, 2.0 * PI / 3.0, 2.0 * PI / 3.0, PI / 4.0, PI / 4.0, PI / 4.0, PI / 4.0, PI / 4.0];
+        let irradiance: Vector3<f32> = self.coefficients.iter()
+            .zip(sh_basis(normal))
+            .zip(bands)
+            .map(|((coefficient, basis), band)| coefficient * (basis * band))
+            .sum();
+        irradiance.map(|c| c.max(0.0))
+    }
+}
+
+fn hammersley(i: u32, count: u32) -> Vector2<f32> {
+    Vector2::new(i as f32 / count as f32, i.reverse_bits() as f32 / 2f32.powi(32))
+}
+
+// half vector distributed by GGX around the normal
+fn importance_sample_ggx(xi: &Vector2<f32>, normal: &Vector3<f32>, alpha: f32) -> Vector3<f32> {
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = ((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let up = if normal.y.abs() < 0.999 { Vector3::y() } else { Vector3::x() };
+    let tangent = up.cross(normal).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + normal * cos_theta).normalize()
+}
+
+// scale and bias to the base reflectance for the split sum approximation, Karis' analytic fit
+// of the preintegrated GGX lookup table
+pub fn environment_brdf(n_dot_v: f32, roughness: f32) -> (f32, f32) {
+    let c0 = Vector4::new(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = Vector4::new(1.0, 0.0425, 1.04, -0.04);
+    let r = c0 * roughness + c1;
+    let a004 = (r.x * r.x).min((-9.28 * n_dot_v).exp2()) * r.x + r.y;
+    (-1.04 * a004 + r.z, 1.04 * a004 + r.w)
+}
+
+// prefiltered environment lighting: spherical harmonics for the diffuse part and one GGX
+// convolved cube map per roughness step for the specular part
+#[derive(Clone, Debug)]
+pub struct ImageBasedLight {
+    pub irradiance: SphericalHarmonics,
+    // level i is convolved for roughness i / (levels - 1), level 0 is a plain mirror
+    pub specular: Vec<CubeMap>,
+    pub intensity: f32,
+}
+
+impl ImageBasedLight {
+    // resolution is the face size of the sharpest specular level, every level halves it down to
+    // 8 texels. samples per texel are used for the convolution
+    pub fn new(environment: &dyn Environment, resolution: usize, levels: usize, samples: u32) -> ImageBasedLight {
+        let mut source = CubeMap::from_environment(environment, resolution);
+        source.generate_mipmaps();
+        let levels = levels.max(2);
+        let specular = (0..levels).map(|level| {
+            let size = (resolution >> level).max(8);
+            if level == 0 {
+                return CubeMap::from_environment(&source, size);
+            }
+            let roughness = level as f32 / (levels - 1) as f32;
+            let alpha = roughness * roughness;
+            // solid angle of one source texel, for picking the source mip level of every sample
+            let texel_solid_angle = 4.0 * PI / (6.0 * (resolution * resolution) as f32);
+            CubeMap::from_environment(&ConvolvedEnvironment(|normal: &Vector3<f32>| {
+                // the view and reflection directions are assumed to equal the normal
+                let mut sum = Vector3::zeros();
+                let mut weight = 0.0;
+                for i in 0..samples {
+                    let half = importance_sample_ggx(&hammersley(i, samples), normal, alpha);
+                    let light = reflect(&-normal, &half);
+                    let n_dot_l = normal.dot(&light);
+                    if n_dot_l <= 0.0 {
+                        continue;
+                    }
+                    let pdf = ggx_distribution(normal.dot(&half).max(0.0), alpha) / 4.0;
+                    let sample_solid_angle = 1.0 / (samples as f32 * pdf + 1e-4);
+                    let lod = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+                    sum += source.sample(&light, lod.max(0.0)) * n_dot_l;
+                    weight += n_dot_l;
+                }
+                sum / weight.max(1e-6)
+            }), size)
+        }).collect();
+        ImageBasedLight { irradiance: SphericalHarmonics::project(environment, 64), specular, intensity: 1.0 }
+    }
+
+    pub fn diffuse(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        self.irradiance.irradiance(normal) * self.intensity
+    }
+
+    // prefiltered radiance around the reflection direction
+    pub fn specular(&self, direction: &Vector3<f32>, roughness: f32) -> Vector3<f32> {
+        let position = roughness.clamp(0.0, 1.0) * (self.specular.len() - 1) as f32;
+        let level = (position.floor() as usize).min(self.specular.len() - 2);
+        let t = position - level as f32;
+        self.specular[level].sample(direction, 0.0).lerp(&self.specular[level + 1].sample(direction, 0.0), t) * self.intensity
+    }
+}
+
+struct ConvolvedEnvironment<F: Fn(&Vector3<f32>) -> Vector3<f32> + Sync>(F);
+
+impl<F: Fn(&Vector3<f32>) -> Vector3<f32> + Sync> Environment for ConvolvedEnvironment<F> {
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        (self.0)(direction)
+    }
+}
+
+// fills every pixel nothing was drawn on with the environment, call it after the objects.
+// the depth stays cleared so fog still sees them as background
+pub fn draw_skybox(framebuffer: &mut Framebuffer, camera: &Camera, environment: &dyn Environment) {
+    let (width, _) = framebuffer.dimensions;
+    for (i, color) in framebuffer.color.iter_mut().enumerate() {
+        if framebuffer.depth[i] <= 1.0 {
+            continue;
+        }
+        let ray = camera.get_ray(framebuffer.dimensions, (i % width) as f32 + 0.5, (i / width) as f32 + 0.5);
+        let radiance = environment.radiance(&ray.direction);
+        *color = Vector4::new(radiance.x, radiance.y, radiance.z, 1.0);
+    }
+}
+
+// background for draw_object, which only knows a clear color. fills the whole buffer, so draw the
+// objects afterwards without a background_color
+pub fn draw_environment(buffer: &mut [u32], dimensions: (usize, usize), camera: &Camera, environment: &dyn Environment, tone_mapper: &ToneMapper) {
+    for (i, pixel) in buffer.iter_mut().enumerate() {
+        let ray = camera.get_ray(dimensions, (i % dimensions.0) as f32 + 0.5, (i / dimensions.0) as f32 + 0.5);
+        *pixel = tone_mapper.to_color(&environment.radiance(&ray.direction));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    #[test]
+    fn faces_are_loaded_in_cube_face_order_and_linearized() {
+        let dir = std::env::temp_dir().join(format!("cube_faces_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // every face is a different shade, px is a float image
+        for (i, face) in CUBE_FACES.iter().enumerate().skip(1) {
+            let value = (i * 40) as u8;
+            ImageBuffer::from_pixel(4, 4, Rgba([value, value, value, 255])).save(dir.join(format!("{}.png", face))).unwrap();
+        }
+        let directory = dir.to_str().unwrap();
+        assert!(CubeMap::load_faces(directory).is_err());
+        ImageBuffer::from_pixel(4, 4, Rgb([4.0f32, 2.0, 1.0])).save(dir.join("px.exr")).unwrap();
+        let cube_map = CubeMap::load_faces(directory).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(cube_map.size(), 4);
+        assert!((cube_map.radiance(&Vector3::x()) - Vector3::new(4.0, 2.0, 1.0)).norm() < 1e-3);
+        let directions = [-Vector3::x(), Vector3::y(), -Vector3::y(), Vector3::z(), -Vector3::z()];
+        for (i, direction) in directions.iter().enumerate() {
+            let expected = srgb_to_linear(((i + 1) * 40) as f32 / 255.0);
+            assert!((cube_map.radiance(direction).x - expected).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use nalgebra::{Vector3, Vector4};
+
+use super::math::srgb_color_to_linear;
+use super::tonemap::ToneMapper;
+
+// render target of the shaded rasterizer. colors are linear hdr rgba and only become 0RGB for the
+// window when resolved with a tone mapper
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub dimensions: (usize, usize),
+    pub color: Vec<Vector4<f32>>,
+    // ndc depth, smaller is closer
+    pub depth: Vec<f32>,
+    // world space surface normals for screen space effects, zero where no shader wrote one
+    pub normals: Vec<Vector3<f32>>,
+}
+
+impl Framebuffer {
+    pub fn new(dimensions: (usize, usize)) -> Framebuffer {
+        Framebuffer {
+            dimensions,
+            color: vec![Vector4::zeros(); dimensions.0 * dimensions.1],
+            depth: vec![f32::MAX; dimensions.0 * dimensions.1],
+            normals: vec![Vector3::zeros(); dimensions.0 * dimensions.1],
+        }
+    }
+
+    // the clear color is sRGB like every other hex color
+    pub fn clear(&mut self, color: u32) {
+        let linear = srgb_color_to_linear(color);
+        let color = Vector4::new(linear.x, linear.y, linear.z, 1.0);
+        self.color.iter_mut().for_each(|pixel| *pixel = color);
+        self.depth.iter_mut().for_each(|depth| *depth = f32::MAX);
+        self.normals.iter_mut().for_each(|normal| *normal = Vector3::zeros());
+    }
+
+    pub fn resolve(&self, tone_mapper: &ToneMapper, buffer: &mut [u32]) {
+        tone_mapper.resolve(&self.color, buffer);
+    }
+
+    // the raw linear rgb, e.g. for saving with radiance_to_image_buffer
+    pub fn radiance(&self) -> Vec<Vector3<f32>> {
+        self.color.iter().map(|color| color.xyz()).collect()
+    }
+}
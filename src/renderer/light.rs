@@ -0,0 +1,172 @@
+use std::f32::consts::PI;
+use nalgebra::{Matrix4, Vector3};
+
+use super::render::Object3D;
+use super::shadow::{CubeShadowMap, ShadowCaster, ShadowMap, ShadowSettings};
+
+const SHADOW_NEAR: f32 = 0.05;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attenuation {
+    // physically correct, never reaches zero
+    InverseSquare,
+    // inverse square windowed to reach zero at the light range
+    Smooth,
+}
+
+// colors are linear rgb. intensities are chosen so that every light kind yields irradiance on a
+// surface facing it: directional and hemisphere lights give it directly, point and spot lights
+// give it at a distance of one unit
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Directional {
+        // the direction the light travels in
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+    },
+    Point {
+        position: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+        range: f32,
+        attenuation: Attenuation,
+    },
+    Spot {
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+        range: f32,
+        attenuation: Attenuation,
+        // full cone angles in degrees, the light fades out between them
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    // ambient light, blended between ground and sky color by the surface orientation
+    Hemisphere {
+        up: Vector3<f32>,
+        sky_color: Vector3<f32>,
+        ground_color: Vector3<f32>,
+        intensity: f32,
+    },
+}
+
+// light arriving at a point from a single direction
+#[derive(Clone, Copy, Debug)]
+pub struct LightSample {
+    // normalized, pointing from the surface towards the light
+    pub direction: Vector3<f32>,
+    // f32::INFINITY for directional lights
+    pub distance: f32,
+    pub irradiance: Vector3<f32>,
+}
+
+fn distance_attenuation(distance: f32, range: f32, attenuation: Attenuation) -> f32 {
+    let inverse_square = 1.0 / (distance * distance).max(1e-4);
+    match attenuation {
+        Attenuation::InverseSquare => inverse_square,
+        Attenuation::Smooth => {
+            let ratio = distance / range;
+            let window = (1.0 - ratio * ratio * ratio * ratio).clamp(0.0, 1.0);
+            inverse_square * window * window
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl Light {
+    // direct light towards a surface point, None for ambient lights or points out of reach
+    pub fn sample(&self, position: &Vector3<f32>) -> Option<LightSample> {
+        match *self {
+            Light::Directional { direction, color, intensity } => Some(LightSample {
+                direction: -direction.normalize(),
+                distance: f32::INFINITY,
+                irradiance: color * intensity,
+            }),
+            Light::Point { position: light_position, color, intensity, range, attenuation } => {
+                let to_light = light_position - position;
+                let distance = to_light.norm();
+                if distance > range || distance <= 0.0 {
+                    return None;
+                }
+                Some(LightSample {
+                    direction: to_light / distance,
+                    distance,
+                    irradiance: color * (intensity * distance_attenuation(distance, range, attenuation)),
+                })
+            }
+            Light::Spot { position: light_position, direction, color, intensity, range, attenuation, inner_angle, outer_angle } => {
+                let to_light = light_position - position;
+                let distance = to_light.norm();
+                if distance > range || distance <= 0.0 {
+                    return None;
+                }
+                let to_light = to_light / distance;
+                let cos_angle = (-to_light).dot(&direction.normalize());
+                let cone = smoothstep((outer_angle / 2.0).to_radians().cos(), (inner_angle / 2.0).to_radians().cos(), cos_angle);
+                if cone <= 0.0 {
+                    return None;
+                }
+                Some(LightSample {
+                    direction: to_light,
+                    distance,
+                    irradiance: color * (intensity * cone * distance_attenuation(distance, range, attenuation)),
+                })
+            }
+            Light::Hemisphere { .. } => None,
+        }
+    }
+
+    // irradiance from ambient lights on a surface with the given normal, zero for direct lights
+    pub fn ambient(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        match *self {
+            Light::Hemisphere { up, sky_color, ground_color, intensity } => {
+                let t = 0.5 + 0.5 * normal.dot(&up.normalize());
+                ground_color.lerp(&sky_color, t) * intensity
+            }
+            _ => Vector3::zeros(),
+        }
+    }
+
+    // radiance seen by a ray leaving the scene, so path tracers integrate the hemisphere light
+    // to the same irradiance the ambient term gives
+    pub fn environment_radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        match *self {
+            Light::Hemisphere { up, sky_color, ground_color, intensity } => {
+                let color = if direction.dot(&up) >= 0.0 { sky_color } else { ground_color };
+                color * (intensity / PI)
+            }
+            _ => Vector3::zeros(),
+        }
+    }
+
+    // renders the matching shadow map kind, scene_center and scene_radius bound all casters in
+    // world space and are only used to fit the orthographic map of directional lights
+    pub fn render_shadow(&self, casters: &[(&Object3D, Matrix4<f32>)], scene_center: Vector3<f32>, scene_radius: f32, settings: ShadowSettings) -> Option<Box<dyn ShadowCaster>> {
+        match *self {
+            Light::Directional { direction, .. } => {
+                let mut map = ShadowMap::orthographic(direction, scene_center, scene_radius, settings);
+                map.render(casters);
+                Some(Box::new(map))
+            }
+            Light::Spot { position, direction, range, outer_angle, .. } => {
+                let far = range.min(scene_radius + (scene_center - position).norm());
+                let mut map = ShadowMap::perspective(position, direction, outer_angle, SHADOW_NEAR, far, settings);
+                map.render(casters);
+                Some(Box::new(map))
+            }
+            Light::Point { position, range, .. } => {
+                let far = range.min(scene_radius + (scene_center - position).norm());
+                let mut map = CubeShadowMap::new(position, SHADOW_NEAR, far, settings);
+                map.render(casters);
+                Some(Box::new(map))
+            }
+            Light::Hemisphere { .. } => None,
+        }
+    }
+}
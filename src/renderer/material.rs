@@ -0,0 +1,212 @@
+use std::f32::consts::PI;
+use std::ops::Range;
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use super::math::srgb_to_linear;
+use super::texture::{Texture, TextureFilter};
+
+// lowest roughness used for shading, perfectly smooth surfaces turn point lights into invisible dots
+const MIN_ROUGHNESS: f32 = 0.02;
+
+pub fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let denom = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * denom * denom)
+}
+
+pub fn smith_g1(n_dot_v: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    2.0 * n_dot_v / (n_dot_v + (alpha2 + (1.0 - alpha2) * n_dot_v * n_dot_v).sqrt())
+}
+
+pub fn fresnel_schlick(f0: Vector3<f32>, cos_theta: f32) -> Vector3<f32> {
+    f0 + (Vector3::repeat(1.0) - f0) * (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+
+// reflectance at normal incidence, 4% for dielectrics and the base color for metals
+pub fn base_reflectance(base_color: &Vector3<f32>, metallic: f32) -> Vector3<f32> {
+    Vector3::repeat(0.04).lerp(base_color, metallic)
+}
+
+// cook-torrance with GGX distribution, smith shadowing and schlick fresnel over a lambert base.
+// returns the reflected fraction of the irradiance arriving from light_direction, i.e. brdf * n.l.
+// all directions are normalized and point away from the surface
+pub fn cook_torrance(normal: &Vector3<f32>, view: &Vector3<f32>, light_direction: &Vector3<f32>, base_color: &Vector3<f32>, metallic: f32, roughness: f32) -> Vector3<f32> {
+    let n_dot_l = normal.dot(light_direction);
+    let n_dot_v = normal.dot(view).max(1e-4);
+    if n_dot_l <= 0.0 {
+        return Vector3::zeros();
+    }
+    let alpha = roughness.clamp(MIN_ROUGHNESS, 1.0).powi(2);
+    let half = (view + light_direction).normalize();
+    let n_dot_h = normal.dot(&half).max(0.0);
+    let v_dot_h = view.dot(&half).max(0.0);
+
+    let fresnel = fresnel_schlick(base_reflectance(base_color, metallic), v_dot_h);
+    let d = ggx_distribution(n_dot_h, alpha);
+    let g = smith_g1(n_dot_v, alpha) * smith_g1(n_dot_l, alpha);
+    let specular = fresnel * (d * g / (4.0 * n_dot_l * n_dot_v));
+    let diffuse = (Vector3::repeat(1.0) - fresnel).component_mul(base_color) * ((1.0 - metallic) / PI);
+    (specular + diffuse) * n_dot_l
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AlphaMode {
+    // alpha is ignored
+    #[default]
+    Opaque,
+    // fragments below the cutoff are discarded, the rest is opaque
+    Mask(f32),
+    // blended over what is already in the framebuffer, draw these last and back to front
+    Blend,
+}
+
+// metallic-roughness material as in glTF. factors multiply the matching texture, color textures
+// are sRGB encoded, the others are linear
+#[derive(Clone, Copy, Debug)]
+pub struct Material<'a> {
+    // linear rgba
+    pub base_color: Vector4<f32>,
+    pub base_color_texture: Option<&'a Texture>,
+    pub metallic: f32,
+    pub roughness: f32,
+    // roughness in the green and metallic in the blue channel
+    pub metallic_roughness_texture: Option<&'a Texture>,
+    // linear rgb, added on top of the lighting
+    pub emissive: Vector3<f32>,
+    pub emissive_texture: Option<&'a Texture>,
+    // tangent space normals, scale strengthens or flattens the bumps
+    pub normal_texture: Option<&'a Texture>,
+    pub normal_scale: f32,
+    // ambient occlusion in the red channel, only darkens ambient light
+    pub occlusion_texture: Option<&'a Texture>,
+    pub occlusion_strength: f32,
+    pub alpha_mode: AlphaMode,
+    // back faces are discarded unless this is set, they are lit with a flipped normal if it is
+    pub double_sided: bool,
+    // multiplies the base color with the object vertex colors
+    pub vertex_colors: bool,
+    pub filter: TextureFilter,
+}
+
+impl<'a> Default for Material<'a> {
+    fn default() -> Material<'a> {
+        Material {
+            base_color: Vector4::repeat(1.0),
+            base_color_texture: None,
+            metallic: 0.0,
+            roughness: 0.5,
+            metallic_roughness_texture: None,
+            emissive: Vector3::zeros(),
+            emissive_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+            vertex_colors: true,
+            filter: TextureFilter::Bilinear,
+        }
+    }
+}
+
+// material inputs resolved for one fragment
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceParameters {
+    pub base_color: Vector4<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vector3<f32>,
+    pub occlusion: f32,
+    // tangent space, (0, 0, 1) without a normal map
+    pub normal: Vector3<f32>,
+}
+
+impl<'a> Material<'a> {
+    // uv_dx and uv_dy select the mip level, vertex_color is linear rgb
+    pub fn evaluate(&self, uv: &Vector2<f32>, uv_dx: &Vector2<f32>, uv_dy: &Vector2<f32>, vertex_color: &Vector3<f32>) -> SurfaceParameters {
+        let sample = |texture: &Texture| texture.sample_grad(uv, uv_dx, uv_dy, self.filter);
+        let mut base_color = self.base_color;
+        if let Some(texture) = self.base_color_texture {
+            let texel = sample(texture);
+            base_color.component_mul_assign(&Vector4::new(srgb_to_linear(texel.x), srgb_to_linear(texel.y), srgb_to_linear(texel.z), texel.w));
+        }
+        if self.vertex_colors {
+            base_color.component_mul_assign(&Vector4::new(vertex_color.x, vertex_color.y, vertex_color.z, 1.0));
+        }
+
+        let (mut metallic, mut roughness) = (self.metallic, self.roughness);
+        if let Some(texture) = self.metallic_roughness_texture {
+            let texel = sample(texture);
+            roughness *= texel.y;
+            metallic *= texel.z;
+        }
+
+        let mut emissive = self.emissive;
+        if let Some(texture) = self.emissive_texture {
+            emissive.component_mul_assign(&sample(texture).xyz().map(srgb_to_linear));
+        }
+
+        let occlusion = self.occlusion_texture.map_or(1.0, |texture| {
+            1.0 + self.occlusion_strength * (sample(texture).x - 1.0)
+        });
+
+        let normal = self.normal_texture.map_or_else(Vector3::z, |texture| {
+            let texel = sample(texture);
+            let normal = Vector3::new((texel.x * 2.0 - 1.0) * self.normal_scale, (texel.y * 2.0 - 1.0) * self.normal_scale, texel.z * 2.0 - 1.0);
+            normal.try_normalize(1e-6).unwrap_or_else(Vector3::z)
+        });
+
+        SurfaceParameters {
+            base_color,
+            metallic: metallic.clamp(0.0, 1.0),
+            roughness: roughness.clamp(0.0, 1.0),
+            emissive,
+            occlusion,
+            normal,
+        }
+    }
+
+    // alpha after the alpha mode is applied, None if the fragment is discarded
+    pub fn resolve_alpha(&self, alpha: f32) -> Option<f32> {
+        match self.alpha_mode {
+            AlphaMode::Opaque => Some(1.0),
+            AlphaMode::Mask(cutoff) => if alpha < cutoff { None } else { Some(1.0) },
+            AlphaMode::Blend => Some(alpha.clamp(0.0, 1.0)),
+        }
+    }
+}
+
+// materials of one object. triangles use the material of the last range containing them and
+// the first material if there is none
+#[derive(Clone, Debug)]
+pub struct MaterialSet<'a> {
+    pub materials: Vec<Material<'a>>,
+    // triangle index ranges into Object3D::triangles with the material index they use
+    pub ranges: Vec<(Range<usize>, usize)>,
+}
+
+impl<'a> MaterialSet<'a> {
+    pub fn single(material: Material<'a>) -> MaterialSet<'a> {
+        MaterialSet { materials: vec![material], ranges: vec![] }
+    }
+
+    // adds a material for a range of triangles and returns its index
+    pub fn assign(&mut self, triangles: Range<usize>, material: Material<'a>) -> usize {
+        self.materials.push(material);
+        let index = self.materials.len() - 1;
+        self.ranges.push((triangles, index));
+        index
+    }
+
+    pub fn material_index(&self, triangle: usize) -> usize {
+        self.ranges.iter().rev()
+            .find(|(range, _)| range.contains(&triangle))
+            .map_or(0, |(_, index)| *index)
+    }
+
+    pub fn material(&self, triangle: usize) -> &Material<'a> {
+        &self.materials[self.material_index(triangle)]
+    }
+}
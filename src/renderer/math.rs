@@ -1,14 +1,13 @@
+use nalgebra::Vector3;
+
 pub fn lerp(a: u8, b: u8, t: f32) -> u8 {
     ((1.0 - t) * a as f32 + t * b as f32) as u8
 }
 
+// blends in linear space, lerping the sRGB values directly makes gradients too dark in the middle
 pub fn lerp_color(a: u32, b: u32, t: f32) -> u32 {
-    let a = (a >> 16, (a >> 8) & 0xFF, a & 0xFF);
-    let b = (b >> 16, (b >> 8) & 0xFF, b & 0xFF);
-    let r = lerp(a.0 as u8, b.0 as u8, t);
-    let g = lerp(a.1 as u8, b.1 as u8, t);
-    let b = lerp(a.2 as u8, b.2 as u8, t);
-    (r as u32) << 16 | (g as u32) << 8 | b as u32
+    let mixed = srgb_color_to_linear(a).lerp(&srgb_color_to_linear(b), t);
+    vector_to_color(mixed.map(linear_to_srgb))
 }
 
 pub fn remap(value: f32, old_min: f32, old_max: f32, new_min: f32, new_max: f32) -> f32 {
@@ -17,4 +16,67 @@ pub fn remap(value: f32, old_min: f32, old_max: f32, new_min: f32, new_max: f32)
     }
     
     (value - old_min) / (old_max - old_min) * (new_max - new_min) + new_min
-}
\ No newline at end of file
+}
+
+pub fn color_to_vector(color: u32) -> Vector3<f32> {
+    Vector3::new(
+        ((color >> 16) & 0xFF) as f32 / 255.0,
+        ((color >> 8) & 0xFF) as f32 / 255.0,
+        (color & 0xFF) as f32 / 255.0,
+    )
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+// for colors picked in sRGB, e.g. light colors given as hex
+pub fn srgb_color_to_linear(color: u32) -> Vector3<f32> {
+    color_to_vector(color).map(srgb_to_linear)
+}
+
+pub fn vector_to_color(color: Vector3<f32>) -> u32 {
+    let r = (color.x.clamp(0.0, 1.0) * 255.0 + 0.5) as u32;
+    let g = (color.y.clamp(0.0, 1.0) * 255.0 + 0.5) as u32;
+    let b = (color.z.clamp(0.0, 1.0) * 255.0 + 0.5) as u32;
+    r << 16 | g << 8 | b
+}
+
+// pcg32, small and deterministic for a given seed and stream
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+    increment: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64, stream: u64) -> Rng {
+        let mut rng = Rng { state: 0, increment: (stream << 1) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old_state = self.state;
+        self.state = old_state.wrapping_mul(6364136223846793005).wrapping_add(self.increment);
+        let xorshifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
+        let rotation = (old_state >> 59) as u32;
+        xorshifted.rotate_right(rotation)
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+}
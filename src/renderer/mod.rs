@@ -1,3 +1,19 @@
 pub mod render;
 pub mod reader;
-pub mod math;
\ No newline at end of file
+pub mod math;
+pub mod bounds;
+pub mod bvh;
+pub mod raytracer;
+pub mod pathtracer;
+pub mod shadow;
+pub mod light;
+pub mod framebuffer;
+pub mod texture;
+pub mod shader;
+pub mod material;
+pub mod tonemap;
+pub mod postprocess;
+pub mod environment;
+pub mod sky;
+pub mod deferred;
+pub mod water;
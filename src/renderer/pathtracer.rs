@@ -0,0 +1,359 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+
+use super::bvh::{Ray, SceneBvh};
+use super::environment::Environment;
+use super::math::Rng;
+use super::raytracer::{reflect, refract, render_tiles, schlick, surface_at};
+use super::light::Light;
+use super::material::{fresnel_schlick, ggx_distribution, smith_g1};
+use super::render::Camera;
+use super::tonemap::ToneMapper;
+
+const RAY_EPSILON: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum PathMaterial {
+    // lambertian, the albedo comes from the vertex colors
+    #[default]
+    Diffuse,
+    // GGX specular lobe over a diffuse base, metallic blends between dielectric and conductor
+    Microfacet { roughness: f32, metallic: f32 },
+    Emissive { color: Vector3<f32>, strength: f32 },
+    // smooth dielectric tinted by the vertex colors
+    Glass { refractive_index: f32 },
+}
+
+pub struct PathTracerSettings {
+    pub max_bounces: u32,
+    // paths are terminated randomly after this many bounces
+    pub russian_roulette_depth: u32,
+    pub tile_size: usize,
+    // 0 uses all available cores
+    pub threads: usize,
+    pub seed: u64,
+}
+
+impl Default for PathTracerSettings {
+    fn default() -> PathTracerSettings {
+        PathTracerSettings {
+            max_bounces: 8,
+            russian_roulette_depth: 3,
+            tile_size: 32,
+            threads: 0,
+            seed: 0,
+        }
+    }
+}
+
+fn orthonormal_basis(normal: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let helper = if normal.x.abs() > 0.9 { Vector3::y() } else { Vector3::x() };
+    let tangent = helper.cross(normal).normalize();
+    (tangent, normal.cross(&tangent))
+}
+
+fn to_world(local: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    tangent * local.x + bitangent * local.y + normal * local.z
+}
+
+fn sample_cosine_hemisphere(normal: &Vector3<f32>, rng: &mut Rng) -> Vector3<f32> {
+    let (u1, u2) = (rng.next_f32(), rng.next_f32());
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    to_world(&Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt()), normal)
+}
+
+struct MicrofacetLobe {
+    albedo: Vector3<f32>,
+    alpha: f32,
+    metallic: f32,
+    normal: Vector3<f32>,
+}
+
+impl MicrofacetLobe {
+    fn new(albedo: Vector3<f32>, roughness: f32, metallic: f32, normal: Vector3<f32>) -> MicrofacetLobe {
+        let roughness = roughness.clamp(0.02, 1.0);
+        MicrofacetLobe { albedo, alpha: roughness * roughness, metallic: metallic.clamp(0.0, 1.0), normal }
+    }
+
+    fn specular_probability(&self) -> f32 {
+        0.5 + 0.5 * self.metallic
+    }
+
+    // bsdf value and the pdf of sampling wi, both directions point away from the surface
+    fn evaluate(&self, wo: &Vector3<f32>, wi: &Vector3<f32>) -> (Vector3<f32>, f32) {
+        let n_dot_l = self.normal.dot(wi);
+        let n_dot_v = self.normal.dot(wo);
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return (Vector3::zeros(), 0.0);
+        }
+        let h = (wo + wi).normalize();
+        let n_dot_h = self.normal.dot(&h).max(0.0);
+        let v_dot_h = wo.dot(&h).max(0.0);
+
+        let f0 = Vector3::repeat(0.04).lerp(&self.albedo, self.metallic);
+        let fresnel = fresnel_schlick(f0, v_dot_h);
+        let d = ggx_distribution(n_dot_h, self.alpha);
+        let g = smith_g1(n_dot_v, self.alpha) * smith_g1(n_dot_l, self.alpha);
+        let specular = fresnel * (d * g / (4.0 * n_dot_l * n_dot_v));
+        let diffuse = (Vector3::repeat(1.0) - fresnel).component_mul(&self.albedo) * ((1.0 - self.metallic) / PI);
+
+        let p_specular = self.specular_probability();
+        let pdf_specular = d * n_dot_h / (4.0 * v_dot_h).max(1e-6);
+        let pdf_diffuse = n_dot_l / PI;
+        (specular + diffuse, p_specular * pdf_specular + (1.0 - p_specular) * pdf_diffuse)
+    }
+
+    fn sample(&self, wo: &Vector3<f32>, rng: &mut Rng) -> Vector3<f32> {
+        if rng.next_f32() >= self.specular_probability() {
+            return sample_cosine_hemisphere(&self.normal, rng);
+        }
+        let (u1, u2) = (rng.next_f32(), rng.next_f32());
+        let phi = 2.0 * PI * u2;
+        let cos_theta = ((1.0 - u1) / (1.0 + (self.alpha * self.alpha - 1.0) * u1)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let h = to_world(&Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta), &self.normal);
+        reflect(&-wo, &h)
+    }
+}
+
+pub struct PathTracer<'a> {
+    pub scene: &'a SceneBvh<'a>,
+    // one per scene instance
+    pub materials: &'a [PathMaterial],
+    pub lights: &'a [Light],
+    pub environment: &'a dyn Environment,
+    pub settings: PathTracerSettings,
+}
+
+impl<'a> PathTracer<'a> {
+    // next event estimation towards every delta light, they can never be hit by a path.
+    // hemisphere lights are picked up through environment_radiance instead
+    fn sample_lights(&self, position: &Vector3<f32>, normal: &Vector3<f32>, evaluate: &dyn Fn(&Vector3<f32>) -> Vector3<f32>) -> Vector3<f32> {
+        let mut radiance = Vector3::zeros();
+        for light in self.lights.iter() {
+            let Some(sample) = light.sample(position) else {
+                continue;
+            };
+            let direction = sample.direction;
+            if normal.dot(&direction) <= 0.0 {
+                continue;
+            }
+            let shadow_ray = Ray::new(position + normal * RAY_EPSILON, direction);
+            if self.scene.is_occluded(&shadow_ray, sample.distance - RAY_EPSILON) {
+                continue;
+            }
+            radiance += evaluate(&direction).component_mul(&sample.irradiance) * normal.dot(&direction);
+        }
+        radiance
+    }
+
+    fn escaped_radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        self.lights.iter().fold(self.environment.radiance(direction), |radiance, light| radiance + light.environment_radiance(direction))
+    }
+
+    pub fn trace(&self, ray: &Ray, rng: &mut Rng) -> Vector3<f32> {
+        let mut radiance = Vector3::zeros();
+        let mut throughput = Vector3::repeat(1.0);
+        let mut ray = *ray;
+
+        for bounce in 0..=self.settings.max_bounces {
+            let Some(hit) = self.scene.intersect_ray(&ray, f32::MAX) else {
+                radiance += throughput.component_mul(&self.escaped_radiance(&ray.direction));
+                break;
+            };
+            let surface = surface_at(self.scene, &hit, &ray);
+            let wo = -ray.direction;
+            let material = self.materials.get(hit.instance).copied().unwrap_or_default();
+
+            match material {
+                PathMaterial::Emissive { color, strength } => {
+                    radiance += throughput.component_mul(&(color * strength));
+                    break;
+                }
+                PathMaterial::Diffuse => {
+                    let albedo = surface.color;
+                    radiance += throughput.component_mul(&self.sample_lights(&surface.position, &surface.normal, &|_| albedo / PI));
+                    let direction = sample_cosine_hemisphere(&surface.normal, rng);
+                    // cosine weighted sampling cancels the cosine and pi of the lambertian bsdf
+                    throughput = throughput.component_mul(&albedo);
+                    ray = Ray::new(surface.position + surface.normal * RAY_EPSILON, direction);
+                }
+                PathMaterial::Microfacet { roughness, metallic } => {
+                    let lobe = MicrofacetLobe::new(surface.color, roughness, metallic, surface.normal);
+                    radiance += throughput.component_mul(&self.sample_lights(&surface.position, &surface.normal, &|wi| lobe.evaluate(&wo, wi).0));
+                    let direction = lobe.sample(&wo, rng);
+                    let (value, pdf) = lobe.evaluate(&wo, &direction);
+                    if pdf <= 0.0 {
+                        break;
+                    }
+                    throughput = throughput.component_mul(&value) * (surface.normal.dot(&direction) / pdf);
+                    ray = Ray::new(surface.position + surface.normal * RAY_EPSILON, direction);
+                }
+                PathMaterial::Glass { refractive_index } => {
+                    let eta = if surface.front_face { 1.0 / refractive_index } else { refractive_index };
+                    let cos_theta = wo.dot(&surface.normal);
+                    let refracted = refract(&ray.direction, &surface.normal, eta);
+                    let reflectance = refracted.map_or(1.0, |_| schlick(cos_theta, eta));
+                    ray = match refracted {
+                        Some(direction) if rng.next_f32() >= reflectance => {
+                            throughput = throughput.component_mul(&surface.color);
+                            Ray::new(surface.position - surface.normal * RAY_EPSILON, direction)
+                        }
+                        _ => Ray::new(surface.position + surface.normal * RAY_EPSILON, reflect(&ray.direction, &surface.normal)),
+                    };
+                }
+            }
+
+            if bounce >= self.settings.russian_roulette_depth {
+                let survival = throughput.max().clamp(0.05, 0.95);
+                if rng.next_f32() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+        }
+        radiance
+    }
+}
+
+// running sum of one sample per pixel and pass, reset whenever the view changes
+pub struct Accumulator {
+    pub dimensions: (usize, usize),
+    pub sum: Vec<Vector3<f32>>,
+    pub passes: u32,
+}
+
+impl Accumulator {
+    pub fn new(dimensions: (usize, usize)) -> Accumulator {
+        Accumulator { dimensions, sum: vec![Vector3::zeros(); dimensions.0 * dimensions.1], passes: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.sum.iter_mut().for_each(|pixel| *pixel = Vector3::zeros());
+        self.passes = 0;
+    }
+
+    pub fn add_pass(&mut self, tracer: &PathTracer, camera: &Camera) {
+        let dimensions = self.dimensions;
+        let pass = self.passes as u64;
+        let samples = render_tiles(dimensions, tracer.settings.tile_size, tracer.settings.threads, |x, y| {
+            // every pixel and pass gets its own stream, so the image does not depend on the thread scheduling
+            let mut rng = Rng::new(tracer.settings.seed ^ pass.wrapping_mul(0x9E3779B97F4A7C15), (y * dimensions.0 + x) as u64);
+            let ray = camera.get_ray(dimensions, x as f32 + rng.next_f32(), y as f32 + rng.next_f32());
+            let sample = tracer.trace(&ray, &mut rng);
+            // a single NaN would otherwise poison the pixel for every following pass
+            if sample.iter().all(|c| c.is_finite()) { sample } else { Vector3::zeros() }
+        });
+        for (sum, sample) in self.sum.iter_mut().zip(samples.iter()) {
+            *sum += sample;
+        }
+        self.passes += 1;
+    }
+
+    // linear radiance, averaged over all passes so far
+    pub fn average(&self) -> Vec<Vector3<f32>> {
+        let scale = 1.0 / self.passes.max(1) as f32;
+        self.sum.iter().map(|pixel| pixel * scale).collect()
+    }
+
+    // gamma corrected for display in the window, values above one are clipped
+    pub fn resolve(&self, tone_mapper: &ToneMapper, buffer: &mut [u32]) {
+        let scale = 1.0 / self.passes.max(1) as f32;
+        for (pixel, sum) in buffer.iter_mut().zip(self.sum.iter()) {
+            *pixel = tone_mapper.to_color(&(sum * scale));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix4, Vector4};
+    use super::super::bvh::{Instance, MeshBvh};
+    use super::super::environment::ConstantEnvironment;
+    use super::super::reader::unit_plane;
+
+    #[test]
+    fn cosine_samples_stay_in_the_hemisphere() {
+        let normal = Vector3::new(0.3, 0.8, -0.5).normalize();
+        let mut rng = Rng::new(1, 0);
+        let mut mean_cosine = 0.0;
+        for _ in 0..20000 {
+            let direction = sample_cosine_hemisphere(&normal, &mut rng);
+            assert!((direction.norm() - 1.0).abs() < 1e-4 && direction.dot(&normal) >= 0.0);
+            mean_cosine += direction.dot(&normal) / 20000.0;
+        }
+        // the mean cosine of a cosine weighted hemisphere is 2/3
+        assert!((mean_cosine - 2.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn microfacet_lobe_does_not_create_energy() {
+        // single scattering loses energy on rough surfaces, but it must never gain any
+        let normal = Vector3::z();
+        let wo = Vector3::new(0.5, 0.0, 0.8).normalize();
+        for (roughness, metallic) in [(0.1, 0.0), (0.5, 0.0), (0.3, 1.0), (1.0, 1.0)] {
+            let lobe = MicrofacetLobe::new(Vector3::repeat(1.0), roughness, metallic, normal);
+            let mut rng = Rng::new(2, 0);
+            let samples = 20000;
+            let mut albedo = Vector3::zeros();
+            for _ in 0..samples {
+                let wi = lobe.sample(&wo, &mut rng);
+                let (value, pdf) = lobe.evaluate(&wo, &wi);
+                if pdf > 0.0 {
+                    albedo += value * (normal.dot(&wi) / pdf) / samples as f32;
+                }
+            }
+            // the same integral with uniform directions, checks that sample and pdf agree
+            let mut uniform = Vector3::zeros();
+            for _ in 0..samples {
+                let (u1, u2) = (rng.next_f32(), rng.next_f32());
+                let sin_theta = (1.0 - u1 * u1).sqrt();
+                let wi = Vector3::new(sin_theta * (2.0 * PI * u2).cos(), sin_theta * (2.0 * PI * u2).sin(), u1);
+                uniform += lobe.evaluate(&wo, &wi).0 * (wi.z * 2.0 * PI) / samples as f32;
+            }
+            assert!(albedo.max() < 1.02, "{} {} {:?}", roughness, metallic, albedo);
+            assert!((albedo - uniform).norm() < 0.03, "{} {} {:?} {:?}", roughness, metallic, albedo, uniform);
+        }
+    }
+
+    #[test]
+    fn white_ground_under_a_white_sky_reflects_the_sky() {
+        let floor = unit_plane(4, 4, 0xFFFFFF);
+        let bvh = MeshBvh::build(&floor);
+        let scene = SceneBvh::build(vec![Instance::new(&floor, &bvh, Matrix4::identity())]);
+        let environment = ConstantEnvironment(Vector3::repeat(1.0));
+        let tracer = PathTracer { scene: &scene, materials: &[PathMaterial::Diffuse], lights: &[], environment: &environment, settings: PathTracerSettings::default() };
+        let mut rng = Rng::new(0, 0);
+        for _ in 0..100 {
+            let ray = Ray::new(Vector3::new(rng.range(-0.5, 0.5), 2.0, rng.range(-0.5, 0.5)), -Vector3::y());
+            assert!((tracer.trace(&ray, &mut rng) - Vector3::repeat(1.0)).norm() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn passes_are_the_same_for_any_thread_count() {
+        let floor = unit_plane(4, 4, 0xB0B0B0);
+        let bvh = MeshBvh::build(&floor);
+        let scene = SceneBvh::build(vec![Instance::new(&floor, &bvh, Matrix4::identity())]);
+        let environment = ConstantEnvironment(Vector3::new(0.2, 0.3, 0.5));
+        let camera = Camera {
+            fov: 90.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(0.0, 2.0, -3.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        };
+        let render = |threads| {
+            let tracer = PathTracer { scene: &scene, materials: &[PathMaterial::Diffuse], lights: &[], environment: &environment, settings: PathTracerSettings { threads, tile_size: 5, ..Default::default() } };
+            let mut accumulator = Accumulator::new((16, 12));
+            accumulator.add_pass(&tracer, &camera);
+            accumulator.add_pass(&tracer, &camera);
+            accumulator.average()
+        };
+        assert_eq!(render(1), render(3));
+    }
+}
@@ -0,0 +1,1088 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs;
+use std::str::FromStr;
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use super::framebuffer::Framebuffer;
+use super::math::{linear_to_srgb, srgb_color_to_linear, srgb_to_linear, Rng};
+use super::render::{view_depth, Camera};
+use super::tonemap::{ToneMapper, ToneMapping};
+
+// a screen space pass over the framebuffer attachments, run after all objects are drawn
+pub trait PostEffect {
+    fn apply(&self, framebuffer: &mut Framebuffer, camera: &Camera);
+}
+
+// effects run in order. put tonemap before the effects that expect display values (fxaa, lut, dither)
+// and resolve the framebuffer with ToneMapping::Clamp afterwards
+#[derive(Default)]
+pub struct PostProcessStack {
+    pub effects: Vec<Box<dyn PostEffect>>,
+}
+
+impl PostProcessStack {
+    pub fn new() -> PostProcessStack {
+        PostProcessStack { effects: vec![] }
+    }
+
+    pub fn push<E: PostEffect + 'static>(&mut self, effect: E) -> &mut PostProcessStack {
+        self.effects.push(Box::new(effect));
+        self
+    }
+
+    pub fn apply(&self, framebuffer: &mut Framebuffer, camera: &Camera) {
+        for effect in self.effects.iter() {
+            effect.apply(framebuffer, camera);
+        }
+    }
+
+    // one effect per line as "name key=value ...", empty lines and lines starting with # are skipped.
+    // parameters that are left out keep their default
+    pub fn parse(text: &str) -> Result<PostProcessStack, String> {
+        let mut stack = PostProcessStack::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let effect = parse_effect(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            stack.effects.push(effect);
+        }
+        Ok(stack)
+    }
+
+    pub fn load(path: &str) -> Result<PostProcessStack, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(PostProcessStack::parse(&text)?)
+    }
+}
+
+struct Parameters<'a> {
+    values: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Parameters<'a> {
+    fn get<T: FromStr>(&mut self, key: &str, default: T) -> Result<T, String> {
+        match self.values.remove(key) {
+            Some(value) => value.parse().map_err(|_| format!("invalid value {} for {}", value, key)),
+            None => Ok(default),
+        }
+    }
+
+    fn get_str(&mut self, key: &str) -> Option<&'a str> {
+        self.values.remove(key)
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.values.keys().next() {
+            Some(key) => Err(format!("unknown parameter {}", key)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_effect(line: &str) -> Result<Box<dyn PostEffect>, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let mut values = HashMap::new();
+    for word in words {
+        let (key, value) = word.split_once('=').ok_or_else(|| format!("expected key=value, got {}", word))?;
+        values.insert(key, value);
+    }
+    let mut parameters = Parameters { values };
+
+    let effect: Box<dyn PostEffect> = match name {
+        "tonemap" => {
+            let operator = match parameters.get_str("operator").unwrap_or("aces") {
+                "clamp" => ToneMapping::Clamp,
+                "reinhard" => ToneMapping::Reinhard,
+                "aces" => ToneMapping::AcesFilmic,
+                "uncharted2" => ToneMapping::Uncharted2,
+                other => return Err(format!("unknown tone mapping operator {}", other)),
+            };
+            Box::new(ToneMapper::new(operator, parameters.get("exposure", 0.0)?))
+        }
+        "fxaa" => {
+            let default = Fxaa::default();
+            Box::new(Fxaa {
+                edge_threshold: parameters.get("edge_threshold", default.edge_threshold)?,
+                edge_threshold_min: parameters.get("edge_threshold_min", default.edge_threshold_min)?,
+                subpixel: parameters.get("subpixel", default.subpixel)?,
+            })
+        }
+        "blur" => match parameters.get_str("kind").unwrap_or("gaussian") {
+            "gaussian" => Box::new(Blur::Gaussian { sigma: parameters.get("sigma", 2.0)? }),
+            "box" => Box::new(Blur::Box { radius: parameters.get("radius", 2)? }),
+            other => return Err(format!("unknown blur kind {}", other)),
+        },
+        "bloom" => {
+            let default = Bloom::default();
+            Box::new(Bloom {
+                threshold: parameters.get("threshold", default.threshold)?,
+                knee: parameters.get("knee", default.knee)?,
+                intensity: parameters.get("intensity", default.intensity)?,
+                sigma: parameters.get("sigma", default.sigma)?,
+                levels: parameters.get("levels", default.levels)?,
+            })
+        }
+        "vignette" => {
+            let default = Vignette::default();
+            Box::new(Vignette {
+                intensity: parameters.get("intensity", default.intensity)?,
+                radius: parameters.get("radius", default.radius)?,
+                softness: parameters.get("softness", default.softness)?,
+            })
+        }
+        "chromatic_aberration" => Box::new(ChromaticAberration { strength: parameters.get("strength", ChromaticAberration::default().strength)? }),
+        "lut" => {
+            let path = parameters.get_str("path").ok_or("lut needs a path")?;
+            let lut = Lut3d::load_cube(path).map_err(|e| format!("could not load {}: {}", path, e))?;
+            Box::new(ColorGrading { lut, intensity: parameters.get("intensity", 1.0)? })
+        }
+        "ssao" => {
+            let default = Ssao::default();
+            Box::new(Ssao {
+                radius: parameters.get("radius", default.radius)?,
+                samples: parameters.get("samples", default.samples)?,
+                strength: parameters.get("strength", default.strength)?,
+                bias: parameters.get("bias", default.bias)?,
+                blur_radius: parameters.get("blur_radius", default.blur_radius)?,
+                debug: parameters.get("debug", default.debug)?,
+            })
+        }
+        "fog" => {
+            let default = Fog::default();
+            let falloff = match parameters.get_str("mode").unwrap_or("exponential") {
+                "none" => FogFalloff::None,
+                "linear" => FogFalloff::Linear { start: parameters.get("start", 10.0)?, end: parameters.get("end", 100.0)? },
+                "exponential" => FogFalloff::Exponential { density: parameters.get("density", 0.02)? },
+                "exponential_squared" => FogFalloff::ExponentialSquared { density: parameters.get("density", 0.02)? },
+                other => return Err(format!("unknown fog mode {}", other)),
+            };
+            let color = match parameters.get_str("color") {
+                Some(hex) => srgb_color_to_linear(u32::from_str_radix(hex.trim_start_matches('#'), 16).map_err(|_| format!("invalid color {}", hex))?),
+                None => default.color,
+            };
+            Box::new(Fog {
+                color,
+                falloff,
+                height_density: parameters.get("height_density", default.height_density)?,
+                height_falloff: parameters.get("height_falloff", default.height_falloff)?,
+                base_height: parameters.get("base_height", default.base_height)?,
+                fog_background: parameters.get("fog_background", default.fog_background)?,
+            })
+        }
+        "sharpen" => Box::new(Sharpen { amount: parameters.get("amount", Sharpen::default().amount)? }),
+        "dither" => {
+            let mode = match parameters.get_str("mode").unwrap_or("ordered") {
+                "ordered" => DitherMode::Ordered,
+                "floyd_steinberg" => DitherMode::FloydSteinberg,
+                other => return Err(format!("unknown dither mode {}", other)),
+            };
+            Box::new(Dither { mode, levels: parameters.get("levels", Dither::default().levels)? })
+        }
+        other => return Err(format!("unknown effect {}", other)),
+    };
+    parameters.finish()?;
+    Ok(effect)
+}
+
+// a plain image the effects work on, lookups outside are clamped to the edge
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vector4<f32>>,
+}
+
+impl Image {
+    fn from_framebuffer(framebuffer: &Framebuffer) -> Image {
+        Image { width: framebuffer.dimensions.0, height: framebuffer.dimensions.1, pixels: framebuffer.color.clone() }
+    }
+
+    fn get(&self, x: i64, y: i64) -> Vector4<f32> {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    // bilinear in pixel coordinates, pixel centers are at +0.5
+    fn sample(&self, x: f32, y: f32) -> Vector4<f32> {
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.get(x0, y0).lerp(&self.get(x0 + 1, y0), tx);
+        let bottom = self.get(x0, y0 + 1).lerp(&self.get(x0 + 1, y0 + 1), tx);
+        top.lerp(&bottom, ty)
+    }
+
+    fn map<F: Fn(usize, usize) -> Vector4<f32>>(width: usize, height: usize, f: F) -> Image {
+        let pixels = (0..width * height).map(|i| f(i % width, i / width)).collect();
+        Image { width, height, pixels }
+    }
+
+    // separable convolution with a symmetric kernel, weights[0] is the center
+    fn convolve(&self, weights: &[f32]) -> Image {
+        let radius = weights.len() as i64 - 1;
+        let pass = |image: &Image, dx: i64, dy: i64| Image::map(image.width, image.height, |x, y| {
+            let (x, y) = (x as i64, y as i64);
+            (-radius..=radius).map(|i| image.get(x + i * dx, y + i * dy) * weights[i.unsigned_abs() as usize]).sum()
+        });
+        pass(&pass(self, 1, 0), 0, 1)
+    }
+
+    fn gaussian_blur(&self, sigma: f32) -> Image {
+        let radius = (sigma * 3.0).ceil().max(1.0) as usize;
+        let weights: Vec<f32> = (0..=radius).map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp()).collect();
+        let sum = weights[0] + 2.0 * weights[1..].iter().sum::<f32>();
+        self.convolve(&weights.iter().map(|w| w / sum).collect::<Vec<_>>())
+    }
+
+    fn box_blur(&self, radius: usize) -> Image {
+        self.convolve(&vec![1.0 / (2 * radius + 1) as f32; radius + 1])
+    }
+
+    fn downsample(&self) -> Image {
+        Image::map((self.width / 2).max(1), (self.height / 2).max(1), |x, y| {
+            let (x, y) = (2 * x as i64, 2 * y as i64);
+            (self.get(x, y) + self.get(x + 1, y) + self.get(x, y + 1) + self.get(x + 1, y + 1)) / 4.0
+        })
+    }
+}
+
+fn luminance(color: &Vector4<f32>) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl PostEffect for ToneMapper {
+    // the colors stay linear but end up in 0..1
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        for color in framebuffer.color.iter_mut() {
+            let mapped = ToneMapper::apply(self, &color.xyz());
+            *color = Vector4::new(mapped.x, mapped.y, mapped.z, color.w);
+        }
+    }
+}
+
+// fast approximate anti aliasing after Timothy Lottes' FXAA 3.11, expects tone mapped colors
+#[derive(Clone, Copy, Debug)]
+pub struct Fxaa {
+    // minimum local contrast relative to the brightest neighbour to count as an edge
+    pub edge_threshold: f32,
+    // skips dark areas where the contrast is below this
+    pub edge_threshold_min: f32,
+    // 0 keeps single pixel details sharp, 1 blurs them fully
+    pub subpixel: f32,
+}
+
+impl Default for Fxaa {
+    fn default() -> Fxaa {
+        Fxaa { edge_threshold: 0.125, edge_threshold_min: 0.0312, subpixel: 0.75 }
+    }
+}
+
+const FXAA_STEPS: [f32; 10] = [1.0, 1.0, 1.0, 1.0, 1.5, 2.0, 2.0, 2.0, 4.0, 8.0];
+
+impl PostEffect for Fxaa {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let image = Image::from_framebuffer(framebuffer);
+        // perceptual luma, the colors are linear
+        let luma = |color: Vector4<f32>| luminance(&color).max(0.0).sqrt();
+        let lumas = Image::map(image.width, image.height, |x, y| Vector4::repeat(luma(image.get(x as i64, y as i64))));
+        let luma_at = |x: i64, y: i64| lumas.get(x, y).x;
+        let luma_sample = |x: f32, y: f32| luma(image.sample(x, y));
+
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let (xi, yi) = (x as i64, y as i64);
+                let m = luma_at(xi, yi);
+                let (n, s, e, w) = (luma_at(xi, yi - 1), luma_at(xi, yi + 1), luma_at(xi + 1, yi), luma_at(xi - 1, yi));
+                let highest = m.max(n).max(s).max(e).max(w);
+                let lowest = m.min(n).min(s).min(e).min(w);
+                let contrast = highest - lowest;
+                if contrast < self.edge_threshold_min.max(highest * self.edge_threshold) {
+                    continue;
+                }
+                let (ne, nw, se, sw) = (luma_at(xi + 1, yi - 1), luma_at(xi - 1, yi - 1), luma_at(xi + 1, yi + 1), luma_at(xi - 1, yi + 1));
+
+                let average = (2.0 * (n + s + e + w) + ne + nw + se + sw) / 12.0;
+                let subpixel_blend = smoothstep(0.0, 1.0, ((average - m).abs() / contrast).clamp(0.0, 1.0));
+                let subpixel_blend = subpixel_blend * subpixel_blend * self.subpixel;
+
+                // a horizontal edge changes brightness vertically
+                let horizontal = 2.0 * (n + s - 2.0 * m).abs() + (ne + se - 2.0 * e).abs() + (nw + sw - 2.0 * w).abs();
+                let vertical = 2.0 * (e + w - 2.0 * m).abs() + (ne + nw - 2.0 * n).abs() + (se + sw - 2.0 * s).abs();
+                let is_horizontal = horizontal >= vertical;
+
+                let (positive, negative) = if is_horizontal { (s, n) } else { (e, w) };
+                let (gradient_positive, gradient_negative) = ((positive - m).abs(), (negative - m).abs());
+                let (step, opposite, gradient) = if gradient_positive >= gradient_negative {
+                    (1.0, positive, gradient_positive)
+                } else {
+                    (-1.0, negative, gradient_negative)
+                };
+
+                // walk along the edge in both directions until the luma leaves the edge
+                let center = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let (across, along) = if is_horizontal { (Vector2::new(0.0, 1.0), Vector2::new(1.0, 0.0)) } else { (Vector2::new(1.0, 0.0), Vector2::new(0.0, 1.0)) };
+                let edge = center + across * (step * 0.5);
+                let edge_luma = (m + opposite) / 2.0;
+                let threshold = gradient / 4.0;
+                let walk = |direction: f32| {
+                    let mut distance = 0.0;
+                    let mut delta = 0.0;
+                    for step_size in FXAA_STEPS {
+                        distance += step_size;
+                        let point = edge + along * (direction * distance);
+                        delta = luma_sample(point.x, point.y) - edge_luma;
+                        if delta.abs() >= threshold {
+                            break;
+                        }
+                    }
+                    (distance, delta)
+                };
+                let (distance_positive, delta_positive) = walk(1.0);
+                let (distance_negative, delta_negative) = walk(-1.0);
+                let (shortest, delta) = if distance_positive <= distance_negative {
+                    (distance_positive, delta_positive)
+                } else {
+                    (distance_negative, delta_negative)
+                };
+                // only blend if the closer edge end bends away from this pixel
+                let edge_blend = if (m - edge_luma < 0.0) != (delta < 0.0) {
+                    0.5 - shortest / (distance_positive + distance_negative)
+                } else {
+                    0.0
+                };
+
+                let blend = edge_blend.max(subpixel_blend);
+                let point = center + across * (step * blend);
+                framebuffer.color[y * image.width + x] = image.sample(point.x, point.y);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Blur {
+    // sigma in pixels
+    Gaussian { sigma: f32 },
+    Box { radius: usize },
+}
+
+impl PostEffect for Blur {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let image = Image::from_framebuffer(framebuffer);
+        let blurred = match *self {
+            Blur::Gaussian { sigma } => image.gaussian_blur(sigma.max(0.01)),
+            Blur::Box { radius } => image.box_blur(radius),
+        };
+        framebuffer.color = blurred.pixels;
+    }
+}
+
+// adds a blurred copy of everything brighter than the threshold, meant for hdr colors before tone mapping
+#[derive(Clone, Copy, Debug)]
+pub struct Bloom {
+    // luminance where the glow starts
+    pub threshold: f32,
+    // soft transition around the threshold
+    pub knee: f32,
+    pub intensity: f32,
+    // blur of every level in pixels of that level
+    pub sigma: f32,
+    // every level halves the resolution, more levels give a wider glow
+    pub levels: usize,
+}
+
+impl Default for Bloom {
+    fn default() -> Bloom {
+        Bloom { threshold: 1.0, knee: 0.5, intensity: 0.3, sigma: 2.0, levels: 5 }
+    }
+}
+
+impl PostEffect for Bloom {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let image = Image::from_framebuffer(framebuffer);
+        let bright = Image::map(image.width, image.height, |x, y| {
+            let color = image.get(x as i64, y as i64);
+            let brightness = luminance(&color);
+            // quadratic soft knee as in most engines
+            let soft = (brightness - self.threshold + self.knee).clamp(0.0, 2.0 * self.knee);
+            let soft = soft * soft / (4.0 * self.knee + 1e-4);
+            let contribution = soft.max(brightness - self.threshold) / brightness.max(1e-4);
+            Vector4::new(color.x, color.y, color.z, 0.0) * contribution.max(0.0)
+        });
+
+        let mut level = bright;
+        let mut glow = vec![Vector4::zeros(); image.width * image.height];
+        for _ in 0..self.levels.max(1) {
+            level = level.downsample();
+            let blurred = level.gaussian_blur(self.sigma.max(0.01));
+            let scale = (blurred.width as f32 / image.width as f32, blurred.height as f32 / image.height as f32);
+            for (i, pixel) in glow.iter_mut().enumerate() {
+                let (x, y) = ((i % image.width) as f32 + 0.5, (i / image.width) as f32 + 0.5);
+                *pixel += blurred.sample(x * scale.0, y * scale.1);
+            }
+        }
+        let weight = self.intensity / self.levels.max(1) as f32;
+        for (color, glow) in framebuffer.color.iter_mut().zip(glow.iter()) {
+            *color += glow * weight;
+        }
+    }
+}
+
+// darkens the image towards the corners
+#[derive(Clone, Copy, Debug)]
+pub struct Vignette {
+    pub intensity: f32,
+    // distance from the center where the darkening starts, 1.0 is a corner
+    pub radius: f32,
+    pub softness: f32,
+}
+
+impl Default for Vignette {
+    fn default() -> Vignette {
+        Vignette { intensity: 0.4, radius: 0.5, softness: 0.5 }
+    }
+}
+
+impl PostEffect for Vignette {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let (width, height) = framebuffer.dimensions;
+        let center = Vector2::new(width as f32, height as f32) / 2.0;
+        let half_diagonal = center.norm();
+        for (i, color) in framebuffer.color.iter_mut().enumerate() {
+            let position = Vector2::new((i % width) as f32 + 0.5, (i / width) as f32 + 0.5);
+            let distance = (position - center).norm() / half_diagonal;
+            let factor = 1.0 - self.intensity * smoothstep(self.radius, self.radius + self.softness, distance);
+            *color = Vector4::new(color.x * factor, color.y * factor, color.z * factor, color.w);
+        }
+    }
+}
+
+// shifts red outwards and blue inwards, growing towards the corners
+#[derive(Clone, Copy, Debug)]
+pub struct ChromaticAberration {
+    // offset in pixels at the corners
+    pub strength: f32,
+}
+
+impl Default for ChromaticAberration {
+    fn default() -> ChromaticAberration {
+        ChromaticAberration { strength: 2.0 }
+    }
+}
+
+impl PostEffect for ChromaticAberration {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let image = Image::from_framebuffer(framebuffer);
+        let center = Vector2::new(image.width as f32, image.height as f32) / 2.0;
+        let half_diagonal = center.norm();
+        for (i, color) in framebuffer.color.iter_mut().enumerate() {
+            let position = Vector2::new((i % image.width) as f32 + 0.5, (i / image.width) as f32 + 0.5);
+            let offset = (position - center) / half_diagonal * self.strength;
+            let red = image.sample(position.x + offset.x, position.y + offset.y).x;
+            let blue = image.sample(position.x - offset.x, position.y - offset.y).z;
+            *color = Vector4::new(red, color.y, blue, color.w);
+        }
+    }
+}
+
+// a 3d color lookup table in the Adobe/Resolve .cube format, applied to sRGB encoded values
+#[derive(Clone, Debug)]
+pub struct Lut3d {
+    pub size: usize,
+    pub domain_min: Vector3<f32>,
+    pub domain_max: Vector3<f32>,
+    // red changes fastest, then green, then blue
+    pub table: Vec<Vector3<f32>>,
+}
+
+impl Lut3d {
+    pub fn parse_cube(text: &str) -> Result<Lut3d, String> {
+        let mut size = 0;
+        let mut domain_min = Vector3::zeros();
+        let mut domain_max = Vector3::repeat(1.0);
+        let mut table = vec![];
+        let parse_vector = |words: &[&str]| -> Result<Vector3<f32>, String> {
+            if words.len() != 3 {
+                return Err(format!("expected three values, got {}", words.join(" ")));
+            }
+            let value = |word: &str| word.parse::<f32>().map_err(|_| format!("invalid number {}", word));
+            Ok(Vector3::new(value(words[0])?, value(words[1])?, value(words[2])?))
+        };
+        for line in text.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.first() {
+                None => {}
+                Some(word) if word.starts_with('#') => {}
+                Some(&"TITLE") => {}
+                Some(&"LUT_3D_SIZE") => {
+                    size = words.get(1).and_then(|word| word.parse().ok()).ok_or("invalid LUT_3D_SIZE")?;
+                }
+                Some(&"DOMAIN_MIN") => domain_min = parse_vector(&words[1..])?,
+                Some(&"DOMAIN_MAX") => domain_max = parse_vector(&words[1..])?,
+                Some(&"LUT_1D_SIZE") => return Err("1d luts are not supported".to_string()),
+                Some(_) => table.push(parse_vector(&words)?),
+            }
+        }
+        if size < 2 || table.len() != size * size * size {
+            return Err(format!("expected {} entries for size {}, got {}", size * size * size, size, table.len()));
+        }
+        Ok(Lut3d { size, domain_min, domain_max, table })
+    }
+
+    pub fn load_cube(path: &str) -> Result<Lut3d, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(Lut3d::parse_cube(&text)?)
+    }
+
+    fn entry(&self, r: usize, g: usize, b: usize) -> Vector3<f32> {
+        self.table[(b * self.size + g) * self.size + r]
+    }
+
+    // trilinear lookup
+    pub fn lookup(&self, color: &Vector3<f32>) -> Vector3<f32> {
+        let range = self.domain_max - self.domain_min;
+        let last = (self.size - 1) as f32;
+        let position = (color - self.domain_min).component_div(&range).map(|c| c.clamp(0.0, 1.0) * last);
+        let base = position.map(|c| (c.floor() as usize).min(self.size - 2));
+        let t = position - base.map(|c| c as f32);
+        let (r, g, b) = (base.x, base.y, base.z);
+        let lerp_g = |b: usize| {
+            let bottom = self.entry(r, g, b).lerp(&self.entry(r + 1, g, b), t.x);
+            let top = self.entry(r, g + 1, b).lerp(&self.entry(r + 1, g + 1, b), t.x);
+            bottom.lerp(&top, t.y)
+        };
+        lerp_g(b).lerp(&lerp_g(b + 1), t.z)
+    }
+}
+
+// expects tone mapped colors
+#[derive(Clone, Debug)]
+pub struct ColorGrading {
+    pub lut: Lut3d,
+    // blends between the original (0.0) and the graded colors (1.0)
+    pub intensity: f32,
+}
+
+impl PostEffect for ColorGrading {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        for color in framebuffer.color.iter_mut() {
+            let encoded = color.xyz().map(linear_to_srgb);
+            let graded = encoded.lerp(&self.lut.lookup(&encoded), self.intensity).map(srgb_to_linear);
+            *color = Vector4::new(graded.x, graded.y, graded.z, color.w);
+        }
+    }
+}
+
+// unsharp mask with the four direct neighbours
+#[derive(Clone, Copy, Debug)]
+pub struct Sharpen {
+    pub amount: f32,
+}
+
+impl Default for Sharpen {
+    fn default() -> Sharpen {
+        Sharpen { amount: 0.3 }
+    }
+}
+
+impl PostEffect for Sharpen {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let image = Image::from_framebuffer(framebuffer);
+        for (i, color) in framebuffer.color.iter_mut().enumerate() {
+            let (x, y) = ((i % image.width) as i64, (i / image.width) as i64);
+            let neighbours = image.get(x - 1, y) + image.get(x + 1, y) + image.get(x, y - 1) + image.get(x, y + 1);
+            let sharpened = (*color * (1.0 + 4.0 * self.amount) - neighbours * self.amount).map(|c| c.max(0.0));
+            *color = Vector4::new(sharpened.x, sharpened.y, sharpened.z, color.w);
+        }
+    }
+}
+
+// view space direction through the center of a pixel with z = 1. x is right and y up
+fn view_ray(camera: &Camera, dimensions: (usize, usize), index: usize) -> Vector3<f32> {
+    let (width, height) = dimensions;
+    let aspect_ratio = width as f32 / height as f32;
+    let focal_length = 1.0 / (camera.fov.to_radians() / 2.0).tan();
+    let ndc_x = ((index % width) as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+    let ndc_y = 1.0 - ((index / width) as f32 + 0.5) / height as f32 * 2.0;
+    Vector3::new(ndc_x * aspect_ratio / focal_length, ndc_y / focal_length, 1.0)
+}
+
+// view space positions from the depth attachment, None where nothing was drawn. the camera has
+// to be the one the framebuffer was drawn with
+fn view_positions(framebuffer: &Framebuffer, camera: &Camera) -> Vec<Option<Vector3<f32>>> {
+    framebuffer.depth.iter().enumerate().map(|(i, &depth)| {
+        view_depth(depth, camera.near, camera.far).map(|depth| view_ray(camera, framebuffer.dimensions, i) * depth)
+    }).collect()
+}
+
+// screen space ambient occlusion from the depth and normals attachments. it darkens the whole
+// color, so it belongs in front of bloom and the tone mapper
+#[derive(Clone, Copy, Debug)]
+pub struct Ssao {
+    // world units around a pixel that can occlude it
+    pub radius: f32,
+    pub samples: usize,
+    // 0 disables the effect, 1 lets fully occluded pixels go black
+    pub strength: f32,
+    // view depth difference below which a sample does not count, hides acne on flat surfaces
+    pub bias: f32,
+    // of the bilateral blur in pixels, 2 covers the 4x4 noise tile
+    pub blur_radius: usize,
+    // shows the occlusion as grayscale instead of applying it
+    pub debug: bool,
+}
+
+impl Default for Ssao {
+    fn default() -> Ssao {
+        Ssao { radius: 0.5, samples: 16, strength: 1.0, bias: 0.025, blur_radius: 2, debug: false }
+    }
+}
+
+const SSAO_NOISE_SIZE: usize = 4;
+
+impl Ssao {
+    // offsets in the hemisphere around +z, denser close to the center
+    fn kernel(&self) -> Vec<Vector3<f32>> {
+        let mut rng = Rng::new(0x55A0, 0);
+        let count = self.samples.max(1);
+        (0..count).map(|i| {
+            let direction = loop {
+                let candidate = Vector3::new(rng.range(-1.0, 1.0), rng.range(-1.0, 1.0), rng.next_f32());
+                if candidate.norm_squared() <= 1.0 && candidate.norm_squared() > 1e-4 {
+                    break candidate.normalize();
+                }
+            };
+            let t = i as f32 / count as f32;
+            direction * rng.next_f32() * (0.1 + 0.9 * t * t)
+        }).collect()
+    }
+
+    // random rotations of the kernel around the normal, tiled over the screen
+    fn noise(&self) -> Vec<Vector3<f32>> {
+        let mut rng = Rng::new(0x55A0, 1);
+        (0..SSAO_NOISE_SIZE * SSAO_NOISE_SIZE).map(|_| {
+            let angle = rng.range(0.0, 2.0 * PI);
+            Vector3::new(angle.cos(), angle.sin(), 0.0)
+        }).collect()
+    }
+
+    // blurred occlusion per pixel, 1.0 is unoccluded
+    pub fn ambient_occlusion(&self, framebuffer: &Framebuffer, camera: &Camera) -> Vec<f32> {
+        let (width, height) = framebuffer.dimensions;
+        let aspect_ratio = width as f32 / height as f32;
+        let focal_length = 1.0 / (camera.fov.to_radians() / 2.0).tan();
+        let positions = view_positions(framebuffer, camera);
+        let (right, up, forward) = camera.get_basis();
+        let normals: Vec<Vector3<f32>> = framebuffer.normals.iter().zip(positions.iter()).map(|(normal, position)| {
+            let normal = Vector3::new(normal.dot(&right), normal.dot(&up), normal.dot(&forward));
+            // double sided surfaces seen from behind
+            match position {
+                Some(position) if normal.dot(position) > 0.0 => -normal,
+                _ => normal,
+            }
+        }).collect();
+
+        let kernel = self.kernel();
+        let noise = self.noise();
+        let mut occlusion = vec![1.0; width * height];
+        for (i, value) in occlusion.iter_mut().enumerate() {
+            let (Some(position), normal) = (positions[i], normals[i]) else {
+                continue;
+            };
+            if normal.norm_squared() < 0.5 {
+                continue;
+            }
+            let random = noise[(i / width) % SSAO_NOISE_SIZE * SSAO_NOISE_SIZE + (i % width) % SSAO_NOISE_SIZE];
+            let tangent = (random - normal * random.dot(&normal))
+                .try_normalize(1e-6)
+                .unwrap_or_else(|| normal.cross(&Vector3::x()).try_normalize(1e-6).unwrap_or_else(Vector3::y));
+            let bitangent = normal.cross(&tangent);
+
+            let mut occluded = 0.0;
+            for offset in kernel.iter() {
+                let sample = position + (tangent * offset.x + bitangent * offset.y + normal * offset.z) * self.radius;
+                if sample.z <= camera.near {
+                    continue;
+                }
+                let x = (sample.x * focal_length / (aspect_ratio * sample.z) + 1.0) / 2.0 * width as f32;
+                let y = (1.0 - sample.y * focal_length / sample.z) / 2.0 * height as f32;
+                if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+                    continue;
+                }
+                // the background never occludes
+                let Some(scene) = positions[y as usize * width + x as usize] else {
+                    continue;
+                };
+                if scene.z <= sample.z - self.bias {
+                    // geometry far in front of the pixel is something else and should not darken it
+                    occluded += smoothstep(0.0, 1.0, self.radius / (position.z - scene.z).abs());
+                }
+            }
+            *value = (1.0 - self.strength * occluded / kernel.len() as f32).clamp(0.0, 1.0);
+        }
+
+        // bilateral blur, neighbours on other surfaces are ignored so edges stay sharp. the window
+        // is 2 * blur_radius wide to cancel the noise tile exactly
+        let radius = self.blur_radius as i64;
+        if radius == 0 {
+            return occlusion;
+        }
+        (0..width * height).map(|i| {
+            let (Some(position), normal) = (positions[i], normals[i]) else {
+                return 1.0;
+            };
+            let (x, y) = ((i % width) as i64, (i / width) as i64);
+            let (mut sum, mut weights) = (0.0, 0.0);
+            for ny in (y - radius).max(0)..(y + radius).min(height as i64) {
+                for nx in (x - radius).max(0)..(x + radius).min(width as i64) {
+                    let neighbour = ny as usize * width + nx as usize;
+                    let Some(neighbour_position) = positions[neighbour] else {
+                        continue;
+                    };
+                    let depth_weight = (1.0 - (neighbour_position.z - position.z).abs() / self.radius).max(0.0);
+                    let normal_weight = normal.dot(&normals[neighbour]).max(0.0).powi(8);
+                    let weight = depth_weight * normal_weight;
+                    sum += occlusion[neighbour] * weight;
+                    weights += weight;
+                }
+            }
+            if weights > 0.0 { sum / weights } else { occlusion[i] }
+        }).collect()
+    }
+}
+
+impl PostEffect for Ssao {
+    fn apply(&self, framebuffer: &mut Framebuffer, camera: &Camera) {
+        let occlusion = self.ambient_occlusion(framebuffer, camera);
+        for (color, occlusion) in framebuffer.color.iter_mut().zip(occlusion.iter()) {
+            *color = if self.debug {
+                // comes out as the occlusion value after the sRGB output transform
+                let gray = srgb_to_linear(*occlusion);
+                Vector4::new(gray, gray, gray, color.w)
+            } else {
+                Vector4::new(color.x * occlusion, color.y * occlusion, color.z * occlusion, color.w)
+            };
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogFalloff {
+    // no distance fog, only height fog if it is enabled
+    None,
+    // no fog before start and full fog after end, in world units from the camera
+    Linear { start: f32, end: f32 },
+    // visibility exp(-density * distance)
+    Exponential { density: f32 },
+    // visibility exp(-(density * distance)^2), stays clear longer and closes in faster
+    ExponentialSquared { density: f32 },
+}
+
+impl FogFalloff {
+    // fraction of the surface color left after the distance
+    pub fn visibility(&self, distance: f32) -> f32 {
+        match *self {
+            FogFalloff::None => 1.0,
+            FogFalloff::Linear { start, end } => 1.0 - ((distance - start) / (end - start).max(1e-6)).clamp(0.0, 1.0),
+            FogFalloff::Exponential { density } => (-density * distance).exp(),
+            FogFalloff::ExponentialSquared { density } => (-(density * distance).powi(2)).exp(),
+        }
+    }
+}
+
+// distance and height fog from the depth attachment, on hdr colors before the tone mapper
+#[derive(Clone, Copy, Debug)]
+pub struct Fog {
+    // linear rgb
+    pub color: Vector3<f32>,
+    pub falloff: FogFalloff,
+    // density at base_height of fog that thins out exponentially above it, 0 disables it
+    pub height_density: f32,
+    // how quickly the height fog thins out per world unit
+    pub height_falloff: f32,
+    pub base_height: f32,
+    // pixels nothing was drawn on are treated as being at the far plane
+    pub fog_background: bool,
+}
+
+impl Default for Fog {
+    fn default() -> Fog {
+        Fog {
+            color: Vector3::new(0.5, 0.6, 0.7),
+            falloff: FogFalloff::Exponential { density: 0.02 },
+            height_density: 0.0,
+            height_falloff: 1.0,
+            base_height: 0.0,
+            fog_background: true,
+        }
+    }
+}
+
+impl Fog {
+    // height fog density integrated along the ray from the camera, see Inigo Quilez' "better fog"
+    fn height_optical_depth(&self, camera_height: f32, direction: &Vector3<f32>, distance: f32) -> f32 {
+        if self.height_density <= 0.0 {
+            return 0.0;
+        }
+        let start = self.height_density * (-self.height_falloff * (camera_height - self.base_height)).exp();
+        let rise = self.height_falloff * direction.y;
+        if rise.abs() < 1e-5 {
+            return start * distance;
+        }
+        start * (1.0 - (-rise * distance).exp()) / rise
+    }
+}
+
+impl PostEffect for Fog {
+    fn apply(&self, framebuffer: &mut Framebuffer, camera: &Camera) {
+        let positions = view_positions(framebuffer, camera);
+        let (right, up, forward) = camera.get_basis();
+        for (i, color) in framebuffer.color.iter_mut().enumerate() {
+            let view_position = match positions[i] {
+                Some(position) => position,
+                None if self.fog_background => view_ray(camera, framebuffer.dimensions, i) * camera.far,
+                None => continue,
+            };
+            let distance = view_position.norm();
+            let direction = (right * view_position.x + up * view_position.y + forward * view_position.z) / distance.max(1e-6);
+            let visibility = self.falloff.visibility(distance) * (-self.height_optical_depth(camera.position.y, &direction, distance)).exp();
+            let fogged = self.color.lerp(&color.xyz(), visibility);
+            *color = Vector4::new(fogged.x, fogged.y, fogged.z, color.w);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DitherMode {
+    // 8x8 bayer matrix
+    Ordered,
+    // error diffusion to the right and the next row
+    FloydSteinberg,
+}
+
+// quantizes the sRGB encoded colors to the given number of levels per channel, hiding the banding
+// with noise. expects tone mapped colors and should run last, 256 levels matches the window
+#[derive(Clone, Copy, Debug)]
+pub struct Dither {
+    pub mode: DitherMode,
+    pub levels: u32,
+}
+
+impl Default for Dither {
+    fn default() -> Dither {
+        Dither { mode: DitherMode::Ordered, levels: 256 }
+    }
+}
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+impl PostEffect for Dither {
+    fn apply(&self, framebuffer: &mut Framebuffer, _camera: &Camera) {
+        let (width, height) = framebuffer.dimensions;
+        let steps = (self.levels.max(2) - 1) as f32;
+        let quantize = |value: f32| (value.round().clamp(0.0, steps)) / steps;
+        match self.mode {
+            DitherMode::Ordered => {
+                for (i, color) in framebuffer.color.iter_mut().enumerate() {
+                    let threshold = (BAYER_8X8[(i / width) % 8][(i % width) % 8] as f32 + 0.5) / 64.0 - 0.5;
+                    let dithered = color.xyz().map(|c| srgb_to_linear(quantize(linear_to_srgb(c) * steps + threshold)));
+                    *color = Vector4::new(dithered.x, dithered.y, dithered.z, color.w);
+                }
+            }
+            DitherMode::FloydSteinberg => {
+                let mut encoded: Vec<Vector3<f32>> = framebuffer.color.iter().map(|color| color.xyz().map(linear_to_srgb) * steps).collect();
+                for y in 0..height {
+                    for x in 0..width {
+                        let index = y * width + x;
+                        let old = encoded[index];
+                        let new = old.map(|c| c.round().clamp(0.0, steps));
+                        let error = old - new;
+                        encoded[index] = new;
+                        let mut spread = |dx: i64, dy: usize, weight: f32| {
+                            let (nx, ny) = (x as i64 + dx, y + dy);
+                            if nx >= 0 && (nx as usize) < width && ny < height {
+                                encoded[ny * width + nx as usize] += error * weight;
+                            }
+                        };
+                        spread(1, 0, 7.0 / 16.0);
+                        spread(-1, 1, 3.0 / 16.0);
+                        spread(0, 1, 5.0 / 16.0);
+                        spread(1, 1, 1.0 / 16.0);
+                    }
+                }
+                for (color, value) in framebuffer.color.iter_mut().zip(encoded.iter()) {
+                    let linear = value.map(|c| srgb_to_linear(c / steps));
+                    *color = Vector4::new(linear.x, linear.y, linear.z, color.w);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera {
+            fov: 60.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(0.0, 0.0, -5.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+
+    // a bright gradient with a few hdr pixels, nothing drawn
+    fn gradient() -> Framebuffer {
+        let mut framebuffer = Framebuffer::new((12, 8));
+        for (i, color) in framebuffer.color.iter_mut().enumerate() {
+            *color = Vector4::new((i % 12) as f32 / 4.0, (i / 12) as f32 / 8.0, 0.5, 1.0);
+        }
+        framebuffer
+    }
+
+    fn applied(effect: &dyn PostEffect) -> Vec<Vector4<f32>> {
+        let mut framebuffer = gradient();
+        effect.apply(&mut framebuffer, &camera());
+        framebuffer.color
+    }
+
+    fn parsed(text: &str) -> Vec<Vector4<f32>> {
+        let mut framebuffer = gradient();
+        PostProcessStack::parse(text).unwrap().apply(&mut framebuffer, &camera());
+        framebuffer.color
+    }
+
+    #[test]
+    fn unknown_effects_and_parameters_are_rejected() {
+        for text in ["sparkle", "vignette glow=1", "vignette intensity=bright", "vignette intensity", "blur kind=triangle", "tonemap operator=filmic", "fog mode=linear density=0.1", "lut"] {
+            assert!(PostProcessStack::parse(text).is_err(), "{}", text);
+        }
+        let error = PostProcessStack::parse("# grading\nvignette\n\nbloom treshold=1").err().unwrap();
+        assert!(error.starts_with("line 4:") && error.contains("treshold"), "{}", error);
+    }
+
+    #[test]
+    fn comments_and_empty_lines_are_skipped() {
+        let stack = PostProcessStack::parse("# hdr\n\n  bloom  \ntonemap operator=reinhard exposure=1\n#fxaa\ndither mode=floyd_steinberg").unwrap();
+        assert_eq!(stack.effects.len(), 3);
+        assert!(PostProcessStack::parse("").unwrap().effects.is_empty());
+    }
+
+    #[test]
+    fn missing_parameters_keep_their_defaults() {
+        assert_eq!(parsed("vignette"), applied(&Vignette::default()));
+        assert_eq!(parsed("bloom"), applied(&Bloom::default()));
+        assert_eq!(parsed("fog"), applied(&Fog::default()));
+        assert_eq!(parsed("tonemap"), applied(&ToneMapper::default()));
+        assert_eq!(parsed("vignette radius=0.2"), applied(&Vignette { radius: 0.2, ..Vignette::default() }));
+        assert_ne!(parsed("vignette radius=0.2"), parsed("vignette"));
+        assert_eq!(
+            parsed("fog mode=linear end=20 color=#FF8000"),
+            applied(&Fog { falloff: FogFalloff::Linear { start: 10.0, end: 20.0 }, color: srgb_color_to_linear(0xFF8000), ..Fog::default() })
+        );
+    }
+
+    #[test]
+    fn identity_cube_lut_returns_the_input() {
+        let text = "TITLE \"identity\"\n# red changes fastest\nLUT_3D_SIZE 2\n0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+        let lut = Lut3d::parse_cube(text).unwrap();
+        assert_eq!(lut.size, 2);
+        for color in [Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.25, 0.5, 0.75), Vector3::new(1.0, 0.1, 0.9), Vector3::new(1.0, 1.0, 1.0)] {
+            assert!((lut.lookup(&color) - color).norm() < 1e-6);
+        }
+        // values outside the domain are clamped to it
+        assert!((lut.lookup(&Vector3::new(2.0, -1.0, 0.5)) - Vector3::new(1.0, 0.0, 0.5)).norm() < 1e-6);
+        // a scaled domain maps its range onto the table
+        let scaled = Lut3d::parse_cube(&format!("DOMAIN_MIN 0 0 0\nDOMAIN_MAX 2 2 2\n{}", text)).unwrap();
+        assert!((scaled.lookup(&Vector3::new(1.0, 0.5, 2.0)) - Vector3::new(0.5, 0.25, 1.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn malformed_cube_files_are_rejected() {
+        assert!(Lut3d::parse_cube("LUT_3D_SIZE 2\n0 0 0\n1 0 0").is_err());
+        assert!(Lut3d::parse_cube("LUT_1D_SIZE 2\n0 0 0\n1 1 1").is_err());
+        assert!(Lut3d::parse_cube("LUT_3D_SIZE 1\n0 0 0").is_err());
+        assert!(Lut3d::parse_cube(&format!("LUT_3D_SIZE 2\n{}0 1\n", "0 0 0\n".repeat(7))).is_err());
+    }
+
+    #[test]
+    fn fog_visibility_curves() {
+        let linear = FogFalloff::Linear { start: 10.0, end: 30.0 };
+        assert_eq!(linear.visibility(5.0), 1.0);
+        assert!((linear.visibility(20.0) - 0.5).abs() < 1e-6);
+        assert_eq!(linear.visibility(40.0), 0.0);
+        let exponential = FogFalloff::Exponential { density: 0.1 };
+        let squared = FogFalloff::ExponentialSquared { density: 0.1 };
+        assert_eq!(FogFalloff::None.visibility(1000.0), 1.0);
+        assert!((exponential.visibility(10.0) - (-1.0f32).exp()).abs() < 1e-6);
+        assert!((squared.visibility(10.0) - (-1.0f32).exp()).abs() < 1e-6);
+        // squared stays clearer up to 1 / density and closes in faster after it
+        assert!(squared.visibility(5.0) > exponential.visibility(5.0));
+        assert!(squared.visibility(20.0) < exponential.visibility(20.0));
+        for falloff in [linear, exponential, squared] {
+            assert_eq!(falloff.visibility(0.0), 1.0);
+            let values: Vec<f32> = (0..100).map(|d| falloff.visibility(d as f32)).collect();
+            assert!(values.windows(2).all(|pair| pair[1] <= pair[0]));
+        }
+    }
+
+    // one pixel looking straight ahead at a surface the given distance away
+    fn fogged(fog: &Fog, camera: &Camera, distance: Option<f32>) -> Vector3<f32> {
+        let mut framebuffer = Framebuffer::new((1, 1));
+        framebuffer.color[0] = Vector4::new(1.0, 0.0, 0.0, 1.0);
+        if let Some(distance) = distance {
+            let clip = camera.get_projection_matrix(1.0) * Vector4::new(0.0, 0.0, distance, 1.0);
+            framebuffer.depth[0] = clip.z / clip.w;
+        }
+        fog.apply(&mut framebuffer, camera);
+        framebuffer.color[0].xyz()
+    }
+
+    #[test]
+    fn fog_blends_towards_its_color_with_the_distance() {
+        let fog = Fog { color: Vector3::new(0.0, 0.0, 1.0), falloff: FogFalloff::Exponential { density: 0.05 }, fog_background: false, ..Fog::default() };
+        let visibility = (-1.0f32).exp();
+        assert!((fogged(&fog, &camera(), Some(20.0)) - Vector3::new(visibility, 0.0, 1.0 - visibility)).norm() < 1e-3);
+        // the background is left alone unless it is fogged at the far plane
+        assert_eq!(fogged(&fog, &camera(), None), Vector3::new(1.0, 0.0, 0.0));
+        let background = fogged(&Fog { fog_background: true, ..fog }, &camera(), None);
+        assert!((background.z - (1.0 - (-0.05 * camera().far).exp())).abs() < 1e-3);
+    }
+
+    #[test]
+    fn height_fog_thins_out_above_its_base() {
+        let fog = Fog { color: Vector3::zeros(), falloff: FogFalloff::None, height_density: 0.1, height_falloff: 0.5, ..Fog::default() };
+        let level = |height: f32| Camera {
+            position: Vector4::new(0.0, height, -5.0, 1.0),
+            look_at: Vector4::new(0.0, height, 0.0, 1.0),
+            ..camera()
+        };
+        // looking level through fog of constant density
+        assert!((fogged(&fog, &level(0.0), Some(20.0)).x - (-0.1f32 * 20.0).exp()).abs() < 1e-3);
+        assert!((fogged(&fog, &level(2.0), Some(20.0)).x - (-0.1f32 * (-1.0f32).exp() * 20.0).exp()).abs() < 1e-3);
+        assert!(fogged(&fog, &level(10.0), Some(20.0)).x > 0.95);
+    }
+}
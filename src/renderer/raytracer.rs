@@ -0,0 +1,354 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use nalgebra::Vector3;
+
+use super::bvh::{triangle_positions, Ray, SceneBvh, SceneHit};
+use super::math::srgb_color_to_linear;
+use super::light::Light;
+use super::render::Camera;
+use super::tonemap::ToneMapper;
+
+const RAY_EPSILON: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TracerMaterial {
+    pub diffuse: f32,
+    pub specular: f32,
+    pub shininess: f32,
+    pub reflectivity: f32,
+    pub transparency: f32,
+    pub refractive_index: f32,
+}
+
+impl Default for TracerMaterial {
+    fn default() -> TracerMaterial {
+        TracerMaterial {
+            diffuse: 1.0,
+            specular: 0.0,
+            shininess: 32.0,
+            reflectivity: 0.0,
+            transparency: 0.0,
+            refractive_index: 1.0,
+        }
+    }
+}
+
+pub struct RaytracerSettings {
+    pub max_depth: u32,
+    // supersampling on a regular grid, 2 means 4 rays per pixel
+    pub samples_per_axis: u32,
+    pub tile_size: usize,
+    // 0 uses all available cores
+    pub threads: usize,
+    pub ambient: f32,
+    // sRGB, like the vertex colors
+    pub background_color: u32,
+    // the traced radiance is linear, this turns it into the displayed colors
+    pub tone_mapper: ToneMapper,
+}
+
+impl Default for RaytracerSettings {
+    fn default() -> RaytracerSettings {
+        RaytracerSettings {
+            max_depth: 5,
+            samples_per_axis: 2,
+            tile_size: 32,
+            threads: 0,
+            ambient: 0.1,
+            background_color: 0x000000,
+            tone_mapper: ToneMapper::default(),
+        }
+    }
+}
+
+pub struct SurfacePoint {
+    pub position: Vector3<f32>,
+    // world space, facing against the incoming ray
+    pub normal: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub front_face: bool,
+}
+
+// interpolates the vertex colors with the barycentric hit coordinates in linear space, the normal is
+// the geometric one
+pub fn surface_at(scene: &SceneBvh, hit: &SceneHit, ray: &Ray) -> SurfacePoint {
+    let instance = &scene.instances[hit.instance];
+    let object = instance.object;
+    let positions = triangle_positions(object, hit.hit.triangle);
+    let local_normal = (positions[1] - positions[0]).cross(&(positions[2] - positions[0]));
+    let normal = instance.normal_to_world(&local_normal);
+    let front_face = normal.dot(&ray.direction) < 0.0;
+
+    let (a, b, c) = object.triangles[hit.hit.triangle];
+    let (u, v) = (hit.hit.u, hit.hit.v);
+    let color = srgb_color_to_linear(object.colors[a]) * (1.0 - u - v)
+        + srgb_color_to_linear(object.colors[b]) * u
+        + srgb_color_to_linear(object.colors[c]) * v;
+
+    SurfacePoint {
+        position: ray.at(hit.hit.t),
+        normal: if front_face { normal } else { -normal },
+        color,
+        front_face,
+    }
+}
+
+pub fn reflect(direction: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
+    direction - normal * 2.0 * direction.dot(normal)
+}
+
+// returns None on total internal reflection
+pub fn refract(direction: &Vector3<f32>, normal: &Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let cos_i = -direction.dot(normal).min(1.0);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if k < 0.0 {
+        None
+    } else {
+        Some((direction * eta + normal * (eta * cos_i - k.sqrt())).normalize())
+    }
+}
+
+pub fn schlick(cos_theta: f32, eta: f32) -> f32 {
+    let r0 = ((1.0 - eta) / (1.0 + eta)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+pub struct Raytracer<'a> {
+    pub scene: &'a SceneBvh<'a>,
+    // one per scene instance
+    pub materials: &'a [TracerMaterial],
+    pub lights: &'a [Light],
+    pub settings: RaytracerSettings,
+}
+
+impl<'a> Raytracer<'a> {
+    fn material(&self, instance: usize) -> TracerMaterial {
+        self.materials.get(instance).copied().unwrap_or_default()
+    }
+
+    fn shade(&self, surface: &SurfacePoint, material: &TracerMaterial, view: &Vector3<f32>) -> Vector3<f32> {
+        let mut color = surface.color * self.settings.ambient;
+        for light in self.lights.iter() {
+            color += surface.color.component_mul(&light.ambient(&surface.normal));
+            let Some(sample) = light.sample(&surface.position) else {
+                continue;
+            };
+            let direction = sample.direction;
+            let n_dot_l = surface.normal.dot(&direction);
+            if n_dot_l <= 0.0 {
+                continue;
+            }
+            let shadow_ray = Ray::new(surface.position + surface.normal * RAY_EPSILON, direction);
+            if self.scene.is_occluded(&shadow_ray, sample.distance - RAY_EPSILON) {
+                continue;
+            }
+            let light_color = sample.irradiance;
+            color += surface.color.component_mul(&light_color) * (material.diffuse * n_dot_l);
+            if material.specular > 0.0 {
+                let highlight = reflect(&-direction, &surface.normal).dot(&-view).max(0.0);
+                color += light_color * (material.specular * highlight.powf(material.shininess));
+            }
+        }
+        color
+    }
+
+    pub fn trace(&self, ray: &Ray, depth: u32) -> Vector3<f32> {
+        let Some(hit) = self.scene.intersect_ray(ray, f32::MAX) else {
+            return srgb_color_to_linear(self.settings.background_color);
+        };
+        let surface = surface_at(self.scene, &hit, ray);
+        let material = self.material(hit.instance);
+        let local = self.shade(&surface, &material, &ray.direction);
+        if depth >= self.settings.max_depth || (material.reflectivity <= 0.0 && material.transparency <= 0.0) {
+            return local;
+        }
+
+        let mut reflectivity = material.reflectivity;
+        let mut transmitted = Vector3::zeros();
+        let mut transparency = 0.0;
+        if material.transparency > 0.0 {
+            let eta = if surface.front_face { 1.0 / material.refractive_index } else { material.refractive_index };
+            let cos_theta = -ray.direction.dot(&surface.normal);
+            match refract(&ray.direction, &surface.normal, eta) {
+                Some(direction) => {
+                    let fresnel = schlick(cos_theta, eta);
+                    transparency = material.transparency * (1.0 - fresnel);
+                    reflectivity += material.transparency * fresnel;
+                    let refracted_ray = Ray::new(surface.position - surface.normal * RAY_EPSILON, direction);
+                    transmitted = self.trace(&refracted_ray, depth + 1);
+                }
+                None => reflectivity += material.transparency,
+            }
+        }
+
+        let mut reflected = Vector3::zeros();
+        if reflectivity > 0.0 {
+            let reflected_ray = Ray::new(surface.position + surface.normal * RAY_EPSILON, reflect(&ray.direction, &surface.normal));
+            reflected = self.trace(&reflected_ray, depth + 1);
+        }
+        let reflectivity = reflectivity.min(1.0);
+        local * (1.0 - reflectivity - transparency).max(0.0) + reflected * reflectivity + transmitted * transparency
+    }
+
+    fn render_pixel(&self, camera: &Camera, dimensions: (usize, usize), x: usize, y: usize) -> u32 {
+        let n = self.settings.samples_per_axis.max(1);
+        let mut color = Vector3::zeros();
+        for sy in 0..n {
+            for sx in 0..n {
+                let px = x as f32 + (sx as f32 + 0.5) / n as f32;
+                let py = y as f32 + (sy as f32 + 0.5) / n as f32;
+                color += self.trace(&camera.get_ray(dimensions, px, py), 0);
+            }
+        }
+        self.settings.tone_mapper.to_color(&(color / (n * n) as f32))
+    }
+
+    pub fn render(&self, buffer: &mut [u32], dimensions: (usize, usize), camera: &Camera) {
+        let pixels = render_tiles(dimensions, self.settings.tile_size, self.settings.threads, |x, y| {
+            self.render_pixel(camera, dimensions, x, y)
+        });
+        buffer.copy_from_slice(&pixels);
+    }
+}
+
+// evaluates every pixel on worker threads, tiles are handed out through a shared counter.
+// the result is in row major order, a thread count of 0 uses all available cores
+pub fn render_tiles<T, F>(dimensions: (usize, usize), tile_size: usize, threads: usize, render_pixel: F) -> Vec<T>
+where
+    T: Send + Copy + Default,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    let tile_size = tile_size.max(1);
+    let tiles_x = dimensions.0.div_ceil(tile_size);
+    let tiles_y = dimensions.1.div_ceil(tile_size);
+    let tile_count = tiles_x * tiles_y;
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let next_tile = AtomicUsize::new(0);
+
+    let rendered_tiles: Vec<(usize, Vec<T>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(|| {
+            let mut tiles = vec![];
+            loop {
+                let tile = next_tile.fetch_add(1, Ordering::Relaxed);
+                if tile >= tile_count {
+                    break;
+                }
+                let (x0, y0) = ((tile % tiles_x) * tile_size, (tile / tiles_x) * tile_size);
+                let (x1, y1) = ((x0 + tile_size).min(dimensions.0), (y0 + tile_size).min(dimensions.1));
+                let mut pixels = Vec::with_capacity((x1 - x0) * (y1 - y0));
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        pixels.push(render_pixel(x, y));
+                    }
+                }
+                tiles.push((tile, pixels));
+            }
+            tiles
+        })).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+
+    let mut image = vec![T::default(); dimensions.0 * dimensions.1];
+    for (tile, pixels) in rendered_tiles {
+        let (x0, y0) = ((tile % tiles_x) * tile_size, (tile / tiles_x) * tile_size);
+        let width = (x0 + tile_size).min(dimensions.0) - x0;
+        for (i, row) in pixels.chunks(width).enumerate() {
+            let start = (y0 + i) * dimensions.0 + x0;
+            image[start..start + width].copy_from_slice(row);
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix4, Vector4};
+    use super::super::bvh::{Instance, MeshBvh};
+    use super::super::math::{linear_to_srgb, srgb_to_linear};
+    use super::super::reader::unit_plane;
+    use super::super::tonemap::ToneMapping;
+
+    #[test]
+    fn reflection_mirrors_around_the_normal() {
+        let reflected = reflect(&Vector3::new(1.0, -1.0, 0.0), &Vector3::y());
+        assert!((reflected - Vector3::new(1.0, 1.0, 0.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn refraction_follows_snells_law() {
+        let normal = Vector3::y();
+        let angle = 40f32.to_radians();
+        let direction = Vector3::new(angle.sin(), -angle.cos(), 0.0);
+        let eta = 1.0 / 1.5;
+        let refracted = refract(&direction, &normal, eta).unwrap();
+        assert!((refracted.norm() - 1.0).abs() < 1e-5);
+        assert!((refracted.x - eta * angle.sin()).abs() < 1e-5 && refracted.y < 0.0);
+        // straight through without bending
+        assert!((refract(&-normal, &normal, eta).unwrap() + normal).norm() < 1e-6);
+    }
+
+    #[test]
+    fn refraction_is_totally_reflected_past_the_critical_angle() {
+        let normal = Vector3::y();
+        let critical = (1.0f32 / 1.5).asin();
+        let below = critical - 0.01;
+        let above = critical + 0.01;
+        assert!(refract(&Vector3::new(below.sin(), -below.cos(), 0.0), &normal, 1.5).is_some());
+        assert!(refract(&Vector3::new(above.sin(), -above.cos(), 0.0), &normal, 1.5).is_none());
+    }
+
+    #[test]
+    fn schlick_goes_from_the_base_reflectance_to_one() {
+        assert!((schlick(1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert!((schlick(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-6);
+        assert!((schlick(0.0, 1.5) - 1.0).abs() < 1e-6);
+        assert!(schlick(0.5, 1.5) < schlick(0.2, 1.5));
+    }
+
+    #[test]
+    fn colors_are_lit_in_linear_space_and_tone_mapped() {
+        let plane = unit_plane(2, 2, 0x808080);
+        // unit_plane(2, 2) spans -1..0, moved under the camera
+        let bvh = MeshBvh::build(&plane);
+        let scene = SceneBvh::build(vec![Instance::new(&plane, &bvh, Matrix4::new_scaling(100.0) * Matrix4::new_translation(&Vector3::new(0.5, 0.0, 0.5)))]);
+        let camera = Camera {
+            fov: 60.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 0.0, 1.0, 0.0),
+            position: Vector4::new(0.0, 5.0, 0.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        };
+        let sun = Light::Directional { direction: -Vector3::y(), color: Vector3::repeat(1.0), intensity: 1.0 };
+        let settings = || RaytracerSettings { samples_per_axis: 1, threads: 1, ambient: 0.0, background_color: 0x203040, tone_mapper: ToneMapper::new(ToneMapping::Clamp, 0.0), ..Default::default() };
+        let render = |lights: &[Light], dimensions: (usize, usize)| {
+            let mut buffer = vec![0; dimensions.0 * dimensions.1];
+            Raytracer { scene: &scene, materials: &[], lights, settings: settings() }.render(&mut buffer, dimensions, &camera);
+            buffer
+        };
+        // without tone mapping curves the colors come back as they went in
+        assert!(render(&[sun], (3, 3)).iter().all(|&pixel| pixel == 0x808080));
+        // half the light is half the linear value, not half the encoded one
+        let half = Light::Directional { direction: -Vector3::y(), color: Vector3::repeat(1.0), intensity: 0.5 };
+        let expected = (linear_to_srgb(0.5 * srgb_to_linear(128.0 / 255.0)) * 255.0).round() as u32;
+        assert_eq!(render(&[half], (1, 1))[0] & 0xFF, expected);
+        // rays that miss show the background
+        let empty = SceneBvh::build(vec![]);
+        let mut buffer = vec![0; 4];
+        Raytracer { scene: &empty, materials: &[], lights: &[], settings: settings() }.render(&mut buffer, (2, 2), &camera);
+        assert!(buffer.iter().all(|&pixel| pixel == 0x203040));
+    }
+
+    #[test]
+    fn tiles_fill_the_image_in_row_major_order() {
+        let dimensions = (37, 23);
+        for (tile_size, threads) in [(1, 1), (8, 3), (64, 0)] {
+            let image = render_tiles(dimensions, tile_size, threads, |x, y| (x, y));
+            assert!(image.iter().enumerate().all(|(i, &(x, y))| (x, y) == (i % dimensions.0, i / dimensions.0)));
+        }
+    }
+}
@@ -1,5 +1,6 @@
+use std::collections::HashMap;
 use std::fs;
-use nalgebra::Vector4;
+use nalgebra::{Vector2, Vector3, Vector4};
 
 use super::render::Object3D;
 
@@ -7,6 +8,7 @@ pub fn unit_sphere(color: u32) -> Object3D{
     let mut vertices: Vec<Vector4<f32>> = vec![];
     let mut colors: Vec<u32> = vec![];
     let mut triangles: Vec<(usize, usize, usize)> = vec![];
+    let mut uvs: Vec<Vector2<f32>> = vec![];
 
     let radius = 1.0;
     let sector_count = 36;
@@ -28,6 +30,7 @@ pub fn unit_sphere(color: u32) -> Object3D{
             let y = xy * sector_angle.sin();
             vertices.push(Vector4::new(x, y, z, 1.0));
             colors.push(color);
+            uvs.push(Vector2::new(j as f32 / sector_count as f32, i as f32 / stack_count as f32));
         }
     }
 
@@ -46,7 +49,9 @@ pub fn unit_sphere(color: u32) -> Object3D{
         }
     }
 
-    Object3D::new(vertices, colors, vec![], triangles)
+    let mut sphere = Object3D::new(vertices, colors, vec![], triangles);
+    sphere.uvs = uvs;
+    sphere
 }
 
 pub fn unit_cube(color: u32) -> Object3D{
@@ -67,12 +72,12 @@ pub fn unit_cube(color: u32) -> Object3D{
     ];
 
     let cube_triangles  = vec![
-        (0, 1, 2), (2, 3, 0),
+        (0, 2, 1), (2, 0, 3),
         (4, 5, 6), (6, 7, 4),
         (0, 4, 7), (7, 3, 0),
-        (1, 5, 6), (6, 2, 1),
-        (2, 6, 7), (7, 3, 2),
-        (0, 4, 5), (5, 1, 0)
+        (1, 6, 5), (6, 1, 2),
+        (2, 7, 6), (7, 2, 3),
+        (0, 5, 4), (5, 0, 1)
     ]; 
 
     Object3D::new(cube_vertices, colors, vec![], cube_triangles)
@@ -82,9 +87,16 @@ pub fn unit_plane(x_division: usize, z_division: usize, color: u32) -> Object3D
     let mut vertices: Vec<Vector4<f32>> = vec![];
     let mut colors: Vec<u32> = vec![];
     let mut triangles: Vec<(usize, usize, usize)> = vec![];
+    let mut uvs: Vec<Vector2<f32>> = vec![];
 
     for x in 0..x_division {
         for z in 0..z_division {
+            // the centers of the pixels of an x_division by z_division map, the way textures and
+            // sample_height are sampled. u follows x and v follows z
+            uvs.push(Vector2::new(
+                (x as f32 + 0.5) / x_division as f32,
+                (z as f32 + 0.5) / z_division as f32,
+            ));
             let x = x as f32 - x_division as f32 / 2.0;
             let z = z as f32 - z_division as f32 / 2.0;
             vertices.push(Vector4::new(x, 0.0, z, 1.0));
@@ -102,14 +114,29 @@ pub fn unit_plane(x_division: usize, z_division: usize, color: u32) -> Object3D
             triangles.push((a, c, d));
         }
     }
-    Object3D::new(vertices, colors, vec![], triangles)
+    let mut plane = Object3D::new(vertices, colors, vec![], triangles);
+    plane.uvs = uvs;
+    plane
+}
+
+// position, uv and normal index of a face corner
+type FaceCorner = (usize, Option<usize>, Option<usize>);
+
+// a face corner "v", "v/vt", "v//vn" or "v/vt/vn", obj indices are one based
+fn parse_face_corner(corner: &str) -> FaceCorner {
+    let mut indices = corner.split('/');
+    let vertex: usize = indices.next().unwrap().parse().expect("Error, could not parse triangle");
+    let uv = indices.next().filter(|index| !index.is_empty()).map(|index| index.parse::<usize>().expect("Error, could not parse uv index"));
+    let normal = indices.next().filter(|index| !index.is_empty()).map(|index| index.parse::<usize>().expect("Error, could not parse normal index"));
+    (vertex - 1, uv.map(|i| i - 1), normal.map(|i| i - 1))
 }
 
 pub fn read_obj(path: &str) -> Object3D {
     let content = fs::read_to_string(path).expect("Something went wrong reading the file");
-    let mut vertices: Vec<Vector4<f32>> = vec![];
-    let mut triangles: Vec<(usize, usize, usize)> = vec![];
-    let mut colors: Vec<u32> = vec![];
+    let mut positions: Vec<Vector4<f32>> = vec![];
+    let mut file_uvs: Vec<Vector2<f32>> = vec![];
+    let mut file_normals: Vec<Vector3<f32>> = vec![];
+    let mut faces: Vec<Vec<FaceCorner>> = vec![];
 
     for line in content.lines(){
         let mut words = line.split_whitespace();
@@ -118,17 +145,63 @@ pub fn read_obj(path: &str) -> Object3D {
                 let x: f32 = words.next().unwrap().parse().expect("Error, could not parse vertex");
                 let y: f32 = words.next().unwrap().parse().expect("Error, could not parse vertex");
                 let z: f32 = words.next().unwrap().parse().expect("Error, could not parse vertex");
-                vertices.push(Vector4::new(x, y, z, 1.0));
-                colors.push(0xFF00EC);
+                positions.push(Vector4::new(x, y, z, 1.0));
+            },
+            Some("vt") => {
+                let u: f32 = words.next().unwrap().parse().expect("Error, could not parse uv");
+                let v: f32 = words.next().unwrap().parse().expect("Error, could not parse uv");
+                file_uvs.push(Vector2::new(u, v));
+            },
+            Some("vn") => {
+                let x: f32 = words.next().unwrap().parse().expect("Error, could not parse normal");
+                let y: f32 = words.next().unwrap().parse().expect("Error, could not parse normal");
+                let z: f32 = words.next().unwrap().parse().expect("Error, could not parse normal");
+                file_normals.push(Vector3::new(x, y, z));
             },
             Some("f") => {
-                let a: usize = words.next().unwrap().parse().expect("Error, could not parse triangle");
-                let b: usize = words.next().unwrap().parse().expect("Error, could not parse triangle");
-                let c: usize = words.next().unwrap().parse().expect("Error, could not parse triangle");
-                triangles.push((a-1, b-1, c-1));
+                faces.push(words.map(parse_face_corner).collect());
             },
             _ => {}
         }
     }
-    Object3D::new(vertices,colors, vec![], triangles)
-}
\ No newline at end of file
+
+    // without uvs and normals the vertices can be used as they are, otherwise every distinct
+    // combination of position, uv and normal becomes its own vertex
+    let has_attributes = faces.iter().flatten().any(|&(_, uv, normal)| uv.is_some() || normal.is_some());
+    let mut vertices: Vec<Vector4<f32>> = vec![];
+    let mut uvs: Vec<Vector2<f32>> = vec![];
+    let mut normals: Vec<Vector3<f32>> = vec![];
+    let mut corner_indices: HashMap<FaceCorner, usize> = HashMap::new();
+    if !has_attributes {
+        vertices = positions.clone();
+    }
+
+    let mut triangles: Vec<(usize, usize, usize)> = vec![];
+    for face in faces.iter() {
+        let indices: Vec<usize> = face.iter().map(|&corner| {
+            if !has_attributes {
+                return corner.0;
+            }
+            *corner_indices.entry(corner).or_insert_with(|| {
+                vertices.push(positions[corner.0]);
+                uvs.push(corner.1.map_or(Vector2::zeros(), |i| file_uvs[i]));
+                normals.push(corner.2.map_or(Vector3::zeros(), |i| file_normals[i]));
+                vertices.len() - 1
+            })
+        }).collect();
+        // polygons are triangulated as a fan, which is fine for the convex quads blender exports
+        for i in 1..indices.len().saturating_sub(1) {
+            triangles.push((indices[0], indices[i], indices[i + 1]));
+        }
+    }
+
+    let colors = vec![0xFF00EC; vertices.len()];
+    let mut object = Object3D::new(vertices, colors, vec![], triangles);
+    if faces.iter().flatten().any(|&(_, uv, _)| uv.is_some()) {
+        object.uvs = uvs;
+    }
+    if has_attributes && faces.iter().flatten().all(|&(_, _, normal)| normal.is_some()) {
+        object.normals = normals;
+    }
+    object
+}
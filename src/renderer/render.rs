@@ -1,24 +1,119 @@
-use nalgebra::{Matrix4, Vector4, Vector3, Translation3, Point3, Unit};
+use std::sync::OnceLock;
+use nalgebra::{Matrix3, Matrix4, Vector2, Vector4, Vector3, Translation3, Point3, Unit};
 use super::math::{remap, lerp_color};
+use super::bounds::{Bounds, Frustum};
+use super::bvh::Ray;
+use super::framebuffer::Framebuffer;
+use super::shader::{FragmentInput, Shader, Uniforms, Varying, VertexInput};
 
 pub struct Object3D {
     pub vertices: Vec<Vector4<f32>>,
     pub colors: Vec<u32>,
     pub edges: Vec<(usize, usize)>,
     pub triangles: Vec<(usize, usize, usize)>,
+    // optional per vertex attributes, empty if the source had none
+    pub normals: Vec<Vector3<f32>>,
+    pub uvs: Vec<Vector2<f32>>,
+    // xyz is the tangent and w the handedness, the bitangent is w * cross(normal, tangent)
+    pub tangents: Vec<Vector4<f32>>,
+    bounds: OnceLock<Bounds>,
 }
 
 impl Object3D {
     pub fn new(vertices: Vec<Vector4<f32>>, colors: Vec<u32>, edges: Vec<(usize, usize)>, triangles: Vec<(usize, usize, usize)>) -> Object3D {
-        Object3D { vertices, colors, edges, triangles }
+        Object3D { vertices, colors, edges, triangles, normals: vec![], uvs: vec![], tangents: vec![], bounds: OnceLock::new() }
+    }
+
+    // object space bounds, computed on first use and cached until the vertices change
+    pub fn bounds(&self) -> &Bounds {
+        self.bounds.get_or_init(|| Bounds::from_vertices(&self.vertices))
+    }
+
+    // has to be called by anything that moves vertices, otherwise culling works on stale bounds
+    pub fn invalidate_bounds(&mut self) {
+        self.bounds.take();
+    }
+
+    // the stored normals if there are any, otherwise smooth normals where every triangle
+    // contributes weighted by its area
+    pub fn vertex_normals(&self) -> Vec<Vector3<f32>> {
+        if self.normals.len() == self.vertices.len() {
+            return self.normals.clone();
+        }
+        let mut normals = vec![Vector3::zeros(); self.vertices.len()];
+        for &(a, b, c) in self.triangles.iter() {
+            let (pa, pb, pc) = (self.vertices[a].xyz(), self.vertices[b].xyz(), self.vertices[c].xyz());
+            let normal = (pb - pa).cross(&(pc - pa));
+            normals[a] += normal;
+            normals[b] += normal;
+            normals[c] += normal;
+        }
+        normals.iter().map(|normal| normal.try_normalize(1e-12).unwrap_or_else(Vector3::y)).collect()
+    }
+
+    // the stored tangents if there are any, otherwise tangents following the uv layout with the
+    // conventions of MikkTSpace: face tangents are projected onto the vertex normal and weighted by
+    // the corner angle, the handedness comes from the accumulated bitangent. empty without uvs
+    pub fn vertex_tangents(&self) -> Vec<Vector4<f32>> {
+        if self.tangents.len() == self.vertices.len() {
+            return self.tangents.clone();
+        }
+        if self.uvs.len() != self.vertices.len() {
+            return vec![];
+        }
+        let normals = self.vertex_normals();
+        let mut tangents = vec![Vector3::zeros(); self.vertices.len()];
+        let mut bitangents = vec![Vector3::zeros(); self.vertices.len()];
+        for &(a, b, c) in self.triangles.iter() {
+            let (pa, pb, pc) = (self.vertices[a].xyz(), self.vertices[b].xyz(), self.vertices[c].xyz());
+            let (e1, e2) = (pb - pa, pc - pa);
+            let (d1, d2) = (self.uvs[b] - self.uvs[a], self.uvs[c] - self.uvs[a]);
+            let determinant = d1.x * d2.y - d2.x * d1.y;
+            if determinant.abs() < 1e-12 {
+                continue;
+            }
+            let tangent = (e1 * d2.y - e2 * d1.y) / determinant;
+            let bitangent = (e2 * d1.x - e1 * d2.x) / determinant;
+            for (corner, previous, next) in [(a, c, b), (b, a, c), (c, b, a)] {
+                let position = self.vertices[corner].xyz();
+                let to_previous = (self.vertices[previous].xyz() - position).try_normalize(1e-12);
+                let to_next = (self.vertices[next].xyz() - position).try_normalize(1e-12);
+                let (Some(to_previous), Some(to_next)) = (to_previous, to_next) else {
+                    continue;
+                };
+                let angle = to_previous.dot(&to_next).clamp(-1.0, 1.0).acos();
+                let normal = normals[corner];
+                if let Some(projected) = (tangent - normal * normal.dot(&tangent)).try_normalize(1e-12) {
+                    tangents[corner] += projected * angle;
+                }
+                if let Some(projected) = (bitangent - normal * normal.dot(&bitangent)).try_normalize(1e-12) {
+                    bitangents[corner] += projected * angle;
+                }
+            }
+        }
+        tangents.iter().zip(bitangents.iter()).zip(normals.iter()).map(|((tangent, bitangent), normal)| {
+            let tangent = (tangent - normal * normal.dot(tangent)).try_normalize(1e-12)
+                .unwrap_or_else(|| normal.cross(&Vector3::z()).try_normalize(1e-12).unwrap_or_else(Vector3::x));
+            let handedness = if normal.cross(&tangent).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+            Vector4::new(tangent.x, tangent.y, tangent.z, handedness)
+        }).collect()
     }
 }
 
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderStats {
+    pub drawn: usize,
+    pub culled: usize,
+}
 
-pub struct Light {
-    pub position: Vector4<f32>,
-    pub color: u32,
-    pub intensity: f32,
+impl RenderStats {
+    pub fn record(&mut self, drawn: bool) {
+        if drawn {
+            self.drawn += 1;
+        } else {
+            self.culled += 1;
+        }
+    }
 }
 
 pub struct Camera {
@@ -35,23 +130,55 @@ impl Camera {
         let fov_rad = self.fov.to_radians();
         let f = 1.0 / (fov_rad / 2.0).tan();
 
+        // left handed like the view matrix, so w is the view depth and -w <= z <= w inside the frustum
         Matrix4::new(
             f / aspect_ratio, 0.0, 0.0, 0.0,
             0.0, f, 0.0, 0.0,
-            0.0, 0.0, (self.far + self.near) / (self.near - self.far), -1.0,
-            0.0, 0.0, (2.0 * self.far * self.near) / (self.near - self.far), 0.0,
+            0.0, 0.0, (self.far + self.near) / (self.far - self.near), (2.0 * self.far * self.near) / (self.near - self.far),
+            0.0, 0.0, 1.0, 0.0,
         )
     }
 
-    pub fn get_view_matrix(&self) -> Matrix4<f32> {
+    // half_width and half_height are the extents of the view volume in world units
+    pub fn get_orthographic_matrix(&self, half_width: f32, half_height: f32) -> Matrix4<f32> {
+        Matrix4::new(
+            1.0 / half_width, 0.0, 0.0, 0.0,
+            0.0, 1.0 / half_height, 0.0, 0.0,
+            0.0, 0.0, 2.0 / (self.far - self.near), -(self.far + self.near) / (self.far - self.near),
+            0.0, 0.0, 0.0, 1.0,
+        )
+    }
+
+    pub fn get_frustum(&self, aspect_ratio: f32) -> Frustum {
+        Frustum::from_matrix(&(self.get_projection_matrix(aspect_ratio) * self.get_view_matrix()))
+    }
+
+    // right, up and forward vectors of the camera in world space
+    pub fn get_basis(&self) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
         let position3 = Vector3::new(self.position.x, self.position.y, self.position.z);
         let look_at3 = Vector3::new(self.look_at.x, self.look_at.y, self.look_at.z);
         let up3 = Vector3::new(self.up.x, self.up.y, self.up.z);
 
-        // Calculate the new basis vectors
         let f = (look_at3 - position3).normalize();
         let r = up3.cross(&f).normalize();
         let u = f.cross(&r).normalize();
+        (r, u, f)
+    }
+
+    // world space ray through a (sub)pixel position, matching the projection of draw_object
+    pub fn get_ray(&self, dimensions: (usize, usize), x: f32, y: f32) -> Ray {
+        let (r, u, f) = self.get_basis();
+        let aspect_ratio = dimensions.0 as f32 / dimensions.1 as f32;
+        let tan_half_fov = (self.fov.to_radians() / 2.0).tan();
+        let ndc_x = 2.0 * x / dimensions.0 as f32 - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / dimensions.1 as f32;
+        let direction = f + r * (ndc_x * tan_half_fov * aspect_ratio) + u * (ndc_y * tan_half_fov);
+        Ray::new(self.position.xyz(), direction.normalize())
+    }
+
+    pub fn get_view_matrix(&self) -> Matrix4<f32> {
+        let position3 = Vector3::new(self.position.x, self.position.y, self.position.z);
+        let (r, u, f) = self.get_basis();
 
         let p = -position3.dot(&r);
         let q = -position3.dot(&u);
@@ -82,6 +209,122 @@ impl Camera {
 }
 
 
+pub fn get_model_matrix(position: Vector4<f32>, rotation: Vector4<f32>, scale: Vector4<f32>) -> Matrix4<f32> {
+    let rotation_matrix = Matrix4::from_euler_angles(
+        rotation.x,
+        rotation.y,
+        rotation.z,
+    );
+    let position_point = Point3::new(position.x, position.y, position.z);
+    let scaling_vec = Vector3::new(scale.x, scale.y, scale.z);
+    let scaling_matrix = Matrix4::new_nonuniform_scaling_wrt_point(&scaling_vec, &position_point);
+    let translation_matrix = Translation3::new(position.x, position.y, position.z).to_homogeneous();
+    translation_matrix * rotation_matrix * scaling_matrix
+}
+
+// inverse transpose of the linear part, keeps normals perpendicular under non uniform scaling.
+// the translation has to stay out, Matrix4::transform_vector would divide by it after transposing
+// view depth of a depth buffer value, the inverse of the depth mapping in get_projection_matrix.
+// None for the cleared depth of the pixels nothing was drawn to
+pub fn view_depth(depth: f32, near: f32, far: f32) -> Option<f32> {
+    if depth > 1.0 {
+        return None;
+    }
+    let a = (far + near) / (far - near);
+    let b = (2.0 * far * near) / (near - far);
+    Some(b / (depth - a))
+}
+
+pub fn get_normal_matrix(transform_matrix: &Matrix4<f32>) -> Matrix3<f32> {
+    transform_matrix.fixed_view::<3, 3>(0, 0).into_owned().try_inverse().unwrap_or_else(Matrix3::identity).transpose()
+}
+
+pub fn is_object_visible(object: &Object3D, frustum: &Frustum, transform_matrix: &Matrix4<f32>) -> bool {
+    let bounds = object.bounds();
+    frustum.intersects_sphere(&bounds.sphere.transformed(transform_matrix))
+        && frustum.intersects_aabb(&bounds.aabb.transformed(transform_matrix))
+}
+
+// screen space x and y with the ndc depth as z, None for vertices behind the camera
+pub fn to_screen_depth(vertex: &Vector4<f32>, dimensions: (usize, usize)) -> Option<Vector3<f32>> {
+    if vertex.w <= 0.0 {
+        return None;
+    }
+    let perspective_vertex = vertex / vertex.w;
+    Some(Vector3::new(
+        (perspective_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0,
+        (-perspective_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0,
+        perspective_vertex.z,
+    ))
+}
+
+fn edge_function(a: &Vector3<f32>, b: &Vector3<f32>, x: f32, y: f32) -> f32 {
+    (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x)
+}
+
+// calls the fragment callback for every pixel center covered by the triangle with the screen
+// space barycentric weights of the three vertices. both windings are filled
+pub fn rasterize_triangle<F: FnMut(usize, usize, [f32; 3])>(dimensions: (usize, usize), p0: &Vector3<f32>, p1: &Vector3<f32>, p2: &Vector3<f32>, mut fragment: F) {
+    let area = edge_function(p0, p1, p2.x, p2.y);
+    if area.abs() < 1e-12 {
+        return;
+    }
+    let min_x = p0.x.min(p1.x).min(p2.x).floor().max(0.0) as usize;
+    let min_y = p0.y.min(p1.y).min(p2.y).floor().max(0.0) as usize;
+    let max_x = (p0.x.max(p1.x).max(p2.x).ceil().max(0.0) as usize).min(dimensions.0);
+    let max_y = (p0.y.max(p1.y).max(p2.y).ceil().max(0.0) as usize).min(dimensions.1);
+
+    for y in min_y..max_y {
+        let py = y as f32 + 0.5;
+        for x in min_x..max_x {
+            let px = x as f32 + 0.5;
+            let w0 = edge_function(p1, p2, px, py) / area;
+            let w1 = edge_function(p2, p0, px, py) / area;
+            let w2 = edge_function(p0, p1, px, py) / area;
+            if w0 >= 0.0 && w1 >= 0.0 && w2 >= 0.0 {
+                fragment(x, y, [w0, w1, w2]);
+            }
+        }
+    }
+}
+
+// filled depth only pass, e.g. for shadow maps. smaller values are closer, the buffer has to be cleared to f32::MAX
+pub fn draw_object_depth(depth_buffer: &mut [f32], object: &Object3D, dimensions: (usize, usize), view_projection: &Matrix4<f32>, transform_matrix: &Matrix4<f32>) -> bool {
+    if !is_object_visible(object, &Frustum::from_matrix(view_projection), transform_matrix) {
+        return false;
+    }
+    let model_view_projection = view_projection * transform_matrix;
+    let screen_vertices: Vec<_> = object.vertices.iter()
+        .map(|vertex| to_screen_depth(&(model_view_projection * vertex), dimensions))
+        .collect();
+
+    for &(a, b, c) in object.triangles.iter() {
+        if let (Some(p0), Some(p1), Some(p2)) = (screen_vertices[a], screen_vertices[b], screen_vertices[c]) {
+            // depth over w is affine in screen space, so it can be interpolated directly
+            rasterize_triangle(dimensions, &p0, &p1, &p2, |x, y, [w0, w1, w2]| {
+                let depth = p0.z * w0 + p1.z * w1 + p2.z * w2;
+                let index = y * dimensions.0 + x;
+                if depth < depth_buffer[index] {
+                    depth_buffer[index] = depth;
+                }
+            });
+        }
+    }
+    true
+}
+
+fn to_screen(vertex: &Vector4<f32>, dimensions: (usize, usize)) -> Option<(usize, usize)> {
+    // everything behind the camera would be mirrored onto the screen
+    if vertex.w <= 0.0 {
+        return None;
+    }
+    let perspective_vertex = vertex / vertex.w;
+    let x = ((perspective_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
+    let y = ((-perspective_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
+    Some((x, y))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn draw_line(buffer: &mut [u32], dimensions: (usize, usize), x0: usize, y0: usize, x1: usize, y1: usize, c0: u32, c1: u32) {
     let mut x0 = x0 as isize;
     let mut y0 = y0 as isize;
@@ -113,77 +356,286 @@ fn draw_line(buffer: &mut [u32], dimensions: (usize, usize), x0: usize, y0: usiz
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn draw_triangle(buffer: &mut [u32], dimensions: (usize, usize), x0: usize, y0: usize, x1: usize, y1: usize, x2: usize, y2: usize, c0: u32, c1: u32, c2: u32) {
     draw_line(buffer, dimensions, x0, y0, x1, y1, c0, c1);
     draw_line(buffer, dimensions, x1, y1, x2, y2, c1, c2);
     draw_line(buffer, dimensions, x2, y2, x0, y0, c2, c0);
 }
 
-pub fn draw_object(buffer: &mut [u32], object: &Object3D, dimensions: (usize, usize), camera: &Camera, position: Vector4<f32>, rotation: Vector4<f32>, scale: Vector4<f32>, background_color: Option<u32>) {
+// returns false if the object was culled by the camera frustum and nothing was drawn
+#[allow(clippy::too_many_arguments)]
+pub fn draw_object(buffer: &mut [u32], object: &Object3D, dimensions: (usize, usize), camera: &Camera, position: Vector4<f32>, rotation: Vector4<f32>, scale: Vector4<f32>, background_color: Option<u32>) -> bool {
     let aspect_ratio = dimensions.0 as f32 / dimensions.1 as f32;
+
+    if let Some(bg_color) = background_color {
+        for pixel in buffer.iter_mut() {
+            *pixel = bg_color;
+        }
+    }
+
+    let transform_matrix = get_model_matrix(position, rotation, scale);
+    if !is_object_visible(object, &camera.get_frustum(aspect_ratio), &transform_matrix) {
+        return false;
+    }
+
     let projection_matrix = camera.get_projection_matrix(aspect_ratio);
     let view_matrix = camera.get_view_matrix();
 
-    let rotation_matrix = Matrix4::from_euler_angles(
-        rotation.x,
-        rotation.y,
-        rotation.z,
-    );
-    let position_point = Point3::new(position.x, position.y, position.z);
-    let scaling_vec = Vector3::new(scale.x, scale.y, scale.z);
-    let scaling_matrix = Matrix4::new_nonuniform_scaling_wrt_point(&scaling_vec, &position_point);
-    let translation_matrix = Translation3::new(position.x, position.y, position.z).to_homogeneous();
-    let transform_matrix = translation_matrix * rotation_matrix * scaling_matrix;
-
-    let transformed_vertices: Vec<_> = object.vertices.iter()
+    let screen_vertices: Vec<_> = object.vertices.iter()
         .map(|vertex| transform_matrix * vertex)
         .map(|vertex| view_matrix * vertex)
         .map(|vertex| projection_matrix * vertex)
+        .map(|vertex| to_screen(&vertex, dimensions))
         .collect();
 
-    if let Some(bg_color) = background_color {
-        for pixel in buffer.iter_mut() {
-            *pixel = bg_color;
+    for (i, screen_vertex) in screen_vertices.iter().enumerate() {
+        if let Some((x, y)) = *screen_vertex {
+            if x < dimensions.0 && y < dimensions.1 {
+                buffer[y * dimensions.0 + x] = object.colors[i];
+            }
+        }
+    }
+
+    for &(start, end) in object.edges.iter() {
+        if let (Some((x0, y0)), Some((x1, y1))) = (screen_vertices[start], screen_vertices[end]) {
+            draw_line(buffer, dimensions, x0, y0, x1, y1, object.colors[start], object.colors[end]);
+        }
+    }
+
+    for &(a, b, c) in object.triangles.iter() {
+        if let (Some((x0, y0)), Some((x1, y1)), Some((x2, y2))) = (screen_vertices[a], screen_vertices[b], screen_vertices[c]) {
+            draw_triangle(buffer, dimensions, x0, y0, x1, y1, x2, y2, object.colors[a], object.colors[b], object.colors[c]);
+        }
+    }
+    true
+}
+
+// clips a triangle against the near plane (z >= -w), returns 0, 3 or 4 vertices in order
+fn clip_near<V: Varying>(triangle: [&(Vector4<f32>, V); 3]) -> Vec<(Vector4<f32>, V)> {
+    let distance = |vertex: &Vector4<f32>| vertex.z + vertex.w;
+    let mut polygon = Vec::with_capacity(4);
+    for i in 0..3 {
+        let current = triangle[i];
+        let next = triangle[(i + 1) % 3];
+        let (d0, d1) = (distance(&current.0), distance(&next.0));
+        if d0 >= 0.0 {
+            polygon.push(*current);
+        }
+        if (d0 >= 0.0) != (d1 >= 0.0) {
+            let t = d0 / (d0 - d1);
+            let position = current.0.lerp(&next.0, t);
+            let varyings = V::weighted_sum([&current.1, &next.1, &current.1], [1.0 - t, t, 0.0]);
+            polygon.push((position, varyings));
+        }
+    }
+    polygon
+}
+
+// depth tested rasterization of one triangle. write gets the pixel index, the fragment and its
+// varyings and returns whether it wrote something, only then the depth is written too
+fn rasterize_fragments<S, W>(depth_buffer: &mut [f32], dimensions: (usize, usize), shader: &S, uniforms: &Uniforms, primitive: usize, triangle: [&(Vector4<f32>, S::Varyings); 3], write: &mut W)
+where
+    S: Shader,
+    W: FnMut(&Uniforms, usize, &FragmentInput, &S::Varyings) -> bool,
+{
+    let (Some(p0), Some(p1), Some(p2)) = (
+        to_screen_depth(&triangle[0].0, dimensions),
+        to_screen_depth(&triangle[1].0, dimensions),
+        to_screen_depth(&triangle[2].0, dimensions),
+    ) else {
+        return;
+    };
+    let area = edge_function(&p0, &p1, p2.x, p2.y);
+    let front_facing = area > 0.0;
+    let inverse_w = [1.0 / triangle[0].0.w, 1.0 / triangle[1].0.w, 1.0 / triangle[2].0.w];
+    // screen space weights are not linear in world space, so they are corrected by 1 / w
+    let perspective_correct = |[w0, w1, w2]: [f32; 3]| {
+        let corrected = [w0 * inverse_w[0], w1 * inverse_w[1], w2 * inverse_w[2]];
+        let sum = corrected[0] + corrected[1] + corrected[2];
+        [corrected[0] / sum, corrected[1] / sum, corrected[2] / sum]
+    };
+    let interpolate = |weights: [f32; 3]| S::Varyings::weighted_sum([&triangle[0].1, &triangle[1].1, &triangle[2].1], perspective_correct(weights));
+    // change of the screen space weights per pixel, the weights are affine on screen
+    let weights_dx = [(p1.y - p2.y) / area, (p2.y - p0.y) / area, (p0.y - p1.y) / area];
+    let weights_dy = [(p2.x - p1.x) / area, (p0.x - p2.x) / area, (p1.x - p0.x) / area];
+
+    rasterize_triangle(dimensions, &p0, &p1, &p2, |x, y, weights| {
+        let depth = p0.z * weights[0] + p1.z * weights[1] + p2.z * weights[2];
+        let index = y * dimensions.0 + x;
+        if !(-1.0..=1.0).contains(&depth) || depth >= depth_buffer[index] {
+            return;
+        }
+        let varyings = interpolate(weights);
+        let (mut uv_dx, mut uv_dy) = (Vector2::zeros(), Vector2::zeros());
+        if let Some(uv) = shader.texture_coordinates(&varyings) {
+            let step = |gradient: [f32; 3]| [weights[0] + gradient[0], weights[1] + gradient[1], weights[2] + gradient[2]];
+            uv_dx = shader.texture_coordinates(&interpolate(step(weights_dx))).map_or(Vector2::zeros(), |next| next - uv);
+            uv_dy = shader.texture_coordinates(&interpolate(step(weights_dy))).map_or(Vector2::zeros(), |next| next - uv);
+        }
+        let input = FragmentInput { x, y, depth, primitive, front_facing, uv_dx, uv_dy };
+        if write(uniforms, index, &input, &varyings) {
+            depth_buffer[index] = depth;
         }
+    });
+}
+
+// runs the vertex stage of the shader and rasterizes the object into the depth buffer, the
+// fragments go to write to end up in whatever render targets the caller has. returns false if
+// the object was culled by the camera frustum
+#[allow(clippy::too_many_arguments)]
+pub fn draw_object_fragments<S, W>(depth_buffer: &mut [f32], dimensions: (usize, usize), object: &Object3D, camera: &Camera, transform_matrix: &Matrix4<f32>, shader: &S, mut write: W) -> bool
+where
+    S: Shader,
+    W: FnMut(&Uniforms, usize, &FragmentInput, &S::Varyings) -> bool,
+{
+    let aspect_ratio = dimensions.0 as f32 / dimensions.1 as f32;
+    if !is_object_visible(object, &camera.get_frustum(aspect_ratio), transform_matrix) {
+        return false;
     }
 
-    for i in 0..transformed_vertices.iter().len() {
-        let vertex = transformed_vertices[i];
-        let perspective_vertex = vertex / vertex.w;
-        let x = ((perspective_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
-        let y = ((-perspective_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
-        if x < dimensions.0 && y < dimensions.1 {
-            buffer[y * dimensions.0 + x] = object.colors[i];
+    let uniforms = Uniforms::new(camera, transform_matrix, aspect_ratio);
+    let normals = object.vertex_normals();
+    let tangents = object.vertex_tangents();
+    let processed: Vec<(Vector4<f32>, S::Varyings)> = object.vertices.iter().enumerate()
+        .map(|(index, position)| {
+            let input = VertexInput {
+                index,
+                position: *position,
+                normal: normals[index],
+                tangent: tangents.get(index).copied().unwrap_or_else(Vector4::zeros),
+                uv: object.uvs.get(index).copied().unwrap_or_else(Vector2::zeros),
+                color: object.colors[index],
+            };
+            shader.vertex(&uniforms, &input)
+        })
+        .collect();
+
+    for (primitive, &(a, b, c)) in object.triangles.iter().enumerate() {
+        let polygon = clip_near([&processed[a], &processed[b], &processed[c]]);
+        for i in 1..polygon.len().saturating_sub(1) {
+            rasterize_fragments(depth_buffer, dimensions, shader, &uniforms, primitive, [&polygon[0], &polygon[i], &polygon[i + 1]], &mut write);
         }
     }
+    true
+}
+
+// filled and depth tested rasterization, the shader decides the position and color of everything.
+// returns false if the object was culled by the camera frustum
+pub fn draw_object_shaded<S: Shader>(framebuffer: &mut Framebuffer, object: &Object3D, camera: &Camera, transform_matrix: &Matrix4<f32>, shader: &S) -> bool {
+    let Framebuffer { dimensions, color, depth, normals } = framebuffer;
+    draw_object_fragments(depth, *dimensions, object, camera, transform_matrix, shader, |uniforms, index, input, varyings| {
+        let Some(fragment) = shader.fragment(uniforms, input, varyings) else {
+            return false;
+        };
+        // blending happens on linear values, so it matches how light adds up
+        let alpha = fragment.w.clamp(0.0, 1.0);
+        let source = Vector4::new(fragment.x, fragment.y, fragment.z, 1.0);
+        color[index] = source * alpha + color[index] * (1.0 - alpha);
+        if let Some(normal) = shader.surface_normal(varyings) {
+            normals[index] = normal.try_normalize(1e-6).unwrap_or_else(Vector3::zeros);
+        }
+        true
+    })
+}
 
-    for i in 0..object.edges.iter().len() {
-        let &(start, end) = &object.edges[i];
-        let start_vertex = transformed_vertices[start] / transformed_vertices[start].w;
-        let end_vertex = transformed_vertices[end] / transformed_vertices[end].w;
-        let x0 = ((start_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
-        let y0 = ((-start_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::reader::{unit_cube, unit_plane, unit_sphere};
+
+    fn camera() -> Camera {
+        Camera {
+            fov: 90.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(0.0, 0.0, -5.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
 
-        let x1 = ((end_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
-        let y1 = ((-end_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
-        draw_line(buffer, dimensions, x0, y0, x1, y1, object.colors[start], object.colors[end]);
+    #[test]
+    fn projection_maps_near_and_far_to_the_ndc_depth_range() {
+        let camera = camera();
+        let view_projection = camera.get_projection_matrix(1.0) * camera.get_view_matrix();
+        let depth = |z: f32| {
+            let clip = view_projection * Vector4::new(0.0, 0.0, z, 1.0);
+            (clip.z / clip.w, clip.w)
+        };
+        let (near, w) = depth(-5.0 + camera.near);
+        assert!((near + 1.0).abs() < 1e-4 && (w - camera.near).abs() < 1e-5);
+        assert!((depth(-5.0 + camera.far).0 - 1.0).abs() < 1e-4);
     }
 
-    for i in 0..object.triangles.iter().len() {
-        let &(a, b, c) = &object.triangles[i];
-        let a_vertex = transformed_vertices[a] / transformed_vertices[a].w;
-        let b_vertex = transformed_vertices[b] / transformed_vertices[b].w;
-        let c_vertex = transformed_vertices[c] / transformed_vertices[c].w;
+    #[test]
+    fn view_depth_inverts_the_projection() {
+        let camera = camera();
+        let projection = camera.get_projection_matrix(1.5);
+        for distance in [camera.near, 0.5, 3.0, 40.0, camera.far] {
+            let clip = projection * Vector4::new(0.2, -0.1, distance, 1.0);
+            let depth = view_depth(clip.z / clip.w, camera.near, camera.far).unwrap();
+            assert!((depth - distance).abs() < distance * 1e-4);
+        }
+        assert_eq!(view_depth(f32::MAX, camera.near, camera.far), None);
+    }
 
-        let x0 = ((a_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
-        let y0 = ((-a_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
+    #[test]
+    fn objects_behind_the_camera_are_culled() {
+        let camera = camera();
+        let frustum = camera.get_frustum(1.0);
+        let cube = unit_cube(0xFFFFFF);
+        assert!(is_object_visible(&cube, &frustum, &Matrix4::identity()));
+        assert!(!is_object_visible(&cube, &frustum, &Matrix4::new_translation(&Vector3::new(0.0, 0.0, -10.0))));
+    }
 
-        let x1 = ((b_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
-        let y1 = ((-b_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
+    #[test]
+    fn moved_vertices_need_invalidated_bounds() {
+        let frustum = camera().get_frustum(1.0);
+        let mut cube = unit_cube(0xFFFFFF);
+        assert!(is_object_visible(&cube, &frustum, &Matrix4::identity()));
+        cube.vertices.iter_mut().for_each(|vertex| vertex.z -= 10.0);
+        // the cached bounds still lie in front of the camera
+        assert!(is_object_visible(&cube, &frustum, &Matrix4::identity()));
+        cube.invalidate_bounds();
+        assert!(!is_object_visible(&cube, &frustum, &Matrix4::identity()));
+    }
 
-        let x2 = ((c_vertex.x + 1.0) * (dimensions.0 as f32) / 2.0) as usize;
-        let y2 = ((-c_vertex.y + 1.0) * (dimensions.1 as f32) / 2.0) as usize;
+    #[test]
+    fn plane_tangents_follow_u_and_the_bitangent_follows_v() {
+        let plane = unit_plane(5, 4, 0xFFFFFF);
+        let normals = plane.vertex_normals();
+        let tangents = plane.vertex_tangents();
+        assert_eq!(tangents.len(), plane.vertices.len());
+        // u grows with x and v with z
+        for (normal, tangent) in normals.iter().zip(tangents.iter()) {
+            assert!((normal - Vector3::y()).norm() < 1e-5);
+            assert!((tangent.xyz() - Vector3::x()).norm() < 1e-5);
+            assert!((normal.cross(&tangent.xyz()) * tangent.w - Vector3::z()).norm() < 1e-5);
+        }
+        // mirroring the uvs flips the handedness, not the tangent frame
+        let mut mirrored = unit_plane(5, 4, 0xFFFFFF);
+        mirrored.uvs.iter_mut().for_each(|uv| uv.y = 1.0 - uv.y);
+        for (tangent, flipped) in tangents.iter().zip(mirrored.vertex_tangents().iter()) {
+            assert!((tangent.xyz() - flipped.xyz()).norm() < 1e-5);
+            assert_eq!(flipped.w, -tangent.w);
+        }
+    }
 
-        draw_triangle(buffer, dimensions, x0, y0, x1, y1, x2, y2, object.colors[a], object.colors[b], object.colors[c]);
+    #[test]
+    fn sphere_tangents_are_unit_and_orthogonal_to_the_normals() {
+        let sphere = unit_sphere(0xFFFFFF);
+        let normals = sphere.vertex_normals();
+        for (normal, tangent) in normals.iter().zip(sphere.vertex_tangents().iter()) {
+            assert!((tangent.xyz().norm() - 1.0).abs() < 1e-4);
+            assert!(normal.dot(&tangent.xyz()).abs() < 1e-4);
+            assert!(tangent.w == 1.0 || tangent.w == -1.0);
+        }
+        // stored tangents win, meshes without uvs have none
+        let mut cube = unit_cube(0xFFFFFF);
+        cube.uvs.clear();
+        assert!(cube.vertex_tangents().is_empty());
+        cube.tangents = vec![Vector4::new(0.0, 0.0, 1.0, -1.0); cube.vertices.len()];
+        assert_eq!(cube.vertex_tangents(), cube.tangents);
     }
-}
\ No newline at end of file
+}
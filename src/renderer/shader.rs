@@ -0,0 +1,555 @@
+use std::f32::consts::PI;
+use nalgebra::{Matrix3, Matrix4, Vector2, Vector3, Vector4};
+
+use super::environment::{environment_brdf, ImageBasedLight};
+use super::light::Light;
+use super::material::{base_reflectance, cook_torrance, fresnel_schlick, MaterialSet, SurfaceParameters};
+use super::math::{srgb_color_to_linear, srgb_to_linear};
+use super::raytracer::reflect;
+use super::render::{get_normal_matrix, Camera, Object3D};
+use super::shadow::ShadowCaster;
+use super::texture::{Texture, TextureFilter};
+
+// values handed from the vertex to the fragment stage, interpolated across the triangle
+pub trait Varying: Copy + Send + Sync {
+    fn weighted_sum(values: [&Self; 3], weights: [f32; 3]) -> Self;
+}
+
+impl Varying for () {
+    fn weighted_sum(_values: [&Self; 3], _weights: [f32; 3]) -> Self {}
+}
+
+macro_rules! impl_linear_varying {
+    ($($t:ty),*) => {
+        $(impl Varying for $t {
+            fn weighted_sum(values: [&Self; 3], weights: [f32; 3]) -> Self {
+                *values[0] * weights[0] + *values[1] * weights[1] + *values[2] * weights[2]
+            }
+        })*
+    };
+}
+
+impl_linear_varying!(f32, Vector2<f32>, Vector3<f32>, Vector4<f32>);
+
+macro_rules! impl_tuple_varying {
+    ($(($($name:ident : $index:tt),*)),*) => {
+        $(impl<$($name: Varying),*> Varying for ($($name,)*) {
+            fn weighted_sum(values: [&Self; 3], weights: [f32; 3]) -> Self {
+                ($($name::weighted_sum([&values[0].$index, &values[1].$index, &values[2].$index], weights),)*)
+            }
+        })*
+    };
+}
+
+impl_tuple_varying!((A: 0, B: 1), (A: 0, B: 1, C: 2), (A: 0, B: 1, C: 2, D: 3), (A: 0, B: 1, C: 2, D: 3, E: 4));
+
+// per draw call values every shader gets, shader specific uniforms are fields of the shader itself
+pub struct Uniforms {
+    pub model: Matrix4<f32>,
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    pub model_view_projection: Matrix4<f32>,
+    // inverse transpose of the model matrix
+    pub normal_matrix: Matrix3<f32>,
+    pub camera_position: Vector3<f32>,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Uniforms {
+    pub fn new(camera: &Camera, transform_matrix: &Matrix4<f32>, aspect_ratio: f32) -> Uniforms {
+        let view = camera.get_view_matrix();
+        let projection = camera.get_projection_matrix(aspect_ratio);
+        Uniforms {
+            model: *transform_matrix,
+            view,
+            projection,
+            model_view_projection: projection * view * transform_matrix,
+            normal_matrix: get_normal_matrix(transform_matrix),
+            camera_position: camera.position.xyz(),
+            near: camera.near,
+            far: camera.far,
+        }
+    }
+
+    pub fn world_position(&self, position: &Vector4<f32>) -> Vector3<f32> {
+        (self.model * position).xyz()
+    }
+
+    pub fn world_normal(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        (self.normal_matrix * normal).normalize()
+    }
+
+    // tangents follow the surface, so they use the model matrix itself. w keeps the handedness
+    pub fn world_tangent(&self, tangent: &Vector4<f32>) -> Vector4<f32> {
+        let direction = self.model.transform_vector(&tangent.xyz()).try_normalize(1e-12).unwrap_or_else(Vector3::zeros);
+        Vector4::new(direction.x, direction.y, direction.z, tangent.w)
+    }
+}
+
+pub struct VertexInput {
+    pub index: usize,
+    pub position: Vector4<f32>,
+    pub normal: Vector3<f32>,
+    // see Object3D::tangents, zero if the object has no uvs
+    pub tangent: Vector4<f32>,
+    pub uv: Vector2<f32>,
+    pub color: u32,
+}
+
+pub struct FragmentInput {
+    pub x: usize,
+    pub y: usize,
+    pub depth: f32,
+    // index into Object3D::triangles
+    pub primitive: usize,
+    // counter clockwise in a right handed sense, i.e. the face normal points towards the camera
+    pub front_facing: bool,
+    // change of the texture coordinates to the next pixel in x and y, zero if the shader has none
+    pub uv_dx: Vector2<f32>,
+    pub uv_dy: Vector2<f32>,
+}
+
+pub trait Shader: Sync {
+    type Varyings: Varying;
+
+    // returns the clip space position and the values to interpolate
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings);
+
+    // rgba in 0..1, None discards the fragment
+    fn fragment(&self, uniforms: &Uniforms, input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>>;
+
+    // the uv used for texture lookups, the rasterizer differentiates it for mip level selection
+    fn texture_coordinates(&self, _varyings: &Self::Varyings) -> Option<Vector2<f32>> {
+        None
+    }
+
+    // world space normal written to the framebuffer normals for post processing
+    fn surface_normal(&self, _varyings: &Self::Varyings) -> Option<Vector3<f32>> {
+        None
+    }
+}
+
+fn with_alpha(color: Vector3<f32>, alpha: f32) -> Vector4<f32> {
+    Vector4::new(color.x, color.y, color.z, alpha)
+}
+
+// blinn-phong over all lights, shadows are optional per light
+#[allow(clippy::too_many_arguments)]
+pub fn blinn_phong(lights: &[Light], shadows: &[Option<&dyn ShadowCaster>], position: &Vector3<f32>, normal: &Vector3<f32>, camera_position: &Vector3<f32>, albedo: &Vector3<f32>, specular: f32, shininess: f32) -> Vector3<f32> {
+    let view = (camera_position - position).normalize();
+    // lambert reflects albedo / pi of the irradiance, like the diffuse part of cook_torrance
+    let diffuse = albedo / PI;
+    let mut color = Vector3::zeros();
+    for (i, light) in lights.iter().enumerate() {
+        color += diffuse.component_mul(&light.ambient(normal));
+        let Some(sample) = light.sample(position) else {
+            continue;
+        };
+        let n_dot_l = normal.dot(&sample.direction);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+        let visibility = shadows.get(i).copied().flatten().map_or(1.0, |shadow| shadow.visibility(position, normal));
+        if visibility <= 0.0 {
+            continue;
+        }
+        let half = (sample.direction + view).normalize();
+        let highlight = specular * normal.dot(&half).max(0.0).powf(shininess);
+        color += (diffuse * n_dot_l + Vector3::repeat(highlight)).component_mul(&sample.irradiance) * visibility;
+    }
+    color
+}
+
+pub struct VertexColorShader;
+
+impl Shader for VertexColorShader {
+    type Varyings = Vector3<f32>;
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        (uniforms.model_view_projection * input.position, srgb_color_to_linear(input.color))
+    }
+
+    fn fragment(&self, _uniforms: &Uniforms, _input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        Some(with_alpha(*varyings, 1.0))
+    }
+}
+
+// lambert lighting with one normal per triangle
+pub struct FlatLitShader<'a> {
+    pub lights: &'a [Light],
+    pub face_normals: Vec<Vector3<f32>>,
+}
+
+impl<'a> FlatLitShader<'a> {
+    pub fn new(object: &Object3D, lights: &'a [Light]) -> FlatLitShader<'a> {
+        let face_normals = object.triangles.iter().map(|&(a, b, c)| {
+            let (pa, pb, pc) = (object.vertices[a].xyz(), object.vertices[b].xyz(), object.vertices[c].xyz());
+            (pb - pa).cross(&(pc - pa)).try_normalize(1e-12).unwrap_or_else(Vector3::y)
+        }).collect();
+        FlatLitShader { lights, face_normals }
+    }
+}
+
+impl<'a> Shader for FlatLitShader<'a> {
+    // world position and vertex color
+    type Varyings = (Vector3<f32>, Vector3<f32>);
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        (uniforms.model_view_projection * input.position, (uniforms.world_position(&input.position), srgb_color_to_linear(input.color)))
+    }
+
+    fn fragment(&self, uniforms: &Uniforms, input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        let (position, albedo) = varyings;
+        let mut normal = uniforms.world_normal(&self.face_normals[input.primitive]);
+        if normal.dot(&(uniforms.camera_position - position)) < 0.0 {
+            normal = -normal;
+        }
+        Some(with_alpha(blinn_phong(self.lights, &[], position, &normal, &uniforms.camera_position, albedo, 0.0, 1.0), 1.0))
+    }
+}
+
+pub struct PhongShader<'a> {
+    pub lights: &'a [Light],
+    pub shadows: &'a [Option<&'a dyn ShadowCaster>],
+    pub specular: f32,
+    pub shininess: f32,
+}
+
+impl<'a> Shader for PhongShader<'a> {
+    // world position, world normal and vertex color
+    type Varyings = (Vector3<f32>, Vector3<f32>, Vector3<f32>);
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        let varyings = (uniforms.world_position(&input.position), uniforms.world_normal(&input.normal), srgb_color_to_linear(input.color));
+        (uniforms.model_view_projection * input.position, varyings)
+    }
+
+    fn fragment(&self, uniforms: &Uniforms, _input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        let (position, normal, albedo) = varyings;
+        let normal = normal.normalize();
+        Some(with_alpha(blinn_phong(self.lights, self.shadows, position, &normal, &uniforms.camera_position, albedo, self.specular, self.shininess), 1.0))
+    }
+
+    fn surface_normal(&self, varyings: &Self::Varyings) -> Option<Vector3<f32>> {
+        Some(varyings.1)
+    }
+}
+
+// samples the sRGB texture with the object uvs, lit if any lights are given. texels with zero alpha are discarded
+pub struct TexturedShader<'a> {
+    pub texture: &'a Texture,
+    pub filter: TextureFilter,
+    pub lights: &'a [Light],
+}
+
+impl<'a> Shader for TexturedShader<'a> {
+    // uv, world position and world normal
+    type Varyings = (Vector2<f32>, Vector3<f32>, Vector3<f32>);
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        let varyings = (input.uv, uniforms.world_position(&input.position), uniforms.world_normal(&input.normal));
+        (uniforms.model_view_projection * input.position, varyings)
+    }
+
+    fn fragment(&self, uniforms: &Uniforms, input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        let (uv, position, normal) = varyings;
+        let texel = self.texture.sample_grad(uv, &input.uv_dx, &input.uv_dy, self.filter);
+        if texel.w <= 0.0 {
+            return None;
+        }
+        let albedo = texel.xyz().map(srgb_to_linear);
+        if self.lights.is_empty() {
+            return Some(with_alpha(albedo, texel.w));
+        }
+        Some(with_alpha(blinn_phong(self.lights, &[], position, &normal.normalize(), &uniforms.camera_position, &albedo, 0.0, 1.0), texel.w))
+    }
+
+    fn texture_coordinates(&self, varyings: &Self::Varyings) -> Option<Vector2<f32>> {
+        Some(varyings.0)
+    }
+
+    fn surface_normal(&self, varyings: &Self::Varyings) -> Option<Vector3<f32>> {
+        Some(varyings.2)
+    }
+}
+
+// applies a tangent space normal from a normal map. the interpolated tangent is made orthogonal to
+// the normal again, which is what MikkTSpace baked maps expect
+pub fn perturb_normal(normal: &Vector3<f32>, tangent: &Vector4<f32>, tangent_space_normal: &Vector3<f32>) -> Vector3<f32> {
+    let Some(orthogonal) = (tangent.xyz() - normal * normal.dot(&tangent.xyz())).try_normalize(1e-12) else {
+        return *normal;
+    };
+    let bitangent = normal.cross(&orthogonal) * tangent.w.signum();
+    (orthogonal * tangent_space_normal.x + bitangent * tangent_space_normal.y + normal * tangent_space_normal.z)
+        .try_normalize(1e-12)
+        .unwrap_or(*normal)
+}
+
+// metallic-roughness shading of a surface point with cook-torrance for every direct light and the
+// split sum approximation for the environment. the normal is the final world space one
+pub fn pbr_lighting(lights: &[Light], shadows: &[Option<&dyn ShadowCaster>], environment: Option<&ImageBasedLight>, position: &Vector3<f32>, normal: &Vector3<f32>, camera_position: &Vector3<f32>, surface: &SurfaceParameters) -> Vector3<f32> {
+    let base_color = surface.base_color.xyz();
+    let view = (camera_position - position).normalize();
+    // ambient light has no single direction, so the specular part only uses fresnel at the view angle
+    let ambient_fresnel = fresnel_schlick(base_reflectance(&base_color, surface.metallic), normal.dot(&view).max(0.0)) * (1.0 - surface.roughness);
+    let ambient_reflectance = (base_color * (1.0 - surface.metallic) + ambient_fresnel) * (surface.occlusion / PI);
+
+    let mut color = surface.emissive;
+    for (i, light) in lights.iter().enumerate() {
+        color += light.ambient(normal).component_mul(&ambient_reflectance);
+        let Some(sample) = light.sample(position) else {
+            continue;
+        };
+        let reflected = cook_torrance(normal, &view, &sample.direction, &base_color, surface.metallic, surface.roughness);
+        if reflected == Vector3::zeros() {
+            continue;
+        }
+        let visibility = shadows.get(i).copied().flatten().map_or(1.0, |shadow| shadow.visibility(position, normal));
+        color += reflected.component_mul(&sample.irradiance) * visibility;
+    }
+    if let Some(environment) = environment {
+        // split sum approximation, the prefiltered radiance times the preintegrated brdf
+        let (scale, bias) = environment_brdf(normal.dot(&view).max(1e-4), surface.roughness);
+        let specular_color = base_reflectance(&base_color, surface.metallic) * scale + Vector3::repeat(bias);
+        let specular = environment.specular(&reflect(&-view, normal), surface.roughness).component_mul(&specular_color);
+        let diffuse = environment.diffuse(normal)
+            .component_mul(&base_color)
+            .component_mul(&(Vector3::repeat(1.0) - specular_color)) * ((1.0 - surface.metallic) / PI);
+        color += (diffuse + specular) * surface.occlusion;
+    }
+    color
+}
+
+// metallic-roughness shading with cook-torrance for every direct light, see Material
+pub struct PbrShader<'a> {
+    pub materials: &'a MaterialSet<'a>,
+    pub lights: &'a [Light],
+    pub shadows: &'a [Option<&'a dyn ShadowCaster>],
+    pub material_indices: Vec<usize>,
+    // ambient light and reflections from the surroundings, on top of the lights
+    pub environment: Option<&'a ImageBasedLight>,
+}
+
+impl<'a> PbrShader<'a> {
+    pub fn new(object: &Object3D, materials: &'a MaterialSet<'a>, lights: &'a [Light], shadows: &'a [Option<&'a dyn ShadowCaster>]) -> PbrShader<'a> {
+        let material_indices = (0..object.triangles.len()).map(|triangle| materials.material_index(triangle)).collect();
+        PbrShader { materials, lights, shadows, material_indices, environment: None }
+    }
+
+    // the material at a fragment with the world space normal after normal mapping and the alpha
+    // after the alpha mode, None if the fragment is culled or discarded
+    pub fn surface(&self, input: &FragmentInput, varyings: &<Self as Shader>::Varyings) -> Option<(SurfaceParameters, Vector3<f32>, f32)> {
+        let (_, normal, uv, vertex_color, tangent) = varyings;
+        let material = &self.materials.materials[self.material_indices[input.primitive]];
+        if !material.double_sided && !input.front_facing {
+            return None;
+        }
+        let surface = material.evaluate(uv, &input.uv_dx, &input.uv_dy, vertex_color);
+        let alpha = material.resolve_alpha(surface.base_color.w)?;
+
+        let mut normal = normal.normalize();
+        if !input.front_facing {
+            normal = -normal;
+        }
+        if material.normal_texture.is_some() {
+            normal = perturb_normal(&normal, tangent, &surface.normal);
+        }
+        Some((surface, normal, alpha))
+    }
+}
+
+impl<'a> Shader for PbrShader<'a> {
+    // world position, world normal, uv, linear vertex color and world tangent
+    type Varyings = (Vector3<f32>, Vector3<f32>, Vector2<f32>, Vector3<f32>, Vector4<f32>);
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        let varyings = (
+            uniforms.world_position(&input.position),
+            uniforms.world_normal(&input.normal),
+            input.uv,
+            srgb_color_to_linear(input.color),
+            uniforms.world_tangent(&input.tangent),
+        );
+        (uniforms.model_view_projection * input.position, varyings)
+    }
+
+    fn fragment(&self, uniforms: &Uniforms, input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        let (surface, normal, alpha) = self.surface(input, varyings)?;
+        let color = pbr_lighting(self.lights, self.shadows, self.environment, &varyings.0, &normal, &uniforms.camera_position, &surface);
+        Some(with_alpha(color, alpha))
+    }
+
+    fn texture_coordinates(&self, varyings: &Self::Varyings) -> Option<Vector2<f32>> {
+        Some(varyings.2)
+    }
+
+    fn surface_normal(&self, varyings: &Self::Varyings) -> Option<Vector3<f32>> {
+        Some(varyings.1)
+    }
+}
+
+// world space normals mapped from -1..1 to 0..1. debug values are linearized so they come out
+// unchanged after the sRGB output transform with ToneMapping::Clamp
+pub struct NormalsDebugShader;
+
+impl Shader for NormalsDebugShader {
+    type Varyings = Vector3<f32>;
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        (uniforms.model_view_projection * input.position, uniforms.world_normal(&input.normal))
+    }
+
+    fn fragment(&self, _uniforms: &Uniforms, _input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        Some(with_alpha((varyings.normalize() * 0.5 + Vector3::repeat(0.5)).map(srgb_to_linear), 1.0))
+    }
+
+    fn surface_normal(&self, varyings: &Self::Varyings) -> Option<Vector3<f32>> {
+        Some(*varyings)
+    }
+}
+
+// linear view distance as grayscale, white at the camera and black at max_distance
+pub struct DepthDebugShader {
+    pub max_distance: f32,
+}
+
+impl Shader for DepthDebugShader {
+    type Varyings = f32;
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        let clip = uniforms.model_view_projection * input.position;
+        // w is the view space depth with this projection
+        (clip, clip.w)
+    }
+
+    fn fragment(&self, _uniforms: &Uniforms, _input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        let value = srgb_to_linear(1.0 - (varyings / self.max_distance).clamp(0.0, 1.0));
+        Some(Vector4::new(value, value, value, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::framebuffer::Framebuffer;
+    use super::super::reader::{unit_cube, unit_sphere};
+    use super::super::render::{draw_object_shaded, rasterize_triangle};
+
+    fn camera() -> Camera {
+        Camera {
+            fov: 90.0,
+            near: 0.1,
+            far: 100.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(1.0, 2.0, -4.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+
+    // white where the rasterizer says the face points at the camera, red where it does not
+    struct FacingShader;
+
+    impl Shader for FacingShader {
+        type Varyings = ();
+
+        fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+            (uniforms.model_view_projection * input.position, ())
+        }
+
+        fn fragment(&self, _uniforms: &Uniforms, input: &FragmentInput, _varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+            Some(if input.front_facing { Vector4::new(1.0, 1.0, 1.0, 1.0) } else { Vector4::new(1.0, 0.0, 0.0, 1.0) })
+        }
+    }
+
+    #[test]
+    fn closed_meshes_only_show_front_faces() {
+        for object in [unit_cube(0xFFFFFF), unit_sphere(0xFFFFFF)] {
+            let mut framebuffer = Framebuffer::new((64, 48));
+            framebuffer.clear(0x000000);
+            draw_object_shaded(&mut framebuffer, &object, &camera(), &Matrix4::identity(), &FacingShader);
+            let covered: Vec<_> = framebuffer.color.iter().filter(|color| color.x > 0.0).collect();
+            assert!(covered.len() > 50);
+            assert!(covered.iter().all(|color| color.y == 1.0));
+        }
+    }
+
+    #[test]
+    fn rasterizer_fills_both_windings_with_normalized_weights() {
+        let (p0, p1, p2) = (Vector3::new(1.0, 1.0, 0.0), Vector3::new(15.0, 2.0, 0.0), Vector3::new(4.0, 12.0, 0.0));
+        let mut counter_clockwise = vec![];
+        rasterize_triangle((16, 16), &p0, &p1, &p2, |x, y, weights| {
+            assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+            // the weights reproduce the pixel center
+            let center = p0 * weights[0] + p1 * weights[1] + p2 * weights[2];
+            assert!((center.x - (x as f32 + 0.5)).abs() < 1e-4 && (center.y - (y as f32 + 0.5)).abs() < 1e-4);
+            counter_clockwise.push((x, y));
+        });
+        let mut clockwise = vec![];
+        rasterize_triangle((16, 16), &p0, &p2, &p1, |x, y, _| clockwise.push((x, y)));
+        assert!(!clockwise.is_empty());
+        assert_eq!(clockwise, counter_clockwise);
+        // half of 14 * 11 - 1 * 3 pixels, give or take the edges
+        assert!((clockwise.len() as f32 - 75.5).abs() < 10.0);
+    }
+
+    #[test]
+    fn nearer_objects_win_in_any_order() {
+        let near = Matrix4::new_translation(&Vector3::new(0.0, 0.0, -1.5)) * Matrix4::new_scaling(0.5);
+        let draws = [(unit_cube(0xFF0000), Matrix4::identity()), (unit_cube(0x00FF00), near)];
+        for order in [[0, 1], [1, 0]] {
+            let mut framebuffer = Framebuffer::new((32, 32));
+            framebuffer.clear(0x000000);
+            for &i in order.iter() {
+                draw_object_shaded(&mut framebuffer, &draws[i].0, &camera(), &draws[i].1, &VertexColorShader);
+            }
+            let center = framebuffer.color[16 * 32 + 16];
+            assert_eq!((center.x, center.y), (0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn normals_use_the_inverse_transpose_without_the_translation() {
+        let transform = Matrix4::new_translation(&Vector3::new(5.0, -3.0, 2.0)) * Matrix4::new_nonuniform_scaling(&Vector3::new(4.0, 1.0, 1.0));
+        let uniforms = Uniforms::new(&camera(), &transform, 1.0);
+        let normal = uniforms.world_normal(&Vector3::new(1.0, 1.0, 0.0).normalize());
+        // the surface x + y = 1 becomes x / 4 + y = 1
+        assert!((normal - Vector3::new(1.0, 4.0, 0.0).normalize()).norm() < 1e-5);
+        assert!((uniforms.world_normal(&Vector3::y()) - Vector3::y()).norm() < 1e-6);
+    }
+
+    #[test]
+    fn lambert_reflects_albedo_over_pi_of_the_irradiance() {
+        let sun = Light::Directional { direction: -Vector3::y(), color: Vector3::repeat(1.0), intensity: PI };
+        let sky = Light::Hemisphere { up: Vector3::y(), sky_color: Vector3::repeat(1.0), ground_color: Vector3::zeros(), intensity: PI };
+        let albedo = Vector3::new(0.5, 0.25, 1.0);
+        let lit = |lights: &[Light]| blinn_phong(lights, &[], &Vector3::zeros(), &Vector3::y(), &Vector3::new(0.0, 1.0, 1.0), &albedo, 0.0, 32.0);
+        assert!((lit(&[sun]) - albedo).norm() < 1e-5);
+        assert!((lit(&[sky]) - albedo).norm() < 1e-5);
+        // light from behind the surface adds nothing and never subtracts
+        let behind = Light::Directional { direction: Vector3::y(), color: Vector3::repeat(1.0), intensity: PI };
+        assert_eq!(lit(&[behind]), Vector3::zeros());
+    }
+
+    #[test]
+    fn normal_map_axes_map_to_the_tangent_frame() {
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        // a tangent that is not quite orthogonal is straightened first
+        let tangent = Vector4::new(1.0, 0.3, 0.0, -1.0);
+        assert!((perturb_normal(&normal, &tangent, &Vector3::z()) - normal).norm() < 1e-6);
+        assert!((perturb_normal(&normal, &tangent, &Vector3::x()) - Vector3::x()).norm() < 1e-6);
+        // the handedness picks the side of the bitangent
+        assert!((perturb_normal(&normal, &tangent, &Vector3::y()) - Vector3::z()).norm() < 1e-6);
+        let right_handed = Vector4::new(1.0, 0.0, 0.0, 1.0);
+        assert!((perturb_normal(&normal, &right_handed, &Vector3::y()) + Vector3::z()).norm() < 1e-6);
+        // tangents along the normal leave nothing to perturb with
+        assert_eq!(perturb_normal(&normal, &Vector4::new(0.0, 2.0, 0.0, 1.0), &Vector3::x()), normal);
+    }
+
+    #[test]
+    fn tuple_varyings_interpolate_every_element() {
+        let values = [(1.0, Vector2::new(0.0, 2.0)), (3.0, Vector2::new(2.0, 0.0)), (5.0, Vector2::new(4.0, 4.0))];
+        let (a, b) = <(f32, Vector2<f32>)>::weighted_sum([&values[0], &values[1], &values[2]], [0.5, 0.25, 0.25]);
+        assert!((a - 2.5).abs() < 1e-6 && (b - Vector2::new(1.5, 2.0)).norm() < 1e-6);
+    }
+}
@@ -0,0 +1,313 @@
+use nalgebra::{Matrix4, Vector3, Vector4};
+
+use super::render::{draw_object_depth, Camera, Object3D};
+
+// anything that can tell how much light reaches a world space point, 1.0 is fully lit
+pub trait ShadowCaster: Sync {
+    fn visibility(&self, position: &Vector3<f32>, normal: &Vector3<f32>) -> f32;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    pub resolution: usize,
+    // subtracted from the receiver depth in ndc units
+    pub bias: f32,
+    // the receiver is moved along its normal by this many world units before the lookup
+    pub normal_bias: f32,
+    // percentage closer filtering over a (2 * radius + 1)^2 texel kernel, 0 gives hard shadows
+    pub pcf_radius: i32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> ShadowSettings {
+        ShadowSettings {
+            resolution: 1024,
+            bias: 0.002,
+            normal_bias: 0.05,
+            pcf_radius: 1,
+        }
+    }
+}
+
+fn light_camera(position: Vector3<f32>, direction: Vector3<f32>, fov: f32, near: f32, far: f32) -> Camera {
+    // the view matrix breaks down if up and the view direction are parallel
+    let up = if direction.normalize().y.abs() > 0.99 { Vector3::z() } else { Vector3::y() };
+    Camera {
+        fov,
+        near,
+        far,
+        up: Vector4::new(up.x, up.y, up.z, 0.0),
+        position: Vector4::new(position.x, position.y, position.z, 1.0),
+        look_at: Vector4::new(position.x + direction.x, position.y + direction.y, position.z + direction.z, 1.0),
+    }
+}
+
+// a single depth map rendered with draw_object_depth from the point of view of a light
+pub struct ShadowMap {
+    pub view_projection: Matrix4<f32>,
+    pub depth: Vec<f32>,
+    pub settings: ShadowSettings,
+}
+
+impl ShadowMap {
+    pub fn new(view_projection: Matrix4<f32>, settings: ShadowSettings) -> ShadowMap {
+        ShadowMap {
+            view_projection,
+            depth: vec![f32::MAX; settings.resolution * settings.resolution],
+            settings,
+        }
+    }
+
+    // directional lights, covers a sphere around center
+    pub fn orthographic(direction: Vector3<f32>, center: Vector3<f32>, radius: f32, settings: ShadowSettings) -> ShadowMap {
+        let direction = direction.normalize();
+        let camera = light_camera(center - direction * radius * 2.0, direction, 90.0, 0.0, radius * 4.0);
+        let view_projection = camera.get_orthographic_matrix(radius, radius) * camera.get_view_matrix();
+        ShadowMap::new(view_projection, settings)
+    }
+
+    // spot lights, fov is the full cone angle in degrees
+    pub fn perspective(position: Vector3<f32>, direction: Vector3<f32>, fov: f32, near: f32, far: f32, settings: ShadowSettings) -> ShadowMap {
+        let camera = light_camera(position, direction, fov, near, far);
+        let view_projection = camera.get_projection_matrix(1.0) * camera.get_view_matrix();
+        ShadowMap::new(view_projection, settings)
+    }
+
+    pub fn clear(&mut self) {
+        self.depth.iter_mut().for_each(|depth| *depth = f32::MAX);
+    }
+
+    // objects with their model matrix, see get_model_matrix
+    pub fn render(&mut self, casters: &[(&Object3D, Matrix4<f32>)]) {
+        self.clear();
+        let resolution = self.settings.resolution;
+        for (object, transform) in casters.iter() {
+            draw_object_depth(&mut self.depth, object, (resolution, resolution), &self.view_projection, transform);
+        }
+    }
+
+    // texel coordinates and ndc depth of a world space point, None outside of the map
+    fn project(&self, position: &Vector3<f32>) -> Option<(f32, f32, f32)> {
+        let clip = self.view_projection * Vector4::new(position.x, position.y, position.z, 1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let ndc = clip.xyz() / clip.w;
+        if ndc.x.abs() > 1.0 || ndc.y.abs() > 1.0 || ndc.z > 1.0 {
+            return None;
+        }
+        let resolution = self.settings.resolution as f32;
+        Some(((ndc.x + 1.0) * resolution / 2.0, (-ndc.y + 1.0) * resolution / 2.0, ndc.z))
+    }
+
+    pub fn contains(&self, position: &Vector3<f32>) -> bool {
+        self.project(position).is_some()
+    }
+}
+
+impl ShadowCaster for ShadowMap {
+    fn visibility(&self, position: &Vector3<f32>, normal: &Vector3<f32>) -> f32 {
+        let offset_position = position + normal * self.settings.normal_bias;
+        let Some((x, y, depth)) = self.project(&offset_position) else {
+            return 1.0;
+        };
+        let resolution = self.settings.resolution as i32;
+        let radius = self.settings.pcf_radius.max(0);
+        let (cx, cy) = (x as i32, y as i32);
+        let mut lit = 0;
+        let mut samples = 0;
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let (sx, sy) = ((cx + dx).clamp(0, resolution - 1), (cy + dy).clamp(0, resolution - 1));
+                if depth - self.settings.bias <= self.depth[(sy * resolution + sx) as usize] {
+                    lit += 1;
+                }
+                samples += 1;
+            }
+        }
+        lit as f32 / samples as f32
+    }
+}
+
+// six 90 degree perspective maps around a point light
+pub struct CubeShadowMap {
+    pub position: Vector3<f32>,
+    pub faces: Vec<ShadowMap>,
+}
+
+impl CubeShadowMap {
+    pub fn new(position: Vector3<f32>, near: f32, far: f32, settings: ShadowSettings) -> CubeShadowMap {
+        let directions = [Vector3::x(), -Vector3::x(), Vector3::y(), -Vector3::y(), Vector3::z(), -Vector3::z()];
+        CubeShadowMap {
+            position,
+            faces: directions.iter().map(|direction| ShadowMap::perspective(position, *direction, 90.0, near, far, settings)).collect(),
+        }
+    }
+
+    pub fn render(&mut self, casters: &[(&Object3D, Matrix4<f32>)]) {
+        self.faces.iter_mut().for_each(|face| face.render(casters));
+    }
+
+    fn face_index(direction: &Vector3<f32>) -> usize {
+        let abs = direction.abs();
+        if abs.x >= abs.y && abs.x >= abs.z {
+            if direction.x > 0.0 { 0 } else { 1 }
+        } else if abs.y >= abs.z {
+            if direction.y > 0.0 { 2 } else { 3 }
+        } else if direction.z > 0.0 {
+            4
+        } else {
+            5
+        }
+    }
+}
+
+impl ShadowCaster for CubeShadowMap {
+    fn visibility(&self, position: &Vector3<f32>, normal: &Vector3<f32>) -> f32 {
+        self.faces[CubeShadowMap::face_index(&(position - self.position))].visibility(position, normal)
+    }
+}
+
+// orthographic maps covering consecutive slices of the camera frustum, for the sun over large terrains
+pub struct CascadedShadowMap {
+    pub cascades: Vec<ShadowMap>,
+    // far distance of each cascade along the camera view direction
+    pub splits: Vec<f32>,
+    camera_position: Vector3<f32>,
+    camera_forward: Vector3<f32>,
+}
+
+impl CascadedShadowMap {
+    // lambda blends between uniform (0.0) and logarithmic (1.0) split distances
+    pub fn new(camera: &Camera, aspect_ratio: f32, direction: Vector3<f32>, cascade_count: usize, max_distance: f32, lambda: f32, settings: ShadowSettings) -> CascadedShadowMap {
+        let (right, up, forward) = camera.get_basis();
+        let near = camera.near;
+        let far = max_distance.min(camera.far);
+        let tan_half_fov = (camera.fov.to_radians() / 2.0).tan();
+        let cascade_count = cascade_count.max(1);
+
+        let splits: Vec<f32> = (1..=cascade_count).map(|i| {
+            let fraction = i as f32 / cascade_count as f32;
+            let logarithmic = near * (far / near).powf(fraction);
+            let uniform = near + (far - near) * fraction;
+            lambda * logarithmic + (1.0 - lambda) * uniform
+        }).collect();
+
+        let mut slice_near = near;
+        let cascades = splits.iter().map(|&slice_far| {
+            // bounding sphere of the frustum slice, it does not change when the camera rotates
+            let corners: Vec<Vector3<f32>> = [slice_near, slice_far].iter().flat_map(|&distance| {
+                let half_height = distance * tan_half_fov;
+                let half_width = half_height * aspect_ratio;
+                let center = camera.position.xyz() + forward * distance;
+                [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+                    .map(|(sx, sy)| center + right * (sx * half_width) + up * (sy * half_height))
+            }).collect();
+            let center = corners.iter().sum::<Vector3<f32>>() / corners.len() as f32;
+            let radius = corners.iter().map(|corner| (corner - center).norm()).fold(0.0, f32::max);
+            slice_near = slice_far;
+
+            // snapping the center to whole texels keeps the shadow edges from crawling while the camera moves
+            let map = ShadowMap::orthographic(direction, center, radius, settings);
+            let texel = 2.0 * radius / settings.resolution as f32;
+            let light_center = map.view_projection * Vector4::new(center.x, center.y, center.z, 1.0);
+            let texel_ndc = texel / radius;
+            let snapped = Vector4::new(
+                (light_center.x / texel_ndc).round() * texel_ndc,
+                (light_center.y / texel_ndc).round() * texel_ndc,
+                light_center.z,
+                light_center.w,
+            );
+            let correction = Matrix4::new_translation(&Vector3::new(snapped.x - light_center.x, snapped.y - light_center.y, 0.0));
+            ShadowMap::new(correction * map.view_projection, settings)
+        }).collect();
+
+        CascadedShadowMap {
+            cascades,
+            splits,
+            camera_position: camera.position.xyz(),
+            camera_forward: forward,
+        }
+    }
+
+    pub fn render(&mut self, casters: &[(&Object3D, Matrix4<f32>)]) {
+        self.cascades.iter_mut().for_each(|cascade| cascade.render(casters));
+    }
+
+    pub fn cascade_index(&self, position: &Vector3<f32>) -> Option<usize> {
+        let distance = (position - self.camera_position).dot(&self.camera_forward);
+        self.splits.iter().position(|&split| distance <= split)
+    }
+}
+
+impl ShadowCaster for CascadedShadowMap {
+    fn visibility(&self, position: &Vector3<f32>, normal: &Vector3<f32>) -> f32 {
+        match self.cascade_index(position) {
+            // the next cascade still covers points just outside of their slice
+            Some(index) => self.cascades[index..].iter()
+                .find(|cascade| cascade.contains(position))
+                .map_or(1.0, |cascade| cascade.visibility(position, normal)),
+            None => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::reader::unit_cube;
+
+    // a cube floating over the origin, the sun straight above
+    fn caster() -> (Object3D, Matrix4<f32>) {
+        (unit_cube(0xFFFFFF), Matrix4::new_translation(&Vector3::new(0.0, 3.0, 0.0)))
+    }
+
+    #[test]
+    fn orthographic_map_shadows_below_the_caster() {
+        let (cube, transform) = caster();
+        let mut map = ShadowMap::orthographic(-Vector3::y(), Vector3::new(0.0, 1.0, 0.0), 6.0, ShadowSettings::default());
+        map.render(&[(&cube, transform)]);
+        let up = Vector3::y();
+        assert_eq!(map.visibility(&Vector3::zeros(), &up), 0.0);
+        assert_eq!(map.visibility(&Vector3::new(3.0, 0.0, 0.0), &up), 1.0);
+        // the top of the cube lights itself
+        assert_eq!(map.visibility(&Vector3::new(0.0, 4.0, 0.0), &up), 1.0);
+        // outside of the map nothing is known, so it is lit
+        assert!(!map.contains(&Vector3::new(50.0, 0.0, 0.0)));
+        assert_eq!(map.visibility(&Vector3::new(50.0, 0.0, 0.0), &up), 1.0);
+    }
+
+    #[test]
+    fn cube_map_shadows_in_every_direction() {
+        let (cube, _) = caster();
+        for direction in [Vector3::x(), -Vector3::x(), Vector3::y(), -Vector3::y(), Vector3::z(), -Vector3::z()] {
+            let transform = Matrix4::new_translation(&(direction * 3.0));
+            let mut map = CubeShadowMap::new(Vector3::zeros(), 0.1, 20.0, ShadowSettings::default());
+            map.render(&[(&cube, transform)]);
+            assert_eq!(map.visibility(&(direction * 6.0), &-direction), 0.0);
+            assert_eq!(map.visibility(&(-direction * 6.0), &direction), 1.0);
+        }
+    }
+
+    #[test]
+    fn cascades_cover_the_view_up_to_the_shadow_distance() {
+        let camera = Camera {
+            fov: 90.0,
+            near: 0.1,
+            far: 1000.0,
+            up: Vector4::new(0.0, 1.0, 0.0, 0.0),
+            position: Vector4::new(0.0, 5.0, -20.0, 1.0),
+            look_at: Vector4::new(0.0, 0.0, 0.0, 1.0),
+        };
+        let cascades = CascadedShadowMap::new(&camera, 1.25, Vector3::new(-1.0, -0.6, 0.4), 3, 40.0, 0.5, ShadowSettings::default());
+        assert!(cascades.splits.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((cascades.splits[2] - 40.0).abs() < 1e-3);
+        let (_, _, forward) = camera.get_basis();
+        for distance in [0.5, 5.0, 15.0, 30.0, 39.0] {
+            let point = camera.position.xyz() + forward * distance;
+            let index = cascades.cascade_index(&point).unwrap();
+            assert!(cascades.cascades[index].contains(&point));
+        }
+        assert_eq!(cascades.cascade_index(&(camera.position.xyz() + forward * 41.0)), None);
+    }
+}
@@ -0,0 +1,181 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+
+use super::environment::Environment;
+use super::light::Light;
+
+// direction towards the sun for a local solar time in hours, a day of the year and a latitude in
+// degrees. +x is east, +y up and +z north
+pub fn sun_direction(time_of_day: f32, day_of_year: f32, latitude: f32) -> Vector3<f32> {
+    let declination = (-23.44f32).to_radians() * (2.0 * PI * (day_of_year + 10.0) / 365.0).cos();
+    let hour_angle = (15.0 * (time_of_day - 12.0)).to_radians();
+    let latitude = latitude.to_radians();
+    Vector3::new(
+        -declination.cos() * hour_angle.sin(),
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos(),
+        latitude.cos() * declination.sin() - latitude.sin() * declination.cos() * hour_angle.cos(),
+    ).normalize()
+}
+
+// distance to where a ray starting inside the sphere around the planet center leaves it
+fn sphere_exit(origin: &Vector3<f32>, direction: &Vector3<f32>, radius: f32) -> f32 {
+    let b = origin.dot(direction);
+    let c = origin.norm_squared() - radius * radius;
+    -b + (b * b - c).max(0.0).sqrt()
+}
+
+// distance to the ground if the ray hits the planet
+fn ground_hit(origin: &Vector3<f32>, direction: &Vector3<f32>, radius: f32) -> Option<f32> {
+    let b = origin.dot(direction);
+    let c = origin.norm_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t = -b - discriminant.sqrt();
+    (t > 0.0).then_some(t)
+}
+
+// single scattering of sunlight by air molecules (rayleigh) and aerosols (mie), ray marched like
+// Nishita et al. distances are in meters, the coefficients are per meter at sea level
+#[derive(Clone, Copy, Debug)]
+pub struct AtmosphericSky {
+    // normalized, pointing towards the sun
+    pub sun_direction: Vector3<f32>,
+    pub sun_intensity: f32,
+    // angular radius of the visible sun disc in degrees, bigger than the real 0.27 so it survives
+    // baking into a cube map
+    pub sun_disc_radius: f32,
+    pub rayleigh_scattering: Vector3<f32>,
+    pub rayleigh_scale_height: f32,
+    pub mie_scattering: f32,
+    pub mie_scale_height: f32,
+    // towards 1 the haze gathers around the sun
+    pub mie_anisotropy: f32,
+    pub planet_radius: f32,
+    pub atmosphere_radius: f32,
+    pub observer_height: f32,
+    // diffuse reflectance of the ground below the horizon
+    pub ground_albedo: f32,
+    // added everywhere so nights are not pitch black
+    pub night_color: Vector3<f32>,
+    pub view_samples: usize,
+    pub light_samples: usize,
+}
+
+impl Default for AtmosphericSky {
+    fn default() -> AtmosphericSky {
+        AtmosphericSky {
+            sun_direction: Vector3::y(),
+            sun_intensity: 20.0,
+            sun_disc_radius: 1.5,
+            rayleigh_scattering: Vector3::new(5.8e-6, 13.5e-6, 33.1e-6),
+            rayleigh_scale_height: 8000.0,
+            mie_scattering: 21e-6,
+            mie_scale_height: 1200.0,
+            mie_anisotropy: 0.76,
+            planet_radius: 6360e3,
+            atmosphere_radius: 6420e3,
+            observer_height: 100.0,
+            ground_albedo: 0.1,
+            night_color: Vector3::new(0.002, 0.003, 0.008),
+            view_samples: 16,
+            light_samples: 8,
+        }
+    }
+}
+
+impl AtmosphericSky {
+    fn observer(&self) -> Vector3<f32> {
+        Vector3::new(0.0, self.planet_radius + self.observer_height, 0.0)
+    }
+
+    // rayleigh and mie particle densities integrated along a ray, relative to sea level
+    fn optical_depth(&self, origin: &Vector3<f32>, direction: &Vector3<f32>, length: f32, samples: usize) -> (f32, f32) {
+        let step = length / samples as f32;
+        (0..samples).fold((0.0, 0.0), |(rayleigh, mie), i| {
+            let height = (origin + direction * ((i as f32 + 0.5) * step)).norm() - self.planet_radius;
+            (rayleigh + (-height / self.rayleigh_scale_height).exp() * step, mie + (-height / self.mie_scale_height).exp() * step)
+        })
+    }
+
+    fn extinction(&self, rayleigh: f32, mie: f32) -> Vector3<f32> {
+        // aerosols absorb a little on top of scattering
+        (self.rayleigh_scattering * rayleigh + Vector3::repeat(self.mie_scattering * 1.1 * mie)).map(|tau| (-tau).exp())
+    }
+
+    // fraction of light that makes it through the atmosphere from position along direction
+    pub fn transmittance(&self, position: &Vector3<f32>, direction: &Vector3<f32>) -> Vector3<f32> {
+        if ground_hit(position, direction, self.planet_radius).is_some() {
+            return Vector3::zeros();
+        }
+        let length = sphere_exit(position, direction, self.atmosphere_radius);
+        let (rayleigh, mie) = self.optical_depth(position, direction, length, self.light_samples.max(1));
+        self.extinction(rayleigh, mie)
+    }
+
+    // the sun as seen from the ground, reddened and dimmed by the atmosphere and gone at night.
+    // irradiance is the intensity with the sun straight overhead and no atmosphere
+    pub fn sun_light(&self, irradiance: f32) -> Light {
+        let transmittance = self.transmittance(&self.observer(), &self.sun_direction);
+        let strength = transmittance.max();
+        Light::Directional {
+            direction: -self.sun_direction,
+            color: if strength > 0.0 { transmittance / strength } else { Vector3::repeat(1.0) },
+            intensity: irradiance * strength,
+        }
+    }
+}
+
+impl Environment for AtmosphericSky {
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let direction = direction.normalize();
+        let origin = self.observer();
+        let ground = ground_hit(&origin, &direction, self.planet_radius);
+        let length = ground.unwrap_or_else(|| sphere_exit(&origin, &direction, self.atmosphere_radius));
+        let step = length / self.view_samples.max(1) as f32;
+
+        let (mut rayleigh_sum, mut mie_sum) = (Vector3::zeros(), Vector3::zeros());
+        let (mut rayleigh_depth, mut mie_depth) = (0.0, 0.0);
+        for i in 0..self.view_samples.max(1) {
+            let position = origin + direction * ((i as f32 + 0.5) * step);
+            let height = position.norm() - self.planet_radius;
+            let rayleigh = (-height / self.rayleigh_scale_height).exp() * step;
+            let mie = (-height / self.mie_scale_height).exp() * step;
+            rayleigh_depth += rayleigh;
+            mie_depth += mie;
+            // points in the shadow of the planet get no sunlight
+            if ground_hit(&position, &self.sun_direction, self.planet_radius).is_some() {
+                continue;
+            }
+            let light_length = sphere_exit(&position, &self.sun_direction, self.atmosphere_radius);
+            let (light_rayleigh, light_mie) = self.optical_depth(&position, &self.sun_direction, light_length, self.light_samples.max(1));
+            let attenuation = self.extinction(rayleigh_depth + light_rayleigh, mie_depth + light_mie);
+            rayleigh_sum += attenuation * rayleigh;
+            mie_sum += attenuation * mie;
+        }
+
+        let mu = direction.dot(&self.sun_direction);
+        let g = self.mie_anisotropy;
+        let rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+        let mie_phase = 3.0 / (8.0 * PI) * ((1.0 - g * g) * (1.0 + mu * mu)) / ((2.0 + g * g) * (1.0 + g * g - 2.0 * g * mu).powf(1.5));
+        let mut radiance = (rayleigh_sum.component_mul(&self.rayleigh_scattering) * rayleigh_phase
+            + mie_sum * (self.mie_scattering * mie_phase)) * self.sun_intensity;
+
+        match ground {
+            // sunlight reflected off the ground, seen through the air in between
+            Some(distance) => {
+                let normal = (origin + direction * distance).normalize();
+                // lifted a little so the surface does not shadow itself
+                let position = origin + direction * distance + normal * 10.0;
+                let sunlight = self.transmittance(&position, &self.sun_direction) * normal.dot(&self.sun_direction).max(0.0);
+                radiance += sunlight.component_mul(&self.extinction(rayleigh_depth, mie_depth)) * (self.ground_albedo / PI * self.sun_intensity);
+            }
+            None if mu > self.sun_disc_radius.to_radians().cos() => {
+                radiance += self.transmittance(&origin, &self.sun_direction) * self.sun_intensity;
+            }
+            None => {}
+        }
+        radiance + self.night_color
+    }
+}
@@ -0,0 +1,252 @@
+use image::imageops::FilterType;
+use image::{ImageBuffer, Rgba};
+use nalgebra::{Vector2, Vector4};
+
+use super::math::{linear_to_srgb, srgb_to_linear};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    // bilinear in the closest mip level
+    NearestMip,
+    // bilinear in the two closest mip levels, blended by the fractional level of detail
+    Trilinear,
+    // up to this many trilinear samples spread along the longer axis of the pixel footprint
+    Anisotropic(u32),
+}
+
+// rgba texture with float texels in 0..1, uvs wrap around
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<Vector4<f32>>,
+    // successively halved copies down to 1x1, empty until generate_mipmaps is called
+    pub mip_levels: Vec<Texture>,
+}
+
+impl Texture {
+    pub fn from_image(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Texture {
+        Texture {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            texels: image.pixels()
+                .map(|pixel| Vector4::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32, pixel[3] as f32) / 255.0)
+                .collect(),
+            mip_levels: vec![],
+        }
+    }
+
+    // float texels are used as they are, e.g. linear radiance from .hdr or .exr files
+    pub fn from_float_image(image: &ImageBuffer<Rgba<f32>, Vec<f32>>) -> Texture {
+        Texture {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            texels: image.pixels().map(|pixel| Vector4::new(pixel[0], pixel[1], pixel[2], pixel[3])).collect(),
+            mip_levels: vec![],
+        }
+    }
+
+    // the color channels go through decode, alpha stays as it is
+    fn to_image(&self, decode: fn(f32) -> f32) -> ImageBuffer<Rgba<f32>, Vec<f32>> {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let texel = self.texels[y as usize * self.width + x as usize];
+            Rgba([decode(texel.x), decode(texel.y), decode(texel.z), texel.w])
+        })
+    }
+
+    // every level is resized from the previous one with the given filter, like scale_image does.
+    // for linear data like normal, roughness or radiance maps
+    pub fn generate_mipmaps(&mut self, filter: FilterType) {
+        self.build_mipmaps(filter, |c| c, |c| c);
+    }
+
+    // for colors stored in sRGB like the base color maps. they are averaged in linear space, the
+    // encoded values would make the smaller levels darker
+    pub fn generate_srgb_mipmaps(&mut self, filter: FilterType) {
+        self.build_mipmaps(filter, srgb_to_linear, linear_to_srgb);
+    }
+
+    fn build_mipmaps(&mut self, filter: FilterType, decode: fn(f32) -> f32, encode: fn(f32) -> f32) {
+        self.mip_levels.clear();
+        let mut image = self.to_image(decode);
+        while image.width() > 1 || image.height() > 1 {
+            image = image::imageops::resize(&image, (image.width() / 2).max(1), (image.height() / 2).max(1), filter);
+            self.mip_levels.push(Texture {
+                width: image.width() as usize,
+                height: image.height() as usize,
+                texels: image.pixels().map(|pixel| Vector4::new(encode(pixel[0]), encode(pixel[1]), encode(pixel[2]), pixel[3])).collect(),
+                mip_levels: vec![],
+            });
+        }
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.mip_levels.len() + 1
+    }
+
+    // level 0 is the texture itself, indices past the smallest level are clamped
+    pub fn level(&self, index: usize) -> &Texture {
+        if index == 0 || self.mip_levels.is_empty() {
+            return self;
+        }
+        &self.mip_levels[(index - 1).min(self.mip_levels.len() - 1)]
+    }
+
+    pub fn texel(&self, x: i64, y: i64) -> Vector4<f32> {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.texels[y * self.width + x]
+    }
+
+    fn nearest(&self, uv: &Vector2<f32>) -> Vector4<f32> {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = uv.y * self.height as f32 - 0.5;
+        self.texel(x.round() as i64, y.round() as i64)
+    }
+
+    fn bilinear(&self, uv: &Vector2<f32>) -> Vector4<f32> {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = uv.y * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0).lerp(&self.texel(x0 + 1, y0), tx);
+        let bottom = self.texel(x0, y0 + 1).lerp(&self.texel(x0 + 1, y0 + 1), tx);
+        top.lerp(&bottom, ty)
+    }
+
+    fn trilinear(&self, uv: &Vector2<f32>, lod: f32) -> Vector4<f32> {
+        let lod = lod.clamp(0.0, (self.level_count() - 1) as f32);
+        let level = lod.floor() as usize;
+        let sample = self.level(level).bilinear(uv);
+        let t = lod - level as f32;
+        if t <= 0.0 {
+            return sample;
+        }
+        sample.lerp(&self.level(level + 1).bilinear(uv), t)
+    }
+
+    // sampling without derivatives always uses the full resolution level
+    pub fn sample(&self, uv: &Vector2<f32>, filter: TextureFilter) -> Vector4<f32> {
+        self.sample_grad(uv, &Vector2::zeros(), &Vector2::zeros(), filter)
+    }
+
+    // uv_dx and uv_dy are the change of uv between neighbouring pixels, see FragmentInput
+    pub fn sample_grad(&self, uv: &Vector2<f32>, uv_dx: &Vector2<f32>, uv_dy: &Vector2<f32>, filter: TextureFilter) -> Vector4<f32> {
+        let size = Vector2::new(self.width as f32, self.height as f32);
+        // footprint of the pixel in texels
+        let length_x = uv_dx.component_mul(&size).norm();
+        let length_y = uv_dy.component_mul(&size).norm();
+        match filter {
+            TextureFilter::Nearest => self.nearest(uv),
+            TextureFilter::Bilinear => self.bilinear(uv),
+            TextureFilter::NearestMip => {
+                let lod = length_x.max(length_y).max(1.0).log2();
+                self.level(lod.round() as usize).bilinear(uv)
+            }
+            TextureFilter::Trilinear => self.trilinear(uv, length_x.max(length_y).max(1.0).log2()),
+            TextureFilter::Anisotropic(max_samples) => {
+                let (major, minor, axis) = if length_x > length_y { (length_x, length_y, uv_dx) } else { (length_y, length_x, uv_dy) };
+                let samples = (major / minor.max(1e-6)).ceil().clamp(1.0, max_samples.max(1) as f32) as usize;
+                // the level is picked for the footprint width left after splitting the major axis
+                let lod = (major / samples as f32).max(1.0).log2();
+                let sum: Vector4<f32> = (0..samples)
+                    .map(|i| self.trilinear(&(uv + axis * ((i as f32 + 0.5) / samples as f32 - 0.5)), lod))
+                    .sum();
+                sum / samples as f32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(width: usize, height: usize, dark: f32, bright: f32) -> Texture {
+        Texture {
+            width,
+            height,
+            texels: (0..width * height)
+                .map(|i| {
+                    let value = if (i % width + i / width).is_multiple_of(2) { dark } else { bright };
+                    Vector4::new(value, value, value, 1.0)
+                })
+                .collect(),
+            mip_levels: vec![],
+        }
+    }
+
+    #[test]
+    fn mip_chains_halve_down_to_one_texel() {
+        let mut texture = checkerboard(16, 4, 0.0, 1.0);
+        texture.generate_mipmaps(FilterType::Triangle);
+        let sizes: Vec<_> = (0..texture.level_count()).map(|i| (texture.level(i).width, texture.level(i).height)).collect();
+        assert_eq!(sizes, [(16, 4), (8, 2), (4, 1), (2, 1), (1, 1)]);
+        // past the last level it stays at 1x1
+        assert_eq!(texture.level(10).width, 1);
+    }
+
+    #[test]
+    fn srgb_mips_average_in_linear_space() {
+        let mut linear = checkerboard(8, 8, 0.0, 1.0);
+        let mut srgb = linear.clone();
+        linear.generate_mipmaps(FilterType::Triangle);
+        srgb.generate_srgb_mipmaps(FilterType::Triangle);
+        let smallest = |texture: &Texture| texture.level(texture.level_count() - 1).texels[0];
+        assert!((smallest(&linear).x - 0.5).abs() < 0.02);
+        // half the light of white, not the encoded middle grey
+        assert!((srgb_to_linear(smallest(&srgb).x) - 0.5).abs() < 0.02);
+        assert!(smallest(&srgb).x > 0.7);
+        // alpha is never encoded
+        assert!((smallest(&srgb).w - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn bilinear_hits_texel_centers_and_interpolates_between_them() {
+        let texture = Texture {
+            width: 2,
+            height: 1,
+            texels: vec![Vector4::new(0.0, 0.0, 0.0, 1.0), Vector4::new(1.0, 1.0, 1.0, 1.0)],
+            mip_levels: vec![],
+        };
+        let sample = |u: f32, filter| texture.sample(&Vector2::new(u, 0.5), filter).x;
+        assert!((sample(0.25, TextureFilter::Bilinear) - 0.0).abs() < 1e-6);
+        assert!((sample(0.75, TextureFilter::Bilinear) - 1.0).abs() < 1e-6);
+        assert!((sample(0.5, TextureFilter::Bilinear) - 0.5).abs() < 1e-6);
+        assert!((sample(0.375, TextureFilter::Bilinear) - 0.25).abs() < 1e-6);
+        // uvs wrap, so the left edge blends with the last texel
+        assert!((sample(0.0, TextureFilter::Bilinear) - 0.5).abs() < 1e-6);
+        assert_eq!(sample(0.3, TextureFilter::Nearest), 0.0);
+        assert_eq!(sample(0.7, TextureFilter::Nearest), 1.0);
+    }
+
+    #[test]
+    fn minified_footprints_use_the_smaller_levels() {
+        let mut texture = checkerboard(64, 64, 0.0, 1.0);
+        texture.generate_mipmaps(FilterType::Triangle);
+        let uv = Vector2::new(0.3, 0.6);
+        // a pixel covering 16x16 texels sees the average of the checkerboard
+        let dx = Vector2::new(16.0 / 64.0, 0.0);
+        let dy = Vector2::new(0.0, 16.0 / 64.0);
+        for filter in [TextureFilter::NearestMip, TextureFilter::Trilinear, TextureFilter::Anisotropic(8)] {
+            assert!((texture.sample_grad(&uv, &dx, &dy, filter).x - 0.5).abs() < 0.05, "{:?}", filter);
+        }
+        // a footprint of one texel stays at full resolution
+        let texel = Vector2::new(1.0 / 64.0, 0.0);
+        let sharp = texture.sample_grad(&Vector2::new(0.5 / 64.0, 0.5 / 64.0), &texel, &texel.yx(), TextureFilter::Trilinear);
+        assert!(sharp.x.abs() < 1e-6);
+        // a long thin footprint is sharper across its minor axis with anisotropic filtering than trilinear
+        let mut stripes = Texture { texels: (0..64 * 64).map(|i| Vector4::repeat(((i / 64) % 2) as f32)).collect(), ..checkerboard(64, 64, 0.0, 0.0) };
+        stripes.generate_mipmaps(FilterType::Triangle);
+        let along = Vector2::new(16.0 / 64.0, 0.0);
+        let across = Vector2::new(0.0, 1.0 / 64.0);
+        let row = Vector2::new(0.5, 1.5 / 64.0);
+        let trilinear = stripes.sample_grad(&row, &along, &across, TextureFilter::Trilinear).x;
+        let anisotropic = stripes.sample_grad(&row, &along, &across, TextureFilter::Anisotropic(16)).x;
+        assert!(anisotropic > 0.99);
+        assert!(trilinear < 0.8);
+    }
+}
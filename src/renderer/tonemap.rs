@@ -0,0 +1,132 @@
+use nalgebra::{Vector3, Vector4};
+
+use super::math::{linear_to_srgb, vector_to_color};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ToneMapping {
+    // everything above 1.0 clips
+    Clamp,
+    // c / (1 + c) per channel
+    Reinhard,
+    // Krzysztof Narkowicz's fit of the ACES reference rendering transform
+    #[default]
+    AcesFilmic,
+    // John Hable's filmic curve from Uncharted 2
+    Uncharted2,
+}
+
+impl ToneMapping {
+    pub fn next(&self) -> ToneMapping {
+        match self {
+            ToneMapping::Clamp => ToneMapping::Reinhard,
+            ToneMapping::Reinhard => ToneMapping::AcesFilmic,
+            ToneMapping::AcesFilmic => ToneMapping::Uncharted2,
+            ToneMapping::Uncharted2 => ToneMapping::Clamp,
+        }
+    }
+}
+
+fn aces_filmic(x: f32) -> f32 {
+    // the fit expects the input scaled down, otherwise mid gray comes out too bright
+    let x = x * 0.6;
+    ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+}
+
+fn uncharted2_curve(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn uncharted2(x: f32) -> f32 {
+    let white_point = 11.2;
+    let exposure_bias = 2.0;
+    (uncharted2_curve(x * exposure_bias) / uncharted2_curve(white_point)).clamp(0.0, 1.0)
+}
+
+// turns linear hdr radiance into displayable sRGB
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ToneMapper {
+    pub operator: ToneMapping,
+    // in stops, every step doubles the brightness
+    pub exposure: f32,
+}
+
+impl ToneMapper {
+    pub fn new(operator: ToneMapping, exposure: f32) -> ToneMapper {
+        ToneMapper { operator, exposure }
+    }
+
+    // linear radiance to linear display values in 0..1
+    pub fn apply(&self, color: &Vector3<f32>) -> Vector3<f32> {
+        let exposed = color.map(|c| c.max(0.0)) * self.exposure.exp2();
+        match self.operator {
+            ToneMapping::Clamp => exposed.map(|c| c.min(1.0)),
+            ToneMapping::Reinhard => exposed.map(|c| c / (1.0 + c)),
+            ToneMapping::AcesFilmic => exposed.map(aces_filmic),
+            ToneMapping::Uncharted2 => exposed.map(uncharted2),
+        }
+    }
+
+    // tone mapped and sRGB encoded 0RGB color as minifb and the png export expect
+    pub fn to_color(&self, color: &Vector3<f32>) -> u32 {
+        vector_to_color(self.apply(color).map(linear_to_srgb))
+    }
+
+    pub fn resolve(&self, hdr: &[Vector4<f32>], buffer: &mut [u32]) {
+        for (pixel, color) in buffer.iter_mut().zip(hdr.iter()) {
+            *pixel = self.to_color(&color.xyz());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMapping; 4] = [ToneMapping::Clamp, ToneMapping::Reinhard, ToneMapping::AcesFilmic, ToneMapping::Uncharted2];
+
+    fn curve(operator: ToneMapping, exposure: f32, x: f32) -> f32 {
+        ToneMapper::new(operator, exposure).apply(&Vector3::repeat(x)).x
+    }
+
+    #[test]
+    fn curves_start_at_black_rise_and_stay_displayable() {
+        for operator in OPERATORS {
+            assert!(curve(operator, 0.0, 0.0).abs() < 1e-3, "{:?}", operator);
+            assert_eq!(curve(operator, 0.0, -1.0), curve(operator, 0.0, 0.0));
+            let values: Vec<f32> = (0..200).map(|i| curve(operator, 0.0, i as f32 * 0.1)).collect();
+            assert!(values.windows(2).all(|pair| pair[1] >= pair[0]), "{:?}", operator);
+            assert!(values.iter().all(|&value| (0.0..=1.0).contains(&value)), "{:?}", operator);
+        }
+    }
+
+    #[test]
+    fn known_curve_values() {
+        assert_eq!(curve(ToneMapping::Clamp, 0.0, 0.5), 0.5);
+        assert_eq!(curve(ToneMapping::Clamp, 0.0, 3.0), 1.0);
+        assert!((curve(ToneMapping::Reinhard, 0.0, 1.0) - 0.5).abs() < 1e-6);
+        assert!((curve(ToneMapping::Reinhard, 0.0, 3.0) - 0.75).abs() < 1e-6);
+        // the filmic curves roll off towards white instead of clipping
+        assert!(curve(ToneMapping::AcesFilmic, 0.0, 16.0) > 0.95);
+        assert!((curve(ToneMapping::Uncharted2, 0.0, 11.2 / 2.0) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn every_stop_of_exposure_doubles_the_input() {
+        for operator in OPERATORS {
+            assert!((curve(operator, 1.0, 0.2) - curve(operator, 0.0, 0.4)).abs() < 1e-6);
+            assert!((curve(operator, -2.0, 0.8) - curve(operator, 0.0, 0.2)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn colors_are_encoded_to_srgb() {
+        let clamp = ToneMapper::new(ToneMapping::Clamp, 0.0);
+        assert_eq!(clamp.to_color(&Vector3::new(1.0, 0.0, 2.0)), 0xFF00FF);
+        // linear middle grey is not 0x80
+        assert_eq!(clamp.to_color(&Vector3::repeat(0.5)), 0xBCBCBC);
+        let mut buffer = [0; 2];
+        clamp.resolve(&[Vector4::new(0.0, 0.0, 0.0, 1.0), Vector4::new(1.0, 1.0, 1.0, 0.0)], &mut buffer);
+        assert_eq!(buffer, [0x000000, 0xFFFFFF]);
+    }
+}
@@ -0,0 +1,198 @@
+use std::f32::consts::PI;
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use super::environment::Environment;
+use super::framebuffer::Framebuffer;
+use super::light::Light;
+use super::material::fresnel_schlick;
+use super::raytracer::reflect;
+use super::render::view_depth;
+use super::shader::{FragmentInput, Shader, Uniforms, VertexInput};
+use super::shadow::ShadowCaster;
+use super::texture::{Texture, TextureFilter};
+
+// a trochoidal wave, the water moves in circles so the crests come out sharp and the troughs wide
+// (Tessendorf 2001, GPU Gems 1 chapter 1). all lengths are world units
+#[derive(Clone, Copy, Debug)]
+pub struct GerstnerWave {
+    // horizontal direction of travel in x and z, normalized
+    pub direction: Vector2<f32>,
+    pub wavelength: f32,
+    pub amplitude: f32,
+    // 0 gives plain sine waves, 1 pointed crests. the sum over all waves should stay below 1 or
+    // the surface loops over itself
+    pub steepness: f32,
+    // world units per second
+    pub speed: f32,
+}
+
+impl GerstnerWave {
+    pub fn new(angle: f32, wavelength: f32, amplitude: f32, steepness: f32, speed: f32) -> GerstnerWave {
+        let angle = angle.to_radians();
+        GerstnerWave { direction: Vector2::new(angle.cos(), angle.sin()), wavelength, amplitude, steepness, speed }
+    }
+
+    fn phase(&self, position: &Vector2<f32>, time: f32) -> (f32, f32) {
+        let frequency = 2.0 * PI / self.wavelength;
+        (frequency, frequency * (self.direction.dot(position) - self.speed * time))
+    }
+}
+
+// a few long swells with shorter chop across them, sized for the heightmap scene
+pub fn default_waves() -> Vec<GerstnerWave> {
+    vec![
+        GerstnerWave::new(20.0, 1.63, 0.010, 0.3, 0.35),
+        GerstnerWave::new(-37.0, 0.97, 0.006, 0.25, 0.28),
+        GerstnerWave::new(71.0, 0.53, 0.003, 0.2, 0.21),
+        GerstnerWave::new(-104.0, 0.37, 0.002, 0.2, 0.17),
+        GerstnerWave::new(143.0, 0.23, 0.0012, 0.15, 0.14),
+        GerstnerWave::new(-11.0, 0.17, 0.0008, 0.1, 0.12),
+    ]
+}
+
+// movement of the point of the still surface at x and z, and the normal of the moving surface there
+pub fn gerstner(waves: &[GerstnerWave], position: &Vector2<f32>, time: f32) -> (Vector3<f32>, Vector3<f32>) {
+    let mut offset = Vector3::zeros();
+    let mut normal = Vector3::y();
+    for wave in waves {
+        let (frequency, phase) = wave.phase(position, time);
+        let (sin, cos) = phase.sin_cos();
+        let horizontal = wave.steepness / (frequency * waves.len() as f32) * cos;
+        offset += Vector3::new(wave.direction.x * horizontal, wave.amplitude * sin, wave.direction.y * horizontal);
+        let slope = frequency * wave.amplitude;
+        normal -= Vector3::new(wave.direction.x * slope * cos, wave.steepness / waves.len() as f32 * sin, wave.direction.y * slope * cos);
+    }
+    (offset, normal.normalize())
+}
+
+// lit water over the opaque scene. background is a copy of the framebuffer before the water is
+// drawn, the water thickness comes from its depth and the colors behind from its color. the
+// surface reflects the environment by the fresnel term and foams where the water gets shallow
+pub struct WaterShader<'a> {
+    pub background: &'a Framebuffer,
+    pub waves: &'a [GerstnerWave],
+    // seconds, moves the waves
+    pub time: f32,
+    pub lights: &'a [Light],
+    pub shadows: &'a [Option<&'a dyn ShadowCaster>],
+    // reflected sky, also lights the water from above
+    pub environment: &'a dyn Environment,
+    // water where the red channel is above one half at the uv, everywhere without a mask
+    pub mask: Option<&'a Texture>,
+    // linear, light lost per world unit of water in every channel
+    pub absorption: Vector3<f32>,
+    // linear color of the light scattered back out of deep water
+    pub scattering: Vector3<f32>,
+    // thickness in world units below which the shore foams
+    pub foam_distance: f32,
+    // how far the waves shift the view of the ground, in pixels
+    pub refraction: f32,
+}
+
+impl<'a> WaterShader<'a> {
+    pub fn new(background: &'a Framebuffer, waves: &'a [GerstnerWave], time: f32, lights: &'a [Light], shadows: &'a [Option<&'a dyn ShadowCaster>], environment: &'a dyn Environment) -> WaterShader<'a> {
+        WaterShader {
+            background,
+            waves,
+            time,
+            lights,
+            shadows,
+            environment,
+            mask: None,
+            // red goes first, so deep water turns blue green
+            absorption: Vector3::new(9.0, 3.0, 1.8),
+            scattering: Vector3::new(0.01, 0.05, 0.07),
+            foam_distance: 0.012,
+            refraction: 12.0,
+        }
+    }
+
+    // view depth of the background, None where it has nothing
+    fn background_depth(&self, uniforms: &Uniforms, index: usize) -> Option<f32> {
+        view_depth(self.background.depth[index], uniforms.near, uniforms.far)
+    }
+}
+
+impl<'a> Shader for WaterShader<'a> {
+    // world position of the still surface and uv
+    type Varyings = (Vector3<f32>, Vector2<f32>);
+
+    fn vertex(&self, uniforms: &Uniforms, input: &VertexInput) -> (Vector4<f32>, Self::Varyings) {
+        let position = uniforms.world_position(&input.position);
+        let (offset, _) = gerstner(self.waves, &position.xz(), self.time);
+        let moved = position + offset;
+        (uniforms.projection * uniforms.view * Vector4::new(moved.x, moved.y, moved.z, 1.0), (position, input.uv))
+    }
+
+    fn fragment(&self, uniforms: &Uniforms, input: &FragmentInput, varyings: &Self::Varyings) -> Option<Vector4<f32>> {
+        let (position, uv) = varyings;
+        if let Some(mask) = self.mask {
+            if mask.sample(uv, TextureFilter::Bilinear).x < 0.5 {
+                return None;
+            }
+        }
+        // the normal per pixel, the grid is too coarse for the short waves
+        let (offset, normal) = gerstner(self.waves, &position.xz(), self.time);
+        let position = position + offset;
+        let to_camera = uniforms.camera_position - position;
+        let distance = to_camera.norm();
+        let view = to_camera / distance;
+        let normal = if normal.dot(&view) < 0.0 { -normal } else { normal };
+
+        // thickness of the water along the view ray, from the view depths in front and behind
+        let (width, height) = self.background.dimensions;
+        let index = input.y * width + input.x;
+        let water_depth = (uniforms.view * Vector4::new(position.x, position.y, position.z, 1.0)).z.max(1e-6);
+        let thickness = |index: usize| self.background_depth(uniforms, index).map_or(f32::INFINITY, |depth| (depth - water_depth).max(0.0) * distance / water_depth);
+        // the ground is seen shifted by the waves, unless the shifted pixel lies in front of the water
+        let shift = Vector2::new(normal.x, normal.z) * self.refraction;
+        let refracted_x = (input.x as f32 + shift.x).clamp(0.0, width as f32 - 1.0) as usize;
+        let refracted_y = (input.y as f32 + shift.y).clamp(0.0, height as f32 - 1.0) as usize;
+        let refracted = refracted_y * width + refracted_x;
+        let behind = if thickness(refracted) > 0.0 { refracted } else { index };
+        let thickness = thickness(behind).min(thickness(index));
+
+        // light reaching the water from the sun and the sky
+        let mut irradiance = self.environment.radiance(&Vector3::y()) * PI;
+        let mut specular = Vector3::zeros();
+        for (i, light) in self.lights.iter().enumerate() {
+            irradiance += light.ambient(&Vector3::y());
+            let Some(sample) = light.sample(&position) else {
+                continue;
+            };
+            let visibility = self.shadows.get(i).copied().flatten().map_or(1.0, |shadow| shadow.visibility(&position, &Vector3::y()));
+            irradiance += sample.irradiance * (sample.direction.y.max(0.0) * visibility);
+            // sharp sun glints, normalized blinn-phong
+            let half = (sample.direction + view).normalize();
+            let shininess = 800.0;
+            specular += sample.irradiance * ((shininess + 8.0) / (8.0 * PI) * normal.dot(&half).max(0.0).powf(shininess) * visibility);
+        }
+
+        // the ground fades into the color scattered by the water the thicker the water gets
+        let transmittance = (-self.absorption * thickness).map(f32::exp);
+        let ground = self.background.color[behind].xyz();
+        let scattered = self.scattering.component_mul(&irradiance) / PI;
+        let body = ground.component_mul(&transmittance) + scattered.component_mul(&(Vector3::repeat(1.0) - transmittance));
+
+        let fresnel = fresnel_schlick(Vector3::repeat(0.02), normal.dot(&view).max(0.0));
+        let reflection = self.environment.radiance(&reflect(&-view, &normal));
+        let mut color = body.component_mul(&(Vector3::repeat(1.0) - fresnel)) + reflection.component_mul(&fresnel) + specular.component_mul(&fresnel);
+
+        // foam along the shore, in bands that run towards the land with the waves
+        if thickness < self.foam_distance {
+            let closeness = 1.0 - thickness / self.foam_distance;
+            let bands = 0.5 + 0.5 * (closeness * 12.0 - self.time * 2.0 + (offset.y * 400.0)).sin();
+            let foam = (closeness * closeness * (0.4 + 0.6 * bands)).clamp(0.0, 1.0);
+            color = color.lerp(&(irradiance * (0.8 / PI)), foam);
+        }
+        Some(Vector4::new(color.x, color.y, color.z, 1.0))
+    }
+
+    fn texture_coordinates(&self, varyings: &Self::Varyings) -> Option<Vector2<f32>> {
+        Some(varyings.1)
+    }
+
+    fn surface_normal(&self, varyings: &Self::Varyings) -> Option<Vector3<f32>> {
+        Some(gerstner(self.waves, &varyings.0.xz(), self.time).1)
+    }
+}